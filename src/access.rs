@@ -0,0 +1,341 @@
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{Error, LookupFlags};
+
+// This is correct for every architecture except alpha, which Rust does not support
+#[cfg(target_os = "linux")]
+const SYS_FACCESSAT2: libc::c_long = 439;
+
+// The state of faccessat2() support, as discovered so far
+#[cfg(target_os = "linux")]
+const FACCESSAT2_UNKNOWN: u8 = 0;
+#[cfg(target_os = "linux")]
+const FACCESSAT2_SUPPORTED: u8 = 1;
+// ENOSYS, or a seccomp filter is blocking faccessat2() with EPERM
+#[cfg(target_os = "linux")]
+const FACCESSAT2_UNSUPPORTED: u8 = 2;
+
+#[cfg(target_os = "linux")]
+static FACCESSAT2_STATE: AtomicU8 = AtomicU8::new(FACCESSAT2_UNKNOWN);
+
+/// Convert the result of an `access()`-like check to whether access is allowed.
+fn allowed(res: io::Result<()>) -> Result<bool, Error> {
+    match res {
+        Ok(()) => Ok(true),
+        Err(e)
+            if matches!(
+                e.raw_os_error(),
+                Some(libc::EACCES) | Some(libc::EPERM) | Some(libc::EROFS) | Some(libc::ETXTBSY)
+            ) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn access<D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &Path,
+    mode: libc::c_int,
+    lookup_flags: LookupFlags,
+    effective: bool,
+) -> Result<bool, Error> {
+    let fd = crate::open::open_file_secure(dir, path, lookup_flags, libc::O_PATH, 0)?;
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+
+    let flags = libc::AT_EMPTY_PATH | if effective { libc::AT_EACCESS } else { 0 };
+    if FACCESSAT2_STATE.load(Ordering::Relaxed) != FACCESSAT2_UNSUPPORTED {
+        match faccessat2(file.as_raw_fd(), b"\0", mode, flags) {
+            Ok(()) => {
+                FACCESSAT2_STATE.store(FACCESSAT2_SUPPORTED, Ordering::Relaxed);
+                return Ok(true);
+            }
+            Err(err) if !faccessat2_failed(&err) => {
+                return allowed(Err(err)).map_err(|e| e.at_component(last_component(path)));
+            }
+            Err(_) => (),
+        }
+    }
+
+    // Linux < 5.8
+    allowed(access_userspace(file.as_raw_fd(), mode, effective))
+        .map_err(|e| e.at_component(last_component(path)))
+}
+
+#[cfg(target_os = "linux")]
+fn faccessat2(dirfd: RawFd, path: &[u8], mode: libc::c_int, flags: libc::c_int) -> io::Result<()> {
+    debug_assert_eq!(path.last(), Some(&0));
+
+    let res = unsafe {
+        libc::syscall(
+            SYS_FACCESSAT2,
+            dirfd,
+            path.as_ptr() as *const libc::c_char,
+            mode,
+            flags,
+        )
+    };
+
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Given an error from `faccessat2()`, record what it says about `faccessat2()` support, and
+/// return whether we should fall back on checking the mode in userspace.
+#[cfg(target_os = "linux")]
+fn faccessat2_failed(err: &io::Error) -> bool {
+    let state = faccessat2_state_after(FACCESSAT2_STATE.load(Ordering::Relaxed), err, || {
+        faccessat2(libc::AT_FDCWD, b"/\0", libc::F_OK, 0)
+    });
+    FACCESSAT2_STATE.store(state, Ordering::Relaxed);
+
+    state == FACCESSAT2_UNSUPPORTED
+}
+
+/// Work out the new state of `faccessat2()` support from the current `state` and an error from
+/// `faccessat2()`. `probe` makes a trivial `faccessat2()` call, in case the error is ambiguous.
+#[cfg(target_os = "linux")]
+fn faccessat2_state_after<F>(state: u8, err: &io::Error, probe: F) -> u8
+where
+    F: FnOnce() -> io::Result<()>,
+{
+    match err.raw_os_error() {
+        // The kernel doesn't support faccessat2()
+        Some(libc::ENOSYS) => FACCESSAT2_UNSUPPORTED,
+
+        // Some seccomp filters block unknown syscalls with EPERM. But EPERM can also be a
+        // legitimate result (for example, W_OK on an immutable file), so check if faccessat2()
+        // fails with a trivial call.
+        Some(libc::EPERM) if state != FACCESSAT2_SUPPORTED => match probe() {
+            Err(e) if matches!(e.raw_os_error(), Some(libc::EPERM | libc::ENOSYS)) => {
+                FACCESSAT2_UNSUPPORTED
+            }
+            _ => FACCESSAT2_SUPPORTED,
+        },
+
+        _ if state == FACCESSAT2_UNKNOWN => FACCESSAT2_SUPPORTED,
+        _ => state,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn access<D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &Path,
+    mode: libc::c_int,
+    lookup_flags: LookupFlags,
+    effective: bool,
+) -> Result<bool, Error> {
+    use std::ffi::CString;
+
+    // Find where the final symlink (if any) leads, since faccessat() can't be allowed to follow it
+    let hops = crate::link_chain::read_link_chain(dir, path, lookup_flags)?;
+    let final_path = match hops.last() {
+        Some(hop) => crate::link_chain::next_path(hop.path(), hop.target()),
+        None => path.to_path_buf(),
+    };
+
+    let root = crate::util::borrow_dir(dir);
+    let (subdir, fname) = crate::prepare_inner_operation(dir, &final_path, lookup_flags)
+        .map_err(|e| e.set_component(last_component(path)))?;
+    let subdir = subdir.as_ref().unwrap_or(&root);
+
+    let fname = CString::new(fname.map_or(&b"."[..], |f| f.as_bytes()))
+        .map_err(|e| Error::from(io::Error::from(e)))?;
+    let flags = libc::AT_SYMLINK_NOFOLLOW | if effective { libc::AT_EACCESS } else { 0 };
+
+    let res = if unsafe { libc::faccessat(subdir.as_raw_fd(), fname.as_ptr(), mode, flags) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+
+    allowed(res).map_err(|e| e.at_component(last_component(path)))
+}
+
+fn last_component(path: &Path) -> Option<usize> {
+    path.components().count().checked_sub(1)
+}
+
+/// Check access to the file `fd` by evaluating its mode, owner, and group.
+#[cfg(target_os = "linux")]
+fn access_userspace(fd: RawFd, mode: libc::c_int, effective: bool) -> io::Result<()> {
+    let st = crate::util::fstat(fd)?;
+
+    if mode & libc::W_OK != 0 {
+        let mut stvfs: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatvfs(fd, &mut stvfs) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if stvfs.f_flag & libc::ST_RDONLY != 0 {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+    }
+
+    let (uid, gid) = unsafe {
+        if effective {
+            (libc::geteuid(), libc::getegid())
+        } else {
+            (libc::getuid(), libc::getgid())
+        }
+    };
+
+    if check_mode(&st, mode, uid, gid, &get_groups()?) {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(libc::EACCES))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_groups() -> io::Result<Vec<libc::gid_t>> {
+    loop {
+        let n = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut groups = vec![0; n as usize];
+        let n = unsafe { libc::getgroups(n, groups.as_mut_ptr()) };
+        if n >= 0 {
+            groups.truncate(n as usize);
+            return Ok(groups);
+        }
+
+        let err = io::Error::last_os_error();
+        // The list grew in between the two calls
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            return Err(err);
+        }
+    }
+}
+
+/// Check whether the permission bits of `st` grant the access in `mode` to the given user and
+/// groups. This doesn't take ACLs or capabilities (other than root's) into account.
+#[cfg(target_os = "linux")]
+fn check_mode(
+    st: &libc::stat,
+    mode: libc::c_int,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+    groups: &[libc::gid_t],
+) -> bool {
+    let want = (mode & (libc::R_OK | libc::W_OK | libc::X_OK)) as libc::mode_t;
+
+    if uid == 0 {
+        // root can read and write anything, and execute anything that is executable by someone
+        // (or is a directory)
+        return want & libc::X_OK as libc::mode_t == 0
+            || st.st_mode & libc::S_IFMT == libc::S_IFDIR
+            || st.st_mode & 0o111 != 0;
+    }
+
+    let bits = if st.st_uid == uid {
+        st.st_mode >> 6
+    } else if st.st_gid == gid || groups.contains(&st.st_gid) {
+        st.st_mode >> 3
+    } else {
+        st.st_mode
+    } & 0o7;
+
+    bits & want == want
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_mode() {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        st.st_uid = 1000;
+        st.st_gid = 100;
+
+        let rw = libc::R_OK | libc::W_OK;
+
+        st.st_mode = libc::S_IFREG | 0o640;
+        // Owner
+        assert!(check_mode(&st, rw, 1000, 1000, &[]));
+        assert!(!check_mode(&st, libc::X_OK, 1000, 1000, &[]));
+        // Group (primary or supplementary)
+        assert!(check_mode(&st, libc::R_OK, 1001, 100, &[]));
+        assert!(check_mode(&st, libc::R_OK, 1001, 1001, &[5, 100]));
+        assert!(!check_mode(&st, rw, 1001, 100, &[]));
+        // Other
+        assert!(!check_mode(&st, libc::R_OK, 1001, 1001, &[]));
+        assert!(check_mode(&st, libc::F_OK, 1001, 1001, &[]));
+
+        // The owner bits apply to the owner, even if the group bits are more permissive
+        st.st_mode = libc::S_IFREG | 0o070;
+        assert!(!check_mode(&st, libc::R_OK, 1000, 100, &[]));
+
+        // root
+        assert!(check_mode(&st, rw, 0, 0, &[]));
+        assert!(check_mode(&st, libc::X_OK, 0, 0, &[]));
+        st.st_mode = libc::S_IFREG | 0o600;
+        assert!(!check_mode(&st, libc::X_OK, 0, 0, &[]));
+        st.st_mode = libc::S_IFDIR;
+        assert!(check_mode(&st, libc::X_OK, 0, 0, &[]));
+    }
+
+    #[test]
+    fn test_faccessat2_state_after() {
+        let err = io::Error::from_raw_os_error;
+        let no_probe = || -> io::Result<()> { panic!("unexpected probe") };
+        let failing_probe = |errno| move || -> io::Result<()> { Err(err(errno)) };
+
+        // The kernel doesn't have faccessat2()
+        for &state in [FACCESSAT2_UNKNOWN, FACCESSAT2_SUPPORTED].iter() {
+            assert_eq!(
+                faccessat2_state_after(state, &err(libc::ENOSYS), no_probe),
+                FACCESSAT2_UNSUPPORTED
+            );
+        }
+
+        // EPERM from a seccomp filter: the probe fails the same way
+        for &probe_errno in [libc::EPERM, libc::ENOSYS].iter() {
+            assert_eq!(
+                faccessat2_state_after(
+                    FACCESSAT2_UNKNOWN,
+                    &err(libc::EPERM),
+                    failing_probe(probe_errno)
+                ),
+                FACCESSAT2_UNSUPPORTED
+            );
+        }
+
+        // A genuine EPERM: the probe succeeds (or fails for an unrelated reason)
+        assert_eq!(
+            faccessat2_state_after(FACCESSAT2_UNKNOWN, &err(libc::EPERM), || Ok(())),
+            FACCESSAT2_SUPPORTED
+        );
+        assert_eq!(
+            faccessat2_state_after(
+                FACCESSAT2_UNKNOWN,
+                &err(libc::EPERM),
+                failing_probe(libc::EACCES)
+            ),
+            FACCESSAT2_SUPPORTED
+        );
+        // Once faccessat2() is known to work, EPERM is always genuine
+        assert_eq!(
+            faccessat2_state_after(FACCESSAT2_SUPPORTED, &err(libc::EPERM), no_probe),
+            FACCESSAT2_SUPPORTED
+        );
+
+        // Other errors show that faccessat2() is there
+        assert_eq!(
+            faccessat2_state_after(FACCESSAT2_UNKNOWN, &err(libc::EACCES), no_probe),
+            FACCESSAT2_SUPPORTED
+        );
+    }
+}
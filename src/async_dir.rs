@@ -0,0 +1,523 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use openat::{Dir, SimpleType};
+use tokio::task::JoinHandle;
+
+use crate::dir_entry::Origin;
+use crate::{DirEntry, DirIter, DirSecureExt, Error, LookupFlags};
+
+// The same chunk size that tokio::fs::ReadDir uses
+const READ_DIR_CHUNK_SIZE: usize = 32;
+
+/// An asynchronous wrapper around a [`Dir`](https://docs.rs/openat/*/openat/struct.Dir.html).
+///
+/// Every operation is run on tokio's blocking thread pool. The underlying `Dir` is shared via an
+/// `Arc`, so cloning an `AsyncSecureDir` is cheap and many tasks can use the same directory
+/// concurrently.
+///
+/// The methods of this struct mirror the methods of [`DirSecureExt`]; see its documentation for
+/// security information.
+///
+/// [`DirSecureExt`]: ./trait.DirSecureExt.html
+#[derive(Clone, Debug)]
+pub struct AsyncSecureDir {
+    dir: Arc<Dir>,
+}
+
+impl AsyncSecureDir {
+    /// Open the directory at the given path.
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        Ok(Self::new(asyncify(move || Dir::open(&path)).await?))
+    }
+
+    /// Wrap an existing `Dir`.
+    pub fn new(dir: Dir) -> Self {
+        Self { dir: Arc::new(dir) }
+    }
+
+    /// Get a reference to the underlying `Dir`.
+    pub fn as_dir(&self) -> &Dir {
+        &self.dir
+    }
+
+    /// Get a new reference to the shared `Dir`.
+    pub fn to_arc(&self) -> Arc<Dir> {
+        self.dir.clone()
+    }
+
+    async fn run<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&Dir) -> Result<T, Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        let dir = self.dir.clone();
+        asyncify(move || f(&dir)).await
+    }
+
+    pub async fn parent_secure(&self) -> Result<Option<AsyncSecureDir>, Error> {
+        Ok(self
+            .run(|dir| dir.parent_secure())
+            .await?
+            .map(AsyncSecureDir::new))
+    }
+
+    pub async fn sub_dir_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<AsyncSecureDir, Error> {
+        let p = p.as_ref().to_path_buf();
+
+        Ok(AsyncSecureDir::new(
+            self.run(move |dir| dir.sub_dir_secure(p, lookup_flags))
+                .await?,
+        ))
+    }
+
+    pub async fn new_file_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        mode: libc::mode_t,
+        lookup_flags: LookupFlags,
+    ) -> Result<tokio::fs::File, Error> {
+        let p = p.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.new_file_secure(p, mode, lookup_flags))
+            .await
+            .map(tokio::fs::File::from_std)
+    }
+
+    pub async fn update_file_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        mode: libc::mode_t,
+        lookup_flags: LookupFlags,
+    ) -> Result<tokio::fs::File, Error> {
+        let p = p.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.update_file_secure(p, mode, lookup_flags))
+            .await
+            .map(tokio::fs::File::from_std)
+    }
+
+    pub async fn open_file_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<tokio::fs::File, Error> {
+        let p = p.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.open_file_secure(p, lookup_flags))
+            .await
+            .map(tokio::fs::File::from_std)
+    }
+
+    pub async fn write_file_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        mode: libc::mode_t,
+        lookup_flags: LookupFlags,
+    ) -> Result<tokio::fs::File, Error> {
+        let p = p.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.write_file_secure(p, mode, lookup_flags))
+            .await
+            .map(tokio::fs::File::from_std)
+    }
+
+    pub async fn append_file_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        mode: libc::mode_t,
+        lookup_flags: LookupFlags,
+    ) -> Result<tokio::fs::File, Error> {
+        let p = p.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.append_file_secure(p, mode, lookup_flags))
+            .await
+            .map(tokio::fs::File::from_std)
+    }
+
+    pub async fn create_dir_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mode: libc::mode_t,
+        lookup_flags: LookupFlags,
+    ) -> Result<(), Error> {
+        let path = path.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.create_dir_secure(path, mode, lookup_flags))
+            .await
+    }
+
+    pub async fn remove_dir_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<(), Error> {
+        let path = path.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.remove_dir_secure(path, lookup_flags))
+            .await
+    }
+
+    pub async fn remove_file_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<(), Error> {
+        let path = path.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.remove_file_secure(path, lookup_flags))
+            .await
+    }
+
+    pub async fn metadata_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<openat::Metadata, Error> {
+        let path = path.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.metadata_secure(path, lookup_flags))
+            .await
+    }
+
+    pub async fn read_link_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<PathBuf, Error> {
+        let path = path.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.read_link_secure(path, lookup_flags))
+            .await
+    }
+
+    pub async fn symlink_secure<P: AsRef<Path>, R: AsRef<Path>>(
+        &self,
+        path: P,
+        value: R,
+        lookup_flags: LookupFlags,
+    ) -> Result<(), Error> {
+        let path = path.as_ref().to_path_buf();
+        let value = value.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.symlink_secure(path, &value, lookup_flags))
+            .await
+    }
+
+    pub async fn local_rename_secure<P: AsRef<Path>, R: AsRef<Path>>(
+        &self,
+        old: P,
+        new: R,
+        lookup_flags: LookupFlags,
+    ) -> Result<(), Error> {
+        let old = old.as_ref().to_path_buf();
+        let new = new.as_ref().to_path_buf();
+
+        self.run(move |dir| dir.local_rename_secure(old, new, lookup_flags))
+            .await
+    }
+
+    /// List the contents of a directory.
+    ///
+    /// This is the asynchronous equivalent of [`DirSecureExt::list_dir_secure()`]. Entries are
+    /// read in chunks on the blocking thread pool.
+    ///
+    /// [`DirSecureExt::list_dir_secure()`]: ./trait.DirSecureExt.html#method.list_dir_secure
+    pub async fn read_dir<P: AsRef<Path>>(
+        &self,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<ReadDir, Error> {
+        let path = path.as_ref().to_path_buf();
+        let origin = Arc::new(Origin {
+            root: self.dir.clone(),
+            path: path.clone(),
+        });
+        let mut read_dir = ReadDir::spawn(self.dir.clone(), path, lookup_flags, origin);
+
+        // Wait for the directory to be opened so errors are reported here
+        if let State::Pending(handle) = &mut read_dir.state {
+            read_dir.state = State::Idle(Some(join(handle).await?));
+        }
+
+        Ok(read_dir)
+    }
+
+    /// Recursively walk the directory tree rooted at the given path.
+    ///
+    /// Entries are yielded in depth-first order, with each directory yielded before its contents.
+    /// Symbolic links are never followed when descending into subdirectories.
+    pub fn walk<P: AsRef<Path>>(&self, path: P, lookup_flags: LookupFlags) -> Walk {
+        let path = path.as_ref().to_path_buf();
+        let origin = Arc::new(Origin {
+            root: self.dir.clone(),
+            path: path.clone(),
+        });
+
+        Walk {
+            stack: vec![WalkFrame {
+                prefix: PathBuf::new(),
+                read_dir: ReadDir::spawn(self.dir.clone(), path, lookup_flags, origin.clone()),
+                maybe_not_dir: false,
+            }],
+            origin,
+            lookup_flags,
+        }
+    }
+}
+
+impl From<Dir> for AsyncSecureDir {
+    fn from(dir: Dir) -> Self {
+        Self::new(dir)
+    }
+}
+
+impl From<Arc<Dir>> for AsyncSecureDir {
+    fn from(dir: Arc<Dir>) -> Self {
+        Self { dir }
+    }
+}
+
+async fn asyncify<F, T, E>(f: F) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<io::Error> + Send + 'static,
+{
+    join(&mut tokio::task::spawn_blocking(f)).await
+}
+
+async fn join<T, E: From<io::Error>>(handle: &mut JoinHandle<Result<T, E>>) -> Result<T, E> {
+    match handle.await {
+        Ok(res) => res,
+        Err(e) => Err(io::Error::other(e).into()),
+    }
+}
+
+struct Chunk {
+    entries: VecDeque<DirEntry>,
+    iter: Option<DirIter>,
+}
+
+impl Chunk {
+    fn fill(mut self) -> Result<Self, Error> {
+        if let Some(iter) = self.iter.as_mut() {
+            while self.entries.len() < READ_DIR_CHUNK_SIZE {
+                match iter.next() {
+                    Some(entry) => self.entries.push_back(entry?),
+                    None => {
+                        self.iter = None;
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+enum State {
+    Idle(Option<Chunk>),
+    Pending(JoinHandle<Result<Chunk, Error>>),
+}
+
+/// An asynchronous iterator over the entries in a directory.
+///
+/// Created by [`AsyncSecureDir::read_dir()`](struct.AsyncSecureDir.html#method.read_dir).
+pub struct ReadDir {
+    state: State,
+}
+
+impl ReadDir {
+    /// List `path` relative to `dir`; `origin` is where that directory would be found from the
+    /// original root.
+    fn spawn(dir: Arc<Dir>, path: PathBuf, lookup_flags: LookupFlags, origin: Arc<Origin>) -> Self {
+        Self {
+            state: State::Pending(tokio::task::spawn_blocking(move || {
+                let dir = crate::open_dir_for_listing(&*dir, &path, lookup_flags)
+                    .map_err(|e| e.context("read_dir", &path))?;
+                Chunk {
+                    entries: VecDeque::new(),
+                    iter: Some(DirIter::with_origin(dir, Some(origin))?),
+                }
+                .fill()
+            })),
+        }
+    }
+
+    /// Get the next entry in the directory, or `None` if there are no more entries.
+    pub async fn next_entry(&mut self) -> Result<Option<DirEntry>, Error> {
+        std::future::poll_fn(|cx| self.poll_next_entry(cx)).await
+    }
+
+    /// Poll for the next entry in the directory.
+    pub fn poll_next_entry(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<DirEntry>, Error>> {
+        loop {
+            match &mut self.state {
+                State::Idle(chunk) => {
+                    let mut chunk = match chunk.take() {
+                        Some(chunk) => chunk,
+                        None => return Poll::Ready(Ok(None)),
+                    };
+
+                    if let Some(entry) = chunk.entries.pop_front() {
+                        self.state = State::Idle(Some(chunk));
+                        return Poll::Ready(Ok(Some(entry)));
+                    } else if chunk.iter.is_some() {
+                        self.state =
+                            State::Pending(tokio::task::spawn_blocking(move || chunk.fill()));
+                    } else {
+                        return Poll::Ready(Ok(None));
+                    }
+                }
+
+                State::Pending(handle) => {
+                    let res = match ready!(Pin::new(handle).poll(cx)) {
+                        Ok(res) => res,
+                        Err(e) => Err(io::Error::other(e).into()),
+                    };
+
+                    match res {
+                        Ok(chunk) => self.state = State::Idle(Some(chunk)),
+                        Err(e) => {
+                            self.state = State::Idle(None);
+                            return Poll::Ready(Err(e));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl futures_core::Stream for ReadDir {
+    type Item = Result<DirEntry, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next_entry(cx).map(Result::transpose)
+    }
+}
+
+/// An entry yielded by [`Walk`](struct.Walk.html).
+#[derive(Debug)]
+pub struct WalkEntry {
+    path: PathBuf,
+    entry: DirEntry,
+}
+
+impl WalkEntry {
+    /// The path of this entry, relative to the directory that is being walked.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The underlying directory entry.
+    pub fn entry(&self) -> &DirEntry {
+        &self.entry
+    }
+
+    /// Returns the simplified type of this entry, if it is known.
+    pub fn simple_type(&self) -> Option<SimpleType> {
+        self.entry.file_type()
+    }
+}
+
+struct WalkFrame {
+    prefix: PathBuf,
+    read_dir: ReadDir,
+    /// The type of the entry wasn't known when we started listing it
+    maybe_not_dir: bool,
+}
+
+/// An asynchronous recursive directory walker.
+///
+/// Created by [`AsyncSecureDir::walk()`](struct.AsyncSecureDir.html#method.walk).
+pub struct Walk {
+    stack: Vec<WalkFrame>,
+    /// The directory that is being walked
+    origin: Arc<Origin>,
+    lookup_flags: LookupFlags,
+}
+
+impl Walk {
+    /// Get the next entry in the directory tree, or `None` if the walk is complete.
+    pub async fn next_entry(&mut self) -> Result<Option<WalkEntry>, Error> {
+        std::future::poll_fn(|cx| self.poll_next_entry(cx)).await
+    }
+
+    /// Poll for the next entry in the directory tree.
+    pub fn poll_next_entry(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<WalkEntry>, Error>> {
+        while let Some(frame) = self.stack.last_mut() {
+            let entry = match ready!(frame.read_dir.poll_next_entry(cx)) {
+                Ok(Some(item)) => item,
+                Ok(None) => {
+                    self.stack.pop();
+                    continue;
+                }
+                Err(e) => {
+                    let maybe_not_dir = frame.maybe_not_dir;
+                    self.stack.pop();
+
+                    match e.raw_os_error() {
+                        // It wasn't a directory after all
+                        Some(libc::ENOTDIR) | Some(libc::ELOOP) if maybe_not_dir => continue,
+                        _ => return Poll::Ready(Err(e)),
+                    }
+                }
+            };
+
+            let path = frame.prefix.join(entry.file_name());
+
+            let ftype = entry.file_type();
+            if ftype == Some(SimpleType::Dir) || ftype.is_none() {
+                let origin = Arc::new(Origin {
+                    root: self.origin.root.clone(),
+                    path: self.origin.path.join(&path),
+                });
+
+                // The name is a single path component, so adding NO_SYMLINKS ensures that we
+                // never follow a symlink that was swapped in after the directory was listed.
+                self.stack.push(WalkFrame {
+                    prefix: path.clone(),
+                    read_dir: ReadDir::spawn(
+                        entry.shared_dir().clone(),
+                        entry.file_name().into(),
+                        self.lookup_flags | LookupFlags::NO_SYMLINKS,
+                        origin,
+                    ),
+                    maybe_not_dir: ftype.is_none(),
+                });
+            }
+
+            return Poll::Ready(Ok(Some(WalkEntry { path, entry })));
+        }
+
+        Poll::Ready(Ok(None))
+    }
+}
+
+impl futures_core::Stream for Walk {
+    type Item = Result<WalkEntry, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_next_entry(cx).map(Result::transpose)
+    }
+}
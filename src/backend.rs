@@ -0,0 +1,211 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::AtomicU32;
+
+#[cfg(target_os = "linux")]
+use crate::openat2;
+
+/// Selects how paths are resolved.
+///
+/// The backend is process-wide; see [`set_backend()`](fn.set_backend.html).
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+#[repr(u8)]
+pub enum Backend {
+    /// Use `openat2()` where it is available and can implement the requested lookup flags, and
+    /// fall back on the manual implementation otherwise. This is the default.
+    #[default]
+    Auto = 0,
+    /// Always use `openat2()`. If it is unavailable, or if it cannot implement the requested
+    /// lookup flags, fail with an error (usually `ENOSYS` or `ENOTSUP`) instead of falling back.
+    ForceOpenat2 = 1,
+    /// Always use the manual implementation, even if `openat2()` is available.
+    ForceFallback = 2,
+}
+
+static BACKEND: AtomicU8 = AtomicU8::new(Backend::Auto as u8);
+
+/// Set the process-wide [`Backend`](enum.Backend.html) used for path resolution.
+///
+/// This is mainly useful for testing. Operations that are already in progress may continue to use
+/// the old backend.
+pub fn set_backend(backend: Backend) {
+    BACKEND.store(backend as u8, Ordering::Relaxed);
+}
+
+/// Get the current process-wide [`Backend`](enum.Backend.html).
+pub fn backend() -> Backend {
+    match BACKEND.load(Ordering::Relaxed) {
+        1 => Backend::ForceOpenat2,
+        2 => Backend::ForceFallback,
+        _ => Backend::Auto,
+    }
+}
+
+// The state of openat2() support, as discovered so far
+#[cfg(target_os = "linux")]
+const OPENAT2_UNKNOWN: u8 = 0;
+#[cfg(target_os = "linux")]
+const OPENAT2_SUPPORTED: u8 = 1;
+// ENOSYS: the kernel doesn't have openat2()
+#[cfg(target_os = "linux")]
+const OPENAT2_UNSUPPORTED: u8 = 2;
+// A seccomp filter is blocking openat2() with EPERM
+#[cfg(target_os = "linux")]
+const OPENAT2_DENIED: u8 = 3;
+
+#[cfg(target_os = "linux")]
+static OPENAT2_STATE: AtomicU8 = AtomicU8::new(OPENAT2_UNKNOWN);
+
+/// Bit `n` is set if `openat2()` failed with `E2BIG` when passed the resolve flags `n`.
+#[cfg(target_os = "linux")]
+static OPENAT2_E2BIG: AtomicU32 = AtomicU32::new(0);
+
+#[cfg(target_os = "linux")]
+fn resolve_bit(resolve_flags: openat2::ResolveFlags) -> u32 {
+    // All of the resolve flags fit in the low 5 bits
+    1 << (resolve_flags.bits() & 0x1f)
+}
+
+/// Returns whether `openat2()` should be tried with the given resolve flags.
+#[cfg(target_os = "linux")]
+pub fn openat2_usable(resolve_flags: openat2::ResolveFlags) -> bool {
+    match backend() {
+        Backend::ForceOpenat2 => true,
+        Backend::ForceFallback => false,
+        Backend::Auto => {
+            matches!(
+                OPENAT2_STATE.load(Ordering::Relaxed),
+                OPENAT2_UNKNOWN | OPENAT2_SUPPORTED
+            ) && OPENAT2_E2BIG.load(Ordering::Relaxed) & resolve_bit(resolve_flags) == 0
+        }
+    }
+}
+
+/// Record that `openat2()` succeeded (or failed for reasons unrelated to support).
+#[cfg(target_os = "linux")]
+pub fn openat2_succeeded() {
+    if OPENAT2_STATE.load(Ordering::Relaxed) == OPENAT2_UNKNOWN {
+        OPENAT2_STATE.store(OPENAT2_SUPPORTED, Ordering::Relaxed);
+    }
+}
+
+/// Given an error from `openat2()` with the given resolve flags, record what it says about
+/// `openat2()` support, and return whether we should fall back on the manual implementation.
+#[cfg(target_os = "linux")]
+pub fn openat2_failed(resolve_flags: openat2::ResolveFlags, err: &io::Error) -> bool {
+    let fallback = match err.raw_os_error() {
+        // The kernel doesn't support openat2()
+        Some(libc::ENOSYS) => {
+            OPENAT2_STATE.store(OPENAT2_UNSUPPORTED, Ordering::Relaxed);
+            true
+        }
+
+        // The kernel doesn't support the options that we passed
+        Some(libc::E2BIG) => {
+            OPENAT2_E2BIG.fetch_or(resolve_bit(resolve_flags), Ordering::Relaxed);
+            true
+        }
+
+        // Some seccomp filters block unknown syscalls with EPERM. But EPERM can also be a
+        // legitimate error from open(), so check if openat2() fails with a trivial call.
+        Some(libc::EPERM) if OPENAT2_STATE.load(Ordering::Relaxed) != OPENAT2_SUPPORTED => {
+            let how = openat2::OpenHow::new(libc::O_PATH);
+
+            match openat2::openat2(None, "/", &how) {
+                Ok(fd) => {
+                    unsafe {
+                        libc::close(fd);
+                    }
+                    OPENAT2_STATE.store(OPENAT2_SUPPORTED, Ordering::Relaxed);
+                    false
+                }
+                Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                    OPENAT2_STATE.store(OPENAT2_DENIED, Ordering::Relaxed);
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+
+        _ => {
+            openat2_succeeded();
+            false
+        }
+    };
+
+    fallback && backend() != Backend::ForceOpenat2
+}
+
+/// Forget everything that has been discovered about `openat2()` support.
+#[cfg(all(test, target_os = "linux"))]
+fn reset_openat2_cache() {
+    OPENAT2_STATE.store(OPENAT2_UNKNOWN, Ordering::Relaxed);
+    OPENAT2_E2BIG.store(0, Ordering::Relaxed);
+}
+
+/// Serialize the unit tests that change (or depend on) process-wide state, like the backend and
+/// the `openat2()` support cache.
+#[cfg(all(test, target_os = "linux"))]
+pub(crate) fn lock_global_state() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    // A test that panicked while holding the lock doesn't matter
+    LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    /// Holds the global state lock, and resets the `openat2()` support cache when it is created
+    /// and dropped (even if the test fails).
+    struct CacheGuard {
+        _lock: std::sync::MutexGuard<'static, ()>,
+    }
+
+    impl CacheGuard {
+        fn new() -> Self {
+            let guard = Self {
+                _lock: lock_global_state(),
+            };
+            reset_openat2_cache();
+            guard
+        }
+    }
+
+    impl Drop for CacheGuard {
+        fn drop(&mut self) {
+            reset_openat2_cache();
+        }
+    }
+
+    #[test]
+    fn test_openat2_cache() {
+        let flags = openat2::ResolveFlags::IN_ROOT | openat2::ResolveFlags::NO_MAGICLINKS;
+        let other_flags = flags | openat2::ResolveFlags::NO_SYMLINKS;
+
+        let _guard = CacheGuard::new();
+        assert!(openat2_usable(flags));
+
+        assert!(openat2_failed(
+            flags,
+            &io::Error::from_raw_os_error(libc::E2BIG)
+        ));
+        assert!(!openat2_usable(flags));
+        assert!(openat2_usable(other_flags));
+
+        assert!(!openat2_failed(
+            other_flags,
+            &io::Error::from_raw_os_error(libc::ENOENT)
+        ));
+        assert!(openat2_usable(other_flags));
+
+        assert!(openat2_failed(
+            other_flags,
+            &io::Error::from_raw_os_error(libc::ENOSYS)
+        ));
+        assert!(!openat2_usable(other_flags));
+    }
+}
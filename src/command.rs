@@ -0,0 +1,73 @@
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::Path;
+use std::process::Command;
+
+use openat::Dir;
+
+use crate::{Error, LookupFlags};
+
+/// Extension methods for `std::process::Command` that start the child process in a directory
+/// that was opened securely.
+///
+/// The child changes into the directory with `fchdir()` from a `pre_exec()` hook, so it starts in
+/// exactly the directory that was opened, even if that directory has since been moved or a
+/// symbolic link has been swapped into its old path.
+///
+/// These should not be combined with `Command::current_dir()`: `Command` changes directory
+/// before running `pre_exec()` hooks, so the directory set here takes precedence, but spawning
+/// still fails if the other directory can't be entered. Since `pre_exec()` hooks run in
+/// the order they were added, this also has no effect on a `Command` built by
+/// [`ExecCommand::to_command()`](struct.ExecCommand.html#method.to_command), which executes the
+/// program from its own hook; use
+/// [`ExecCommand::current_dir_fd()`](struct.ExecCommand.html#method.current_dir_fd) instead.
+pub trait CommandSecureExt {
+    /// Start the child process in the given directory.
+    ///
+    /// On Linux, `dir` may be an `O_PATH` file descriptor (like the ones returned by
+    /// [`DirSecureExt::sub_dir_secure()`](trait.DirSecureExt.html#method.sub_dir_secure)).
+    fn current_dir_fd(&mut self, dir: Dir) -> &mut Self;
+
+    /// Start the child process in the subdirectory `path` of `root`.
+    ///
+    /// The directory is opened now, as if by
+    /// [`DirSecureExt::sub_dir_secure()`](trait.DirSecureExt.html#method.sub_dir_secure); see the
+    /// documentation of
+    /// [`DirSecureExt::open_file_secure()`](trait.DirSecureExt.html#method.open_file_secure) for
+    /// security information.
+    fn current_dir_secure<D: AsRawFd + ?Sized, P: AsRef<Path>>(
+        &mut self,
+        root: &D,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<&mut Self, Error>;
+}
+
+impl CommandSecureExt for Command {
+    fn current_dir_fd(&mut self, dir: Dir) -> &mut Self {
+        unsafe { self.pre_exec(move || fchdir(&dir)) }
+    }
+
+    fn current_dir_secure<D: AsRawFd + ?Sized, P: AsRef<Path>>(
+        &mut self,
+        root: &D,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<&mut Self, Error> {
+        let path = path.as_ref();
+        let dir = crate::open_sub_dir(root, path, lookup_flags)
+            .map_err(|e| e.context("current_dir_secure", path))?;
+
+        Ok(self.current_dir_fd(dir))
+    }
+}
+
+/// Change the current directory of this process (this is async-signal-safe, so it can be called
+/// after `fork()`).
+pub(crate) fn fchdir(dir: &Dir) -> io::Result<()> {
+    if unsafe { libc::fchdir(dir.as_raw_fd()) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
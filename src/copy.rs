@@ -0,0 +1,380 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use openat::Dir;
+
+use crate::{error, util, Error, LookupFlags};
+
+/// The number of temporary names that will be tried before giving up.
+const TEMP_ATTEMPTS: u32 = 100;
+
+static TEMP_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// The maximum length of a file name on most filesystems.
+const NAME_MAX: usize = 255;
+
+/// Options for [`copy_file_secure()`](fn.copy_file_secure.html).
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub struct CopyOptions {
+    /// The lookup flags used to resolve both the source and the destination.
+    pub lookup_flags: LookupFlags,
+    /// Fail with `EEXIST` instead of replacing the destination if it already exists.
+    pub no_clobber: bool,
+    /// Copy the access and modification times of the source.
+    pub preserve_timestamps: bool,
+    /// Copy the extended attributes of the source (only supported on Linux; elsewhere, the copy
+    /// fails with `ENOTSUP`).
+    ///
+    /// Attributes that can't be set on the destination (because of permissions, or because its
+    /// filesystem doesn't support them) are skipped.
+    pub preserve_xattrs: bool,
+}
+
+impl CopyOptions {
+    /// Set the lookup flags.
+    pub fn lookup_flags(mut self, lookup_flags: LookupFlags) -> Self {
+        self.lookup_flags = lookup_flags;
+        self
+    }
+
+    /// Set whether an existing destination should be left alone.
+    pub fn no_clobber(mut self, no_clobber: bool) -> Self {
+        self.no_clobber = no_clobber;
+        self
+    }
+
+    /// Set whether the access and modification times should be copied.
+    pub fn preserve_timestamps(mut self, preserve_timestamps: bool) -> Self {
+        self.preserve_timestamps = preserve_timestamps;
+        self
+    }
+
+    /// Set whether extended attributes should be copied.
+    pub fn preserve_xattrs(mut self, preserve_xattrs: bool) -> Self {
+        self.preserve_xattrs = preserve_xattrs;
+        self
+    }
+}
+
+/// Copy the regular file at `src` (relative to `src_dir`) to `dst` (relative to `dst_dir`),
+/// returning the number of bytes copied.
+///
+/// Both paths are resolved as if by
+/// [`DirSecureExt::open_file_secure()`](trait.DirSecureExt.html#method.open_file_secure) (a
+/// symbolic link in the final component of `src` is followed, but one in the final component of
+/// `dst` is replaced).
+///
+/// The contents are written to a temporary file in the directory that will contain `dst`, which
+/// is then renamed over `dst`, so other processes never see a partially written file. The data is
+/// copied with the `FICLONE` ioctl (a reflink) if the filesystem supports it, or else with
+/// `copy_file_range()`, `sendfile()`, or plain reads and writes. The permission bits of the source
+/// are always copied; its timestamps and extended attributes are copied if requested in
+/// `options`. The owner is not copied.
+///
+/// If `options.no_clobber` is set, the temporary file is hard-linked to `dst` instead of being
+/// renamed over it, so this fails with `EEXIST` (without changing anything) if `dst` exists.
+pub fn copy_file_secure<D1, D2, P, R>(
+    src_dir: &D1,
+    src: P,
+    dst_dir: &D2,
+    dst: R,
+    options: &CopyOptions,
+) -> Result<u64, Error>
+where
+    D1: AsRawFd + ?Sized,
+    D2: AsRawFd + ?Sized,
+    P: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+
+    // With O_NONBLOCK, opening a FIFO that someone planted at `src` doesn't block waiting for a
+    // writer
+    let src_file = crate::open_file(
+        src_dir,
+        "copy_file_secure",
+        src,
+        options.lookup_flags,
+        libc::O_RDONLY | libc::O_NOCTTY | libc::O_NONBLOCK,
+        0,
+    )?;
+
+    let src_meta = error::with_context("copy_file_secure", src, || {
+        let meta = src_file.metadata()?;
+
+        if meta.is_dir() {
+            return Err(io::Error::from_raw_os_error(libc::EISDIR).into());
+        } else if !meta.is_file() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL).into());
+        }
+
+        // It's a regular file, so reads can go back to blocking
+        let fd = src_file.as_raw_fd();
+        let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+        if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(meta)
+    })?;
+
+    error::with_context("copy_file_secure", dst, || {
+        let dst_root = util::borrow_dir(dst_dir);
+        let (dst_subdir, dst_fname) =
+            crate::prepare_inner_operation(dst_dir, dst, options.lookup_flags)?;
+        let dst_subdir = dst_subdir.as_ref().unwrap_or(&dst_root);
+
+        let dst_fname = match dst_fname {
+            Some(fname) if !fname.as_bytes().ends_with(b"/") => fname,
+            // "dst/", ".", "a/..", etc.
+            _ => return Err(io::Error::from_raw_os_error(libc::EISDIR).into()),
+        };
+
+        if options.no_clobber && dst_subdir.metadata(dst_fname).is_ok() {
+            return Err(error::at_final(
+                dst,
+                io::Error::from_raw_os_error(libc::EEXIST),
+            ));
+        }
+
+        let (tmp_name, mut tmp_file) = create_temp(dst_subdir, dst_fname)?;
+
+        let res = fill_temp(&src_file, &src_meta, &mut tmp_file, options).and_then(|n| {
+            if options.no_clobber {
+                openat::hardlink(dst_subdir, tmp_name.as_c_str(), dst_subdir, dst_fname)?;
+                dst_subdir.remove_file(tmp_name.as_c_str())?;
+            } else {
+                openat::rename(dst_subdir, tmp_name.as_c_str(), dst_subdir, dst_fname)?;
+            }
+            Ok(n)
+        });
+
+        res.map_err(|e| {
+            // Don't leave the temporary file behind
+            let _ = dst_subdir.remove_file(tmp_name.as_c_str());
+            error::at_final(dst, e)
+        })
+    })
+}
+
+/// Create a new temporary file next to `fname` in `dir`.
+fn create_temp(dir: &Dir, fname: &OsStr) -> io::Result<(CString, fs::File)> {
+    let pid = std::process::id();
+
+    for _ in 0..TEMP_ATTEMPTS {
+        let suffix = format!(
+            ".{}.{}.tmp",
+            pid,
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+
+        // Shorten the destination name as necessary so the whole name still fits in NAME_MAX
+        let fname = fname.as_bytes();
+        let fname = &fname[..fname.len().min(NAME_MAX - 1 - suffix.len())];
+
+        let mut name = OsString::from(".");
+        name.push(OsStr::from_bytes(fname));
+        name.push(suffix);
+
+        let name = CString::new(name.into_vec())?;
+        let fd = unsafe {
+            libc::openat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+                0o600 as libc::c_uint,
+            )
+        };
+
+        if fd >= 0 {
+            return Ok((name, unsafe { fs::File::from_raw_fd(fd) }));
+        }
+
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EEXIST) {
+            return Err(err);
+        }
+    }
+
+    Err(io::Error::from_raw_os_error(libc::EEXIST))
+}
+
+/// Copy the data and metadata of `src` into the temporary file.
+fn fill_temp(
+    src: &fs::File,
+    src_meta: &fs::Metadata,
+    tmp: &mut fs::File,
+    options: &CopyOptions,
+) -> io::Result<u64> {
+    let n = copy_data(src, tmp, src_meta.len())?;
+
+    if options.preserve_xattrs {
+        copy_xattrs(src, tmp)?;
+    }
+
+    // After the extended attributes, in case copying them changed the mode (e.g. POSIX ACLs)
+    if unsafe { libc::fchmod(tmp.as_raw_fd(), (src_meta.mode() & 0o7777) as libc::mode_t) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The timestamps from before the copy (since reading the file may have updated the access
+    // time)
+    if options.preserve_timestamps {
+        let times = [
+            libc::timespec {
+                tv_sec: src_meta.atime() as _,
+                tv_nsec: src_meta.atime_nsec() as _,
+            },
+            libc::timespec {
+                tv_sec: src_meta.mtime() as _,
+                tv_nsec: src_meta.mtime_nsec() as _,
+            },
+        ];
+
+        if unsafe { libc::futimens(tmp.as_raw_fd(), times.as_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(n)
+}
+
+/// Returns whether an error from a copy offload mechanism means that the next one should be tried.
+#[cfg(target_os = "linux")]
+fn unsupported(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ENOSYS)
+            | Some(libc::EOPNOTSUPP)
+            | Some(libc::ENOTTY)
+            | Some(libc::EXDEV)
+            | Some(libc::EINVAL)
+            | Some(libc::EPERM)
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn copy_data(src: &fs::File, dst: &mut fs::File, size: u64) -> io::Result<u64> {
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == 0 {
+        return Ok(size);
+    }
+    let err = io::Error::last_os_error();
+    if !unsupported(&err) {
+        return Err(err);
+    }
+
+    let mut copied = 0;
+
+    // The file may be on a filesystem that reports a size of 0 (like procfs), in which case we
+    // go straight to read()/write()
+    if size > 0 {
+        // copy_file_range(), and then sendfile(); each of these can fail immediately if it isn't
+        // supported, but not partway through.
+        for &use_sendfile in [false, true].iter() {
+            loop {
+                let n = unsafe {
+                    if use_sendfile {
+                        libc::sendfile(
+                            dst.as_raw_fd(),
+                            src.as_raw_fd(),
+                            std::ptr::null_mut(),
+                            1 << 30,
+                        ) as libc::c_long
+                    } else {
+                        libc::syscall(
+                            libc::SYS_copy_file_range,
+                            src.as_raw_fd(),
+                            std::ptr::null_mut::<libc::loff_t>(),
+                            dst.as_raw_fd(),
+                            std::ptr::null_mut::<libc::loff_t>(),
+                            1usize << 30,
+                            0,
+                        )
+                    }
+                };
+
+                if n < 0 {
+                    let err = io::Error::last_os_error();
+                    if copied == 0 && unsupported(&err) {
+                        break;
+                    }
+                    return Err(err);
+                } else if n == 0 {
+                    if copied == 0 {
+                        // Nothing was copied; maybe the size was wrong, so fall back to reading
+                        break;
+                    }
+                    return Ok(copied);
+                }
+
+                copied += n as u64;
+            }
+        }
+    }
+
+    Ok(copied + io::copy(&mut &*src, dst)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_data(src: &fs::File, dst: &mut fs::File, _size: u64) -> io::Result<u64> {
+    io::copy(&mut &*src, dst)
+}
+
+#[cfg(target_os = "linux")]
+fn copy_xattrs(src: &fs::File, dst: &fs::File) -> io::Result<()> {
+    use crate::path_handle::read_sized;
+
+    let names = read_sized(|buf, size| unsafe {
+        libc::flistxattr(src.as_raw_fd(), buf as *mut libc::c_char, size)
+    })?;
+
+    for name in names.split(|&c| c == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name)?;
+
+        let value = match read_sized(|buf, size| unsafe {
+            libc::fgetxattr(
+                src.as_raw_fd(),
+                name.as_ptr(),
+                buf as *mut libc::c_void,
+                size,
+            )
+        }) {
+            Ok(value) => value,
+            // It was removed after it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(e) => return Err(e),
+        };
+
+        if unsafe {
+            libc::fsetxattr(
+                dst.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        } < 0
+        {
+            let err = io::Error::last_os_error();
+            // Like `cp --preserve=xattr`, skip attributes that we aren't allowed to set (like
+            // "trusted.*" ones without CAP_SYS_ADMIN) or that the destination doesn't support
+            if !matches!(
+                err.raw_os_error(),
+                Some(libc::EPERM | libc::EACCES | libc::ENOTSUP)
+            ) {
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_xattrs(_src: &fs::File, _dst: &fs::File) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+}
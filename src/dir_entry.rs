@@ -0,0 +1,222 @@
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use openat::{Dir, SimpleType};
+
+use crate::{error, Error, LookupFlags};
+
+/// An iterator over the entries in a directory.
+///
+/// Created by [`DirSecureExt::list_dir_secure()`](trait.DirSecureExt.html#method.list_dir_secure).
+/// The `.` and `..` entries are skipped.
+#[derive(Debug)]
+pub struct DirIter {
+    dir: Arc<Dir>,
+    origin: Option<Arc<Origin>>,
+    #[cfg(target_os = "linux")]
+    buf: crate::getdents::GetdentsBuf,
+    #[cfg(not(target_os = "linux"))]
+    iter: openat::DirIter,
+}
+
+// `openat::DirIter` wraps a `DIR *`, which can safely be moved between threads as long as it is
+// only used by one thread at a time.
+#[cfg(not(target_os = "linux"))]
+unsafe impl Send for DirIter {}
+
+impl DirIter {
+    /// `dir` must have been opened for reading. Symbolic links in it are resolved with `dir` as
+    /// the root.
+    pub(crate) fn new(dir: Dir) -> io::Result<Self> {
+        Self::with_origin(dir, None)
+    }
+
+    /// Like `new()`, but symbolic links are resolved as if `dir` was opened from `origin`.
+    pub(crate) fn with_origin(dir: Dir, origin: Option<Arc<Origin>>) -> io::Result<Self> {
+        Ok(Self {
+            origin,
+            #[cfg(target_os = "linux")]
+            buf: crate::getdents::GetdentsBuf::new(crate::getdents::DEFAULT_BUF_SIZE),
+            #[cfg(not(target_os = "linux"))]
+            iter: dir.list_self()?,
+            dir: Arc::new(dir),
+        })
+    }
+
+    /// The directory that is being listed.
+    pub fn dir(&self) -> &Dir {
+        &self.dir
+    }
+}
+
+impl Iterator for DirIter {
+    type Item = io::Result<DirEntry>;
+
+    #[cfg(target_os = "linux")]
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.buf.next(self.dir.as_raw_fd())?;
+
+        Some(entry.map(|entry| DirEntry {
+            dir: self.dir.clone(),
+            origin: self.origin.clone(),
+            file_type: entry.file_type(),
+            name: OsString::from_vec(entry.into_file_name().into_vec()),
+        }))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.iter.next()?;
+
+        Some(entry.map(|entry| DirEntry {
+            dir: self.dir.clone(),
+            origin: self.origin.clone(),
+            file_type: entry.simple_type(),
+            name: entry.file_name().to_os_string(),
+        }))
+    }
+}
+
+/// The root directory that a listed directory was opened from, and its path relative to that
+/// root.
+#[derive(Debug)]
+pub(crate) struct Origin {
+    pub root: Arc<Dir>,
+    pub path: PathBuf,
+}
+
+/// An entry in a directory, yielded by [`DirIter`](struct.DirIter.html).
+///
+/// The entry keeps the directory that it came from open, and its methods operate relative to that
+/// directory. So (unlike joining the name onto the directory's path and looking it up again) they
+/// can't be redirected by concurrent renames of the directory or its ancestors.
+///
+/// The one exception is opening an entry that is a symbolic link: it is resolved against the
+/// root directory that was passed to
+/// [`DirSecureExt::list_dir_secure()`](trait.DirSecureExt.html#method.list_dir_secure), by
+/// looking up the directory's path followed by the entry's name again.
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    dir: Arc<Dir>,
+    origin: Option<Arc<Origin>>,
+    name: OsString,
+    file_type: Option<SimpleType>,
+}
+
+impl DirEntry {
+    /// The name of this entry.
+    pub fn file_name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// The type of this entry, as reported by the directory listing (without making any extra
+    /// system calls).
+    ///
+    /// This may be `None` if the filesystem does not report file types; use
+    /// [`metadata()`](#method.metadata) to get the type in that case.
+    pub fn file_type(&self) -> Option<SimpleType> {
+        self.file_type
+    }
+
+    /// The directory containing this entry.
+    pub fn dir(&self) -> &Dir {
+        &self.dir
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn shared_dir(&self) -> &Arc<Dir> {
+        &self.dir
+    }
+
+    /// Query the metadata of this entry, without following symbolic links.
+    pub fn metadata(&self) -> Result<openat::Metadata, Error> {
+        let name = self.file_name();
+
+        error::with_context("DirEntry::metadata", Path::new(name), || {
+            self.dir
+                .metadata(name)
+                .map_err(|e| error::at_final(Path::new(name), e))
+        })
+    }
+
+    /// Open this entry as read-only.
+    ///
+    /// If it is a symbolic link, it is resolved as if by
+    /// [`DirSecureExt::open_file_secure()`](trait.DirSecureExt.html#method.open_file_secure),
+    /// relative to the root directory that the listing was started from.
+    pub fn open_secure(&self, lookup_flags: LookupFlags) -> Result<fs::File, Error> {
+        self.open_with(lookup_flags, |dir, path, lookup_flags| {
+            crate::open_file(
+                dir,
+                "DirEntry::open_secure",
+                path,
+                lookup_flags,
+                libc::O_RDONLY,
+                0,
+            )
+        })
+    }
+
+    /// Open this entry as a directory.
+    ///
+    /// If it is a symbolic link, it is resolved as if by
+    /// [`DirSecureExt::sub_dir_secure()`](trait.DirSecureExt.html#method.sub_dir_secure),
+    /// relative to the root directory that the listing was started from.
+    pub fn sub_dir_secure(&self, lookup_flags: LookupFlags) -> Result<Dir, Error> {
+        self.open_with(lookup_flags, |dir, path, lookup_flags| {
+            crate::open_sub_dir(dir, path, lookup_flags)
+                .map_err(|e| e.context("DirEntry::sub_dir_secure", path))
+        })
+    }
+
+    /// Open this entry with `open` in the containing directory, without following symlinks. If it
+    /// is a symlink, open it from the original root instead, so that absolute targets and `..`
+    /// components are resolved the same way they would have been by the original lookup.
+    fn open_with<T, F>(&self, lookup_flags: LookupFlags, open: F) -> Result<T, Error>
+    where
+        F: Fn(&Dir, &Path, LookupFlags) -> Result<T, Error>,
+    {
+        let name = Path::new(self.file_name());
+
+        let origin = match self.origin.as_ref() {
+            Some(origin) => origin,
+            None => return open(&self.dir, name, lookup_flags),
+        };
+
+        match open(&self.dir, name, lookup_flags | LookupFlags::NO_SYMLINKS) {
+            Err(e)
+                if e.raw_os_error() == Some(libc::ELOOP)
+                    && !lookup_flags.contains(LookupFlags::NO_SYMLINKS) =>
+            {
+                open(&origin.root, &origin.path.join(name), lookup_flags)
+            }
+            res => res,
+        }
+    }
+
+    /// Remove this entry (with `rmdir()` if it is a directory, and `unlink()` otherwise).
+    ///
+    /// Symbolic links are removed, not followed.
+    pub fn remove(&self) -> Result<(), Error> {
+        let name = self.file_name();
+
+        error::with_context("DirEntry::remove", Path::new(name), || {
+            let ftype = match self.file_type() {
+                Some(ftype) => ftype,
+                None => self.metadata()?.simple_type(),
+            };
+
+            if ftype == SimpleType::Dir {
+                self.dir.remove_dir(name)
+            } else {
+                self.dir.remove_file(name)
+            }
+            .map_err(|e| error::at_final(Path::new(name), e))
+        })
+    }
+}
@@ -0,0 +1,252 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// The category of an [`Error`](struct.Error.html).
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The path tried to escape the root directory, and the operation was refused rather than
+    /// clamping the path to the root directory.
+    EscapeAttempt,
+    /// Too many symbolic links were encountered during resolution, or a symbolic link was found
+    /// and `LookupFlags::NO_SYMLINKS` was passed.
+    SymlinkLoop,
+    /// Resolution crossed a filesystem boundary and `LookupFlags::NO_XDEV` was passed (or an
+    /// operation such as a rename was attempted across filesystems).
+    CrossDevice,
+    /// A concurrent modification of the directory tree was detected during resolution. Retrying
+    /// the operation may succeed.
+    Race,
+    /// A component of the path that had to be a directory was not a directory.
+    NotADirectory,
+    /// `openat2()` and the manual implementation disagreed about the result of a lookup with
+    /// `LookupFlags::PARANOID`. This indicates a bug in this crate (or in the kernel).
+    BackendMismatch,
+    /// Any other error; see the underlying `io::Error`.
+    Other,
+}
+
+impl ErrorKind {
+    fn from_errno(errno: Option<i32>) -> Self {
+        match errno {
+            Some(libc::ELOOP) => Self::SymlinkLoop,
+            Some(libc::EXDEV) => Self::CrossDevice,
+            Some(libc::ENOTDIR) => Self::NotADirectory,
+            _ => Self::Other,
+        }
+    }
+
+    fn description(self) -> Option<&'static str> {
+        match self {
+            Self::EscapeAttempt => Some("path escapes the root directory"),
+            Self::SymlinkLoop => Some("too many symbolic links"),
+            Self::CrossDevice => Some("crossed a filesystem boundary"),
+            Self::Race => Some("concurrent modification detected"),
+            Self::NotADirectory => Some("not a directory"),
+            Self::BackendMismatch => Some("path resolution backends disagree"),
+            Self::Other => None,
+        }
+    }
+}
+
+/// The error type returned by the `*_secure` operations in this crate.
+///
+/// In addition to the underlying OS error, this records what kind of failure occurred, the name
+/// of the operation that failed, the path it was operating on, and (if known) the index of the
+/// path component at which the failure occurred.
+///
+/// This can be converted into an `io::Error` (so the `?` operator works in functions returning
+/// `io::Result`). If it was caused by an OS error, the conversion returns the underlying
+/// `io::Error`, so `raw_os_error()` still works but the extra information is discarded;
+/// otherwise, the resulting error wraps this one, and it can be recovered with
+/// `io_err.get_ref().and_then(|e| e.downcast_ref::<openat_secure::Error>())`.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    op: &'static str,
+    path: Option<PathBuf>,
+    component: Option<usize>,
+    retries: u32,
+    source: io::Error,
+}
+
+impl Error {
+    pub(crate) fn new(kind: ErrorKind, source: io::Error) -> Self {
+        Self {
+            kind,
+            op: "",
+            path: None,
+            component: None,
+            retries: 0,
+            source,
+        }
+    }
+
+    pub(crate) fn from_raw_os_error(kind: ErrorKind, errno: i32) -> Self {
+        Self::new(kind, io::Error::from_raw_os_error(errno))
+    }
+
+    /// Set the index of the failing path component, if it has not already been set.
+    pub(crate) fn at_component(mut self, index: Option<usize>) -> Self {
+        if self.component.is_none() {
+            self.component = index;
+        }
+        self
+    }
+
+    /// Set the index of the failing path component, replacing any existing index.
+    pub(crate) fn set_component(mut self, index: Option<usize>) -> Self {
+        self.component = index;
+        self
+    }
+
+    /// Shift the index of the failing path component (used when a prefix of the path was
+    /// stripped before resolution).
+    pub(crate) fn shift_component(mut self, n: usize) -> Self {
+        if let Some(component) = self.component.as_mut() {
+            *component += n;
+        }
+        self
+    }
+
+    pub(crate) fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Record the operation, if it has not already been set.
+    pub(crate) fn with_operation(mut self, op: &'static str) -> Self {
+        if self.op.is_empty() {
+            self.op = op;
+        }
+        self
+    }
+
+    /// Record the operation and path, if they have not already been set.
+    pub(crate) fn context(mut self, op: &'static str, path: &Path) -> Self {
+        if self.op.is_empty() {
+            self.op = op;
+        }
+        if self.path.is_none() {
+            self.path = Some(path.to_path_buf());
+        }
+        self
+    }
+
+    /// The kind of failure that occurred.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The name of the operation that failed (for example, `"open_file_secure"`).
+    pub fn operation(&self) -> &'static str {
+        self.op
+    }
+
+    /// The path that the operation was performed on.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// The index (in `path().components()`) of the component at which the failure occurred, if
+    /// known.
+    ///
+    /// If the failure occurred while resolving the target of a symbolic link, this is the index
+    /// of the symbolic link.
+    pub fn component(&self) -> Option<usize> {
+        self.component
+    }
+
+    /// The name of the component at which the failure occurred, if known.
+    pub fn component_name(&self) -> Option<&OsStr> {
+        let component = self.path.as_ref()?.components().nth(self.component?)?;
+
+        Some(match component {
+            Component::Normal(name) => name,
+            other => other.as_os_str(),
+        })
+    }
+
+    /// The number of times path resolution was retried (because of race conditions) before the
+    /// operation failed. See [`RetryPolicy`](struct.RetryPolicy.html).
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The OS error number, if this error was caused by an OS error.
+    pub fn raw_os_error(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+
+    /// The underlying `io::Error`.
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+
+    /// Discard the extra information and return the underlying `io::Error`.
+    pub fn into_io_error(self) -> io::Error {
+        self.source
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.op.is_empty() {
+            write!(f, "{}", self.op)?;
+
+            if let Some(path) = self.path.as_ref() {
+                write!(f, "({:?})", path)?;
+            }
+            f.write_str(": ")?;
+        }
+
+        if let Some(desc) = self.kind.description() {
+            write!(f, "{}", desc)?;
+
+            if let Some(name) = self.component_name() {
+                write!(f, " at {:?}", name)?;
+            }
+            f.write_str(": ")?;
+        }
+
+        write!(f, "{}", self.source)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Self::new(ErrorKind::from_errno(e.raw_os_error()), e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        // Preserve the error number for callers that match on it
+        if e.source.raw_os_error().is_some() {
+            e.source
+        } else {
+            io::Error::new(e.source.kind(), e)
+        }
+    }
+}
+
+/// Run `f`, attaching the given operation name and path to any error it returns.
+pub(crate) fn with_context<T, F>(op: &'static str, path: &Path, f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    f().map_err(|e| e.context(op, path))
+}
+
+/// Attribute an error from an operation on the final component of `path` to that component.
+pub(crate) fn at_final(path: &Path, e: io::Error) -> Error {
+    Error::from(e).at_component(path.components().count().checked_sub(1))
+}
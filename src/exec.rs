@@ -0,0 +1,463 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use openat::Dir;
+
+use crate::{Error, LookupFlags};
+
+/// The maximum number of nested interpreters (the same as Linux's `BINPRM_MAX_RECURSION`).
+const MAX_INTERPRETERS: usize = 4;
+
+/// The number of bytes of a script that are examined for a `#!` line (the same as Linux's
+/// `BINPRM_BUF_SIZE`).
+const SHEBANG_BUF_SIZE: usize = 256;
+
+/// An executable file that has been opened securely, and can be run without resolving its path
+/// again.
+///
+/// Created by
+/// [`DirSecureExt::open_executable_secure()`](trait.DirSecureExt.html#method.open_executable_secure).
+/// Use [`command()`](#method.command) to build a `std::process::Command` that runs it.
+#[derive(Debug)]
+pub struct Executable {
+    path: PathBuf,
+    file: fs::File,
+    interpreter: Option<Box<Interpreter>>,
+}
+
+#[derive(Debug)]
+struct Interpreter {
+    /// The interpreter path, exactly as it appeared in the `#!` line.
+    path: PathBuf,
+    arg: Option<OsString>,
+    exe: Executable,
+}
+
+impl Executable {
+    /// The path that this executable was opened with.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The open file.
+    ///
+    /// This is opened read-only if possible; otherwise (on Linux), it is an `O_PATH` file
+    /// descriptor.
+    pub fn file(&self) -> &fs::File {
+        &self.file
+    }
+
+    /// If this is a script, the executable that its `#!` line refers to.
+    pub fn interpreter(&self) -> Option<&Executable> {
+        self.interpreter.as_ref().map(|interp| &interp.exe)
+    }
+
+    /// Start building a command that runs this executable.
+    ///
+    /// By default, `argv[0]` is the path that the executable was opened with, there are no other
+    /// arguments, and the environment is inherited.
+    pub fn command(&self) -> ExecCommand<'_> {
+        ExecCommand {
+            exe: self,
+            arg0: self.path.as_os_str().to_os_string(),
+            args: Vec::new(),
+            env_clear: false,
+            envs: Vec::new(),
+            cwd: None,
+        }
+    }
+}
+
+/// A builder for a `std::process::Command` that runs an [`Executable`](struct.Executable.html).
+///
+/// The arguments and environment must be set here, and not on the `Command` returned by
+/// [`to_command()`](#method.to_command): the executable is started with
+/// `execveat(fd, "", AT_EMPTY_PATH)` (or `fexecve()`) from a `pre_exec()` hook, which runs before
+/// `Command` applies its own environment changes. Everything else (standard I/O, working
+/// directory, user and group IDs, etc.) can be configured on the `Command`, except that further
+/// `pre_exec()` hooks would never run; this includes the ones added by
+/// [`CommandSecureExt`](trait.CommandSecureExt.html), so use
+/// [`current_dir_fd()`](#method.current_dir_fd) to start in a directory that was opened securely.
+#[derive(Debug)]
+pub struct ExecCommand<'a> {
+    exe: &'a Executable,
+    arg0: OsString,
+    args: Vec<OsString>,
+    env_clear: bool,
+    envs: Vec<(OsString, Option<OsString>)>,
+    cwd: Option<Dir>,
+}
+
+impl ExecCommand<'_> {
+    /// Set `argv[0]`.
+    pub fn arg0<S: AsRef<OsStr>>(&mut self, arg0: S) -> &mut Self {
+        self.arg0 = arg0.as_ref().to_os_string();
+        self
+    }
+
+    /// Add an argument.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Add several arguments.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_os_string()));
+        self
+    }
+
+    /// Set an environment variable.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Self {
+        self.envs.push((
+            key.as_ref().to_os_string(),
+            Some(val.as_ref().to_os_string()),
+        ));
+        self
+    }
+
+    /// Set several environment variables.
+    pub fn envs<I, K, V>(&mut self, vars: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        for (key, val) in vars {
+            self.env(key, val);
+        }
+        self
+    }
+
+    /// Remove an environment variable.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+        self.envs.push((key.as_ref().to_os_string(), None));
+        self
+    }
+
+    /// Don't inherit any environment variables from this process.
+    pub fn env_clear(&mut self) -> &mut Self {
+        self.env_clear = true;
+        self.envs.clear();
+        self
+    }
+
+    /// Start the child process in the given directory (with `fchdir()`, just before the
+    /// executable is started).
+    ///
+    /// See [`CommandSecureExt::current_dir_fd()`](trait.CommandSecureExt.html#tymethod.current_dir_fd).
+    pub fn current_dir_fd(&mut self, dir: Dir) -> &mut Self {
+        self.cwd = Some(dir);
+        self
+    }
+
+    /// Build the `Command`.
+    ///
+    /// The environment is computed now (from this process's environment, unless
+    /// [`env_clear()`](#method.env_clear) was called), and the file descriptors of the executable
+    /// (and any interpreters, and the working directory) are duplicated, so the `Command` does not
+    /// borrow anything.
+    ///
+    /// This fails with `ENOTSUP` on platforms that can't execute a file descriptor (other than
+    /// Linux, FreeBSD, and DragonFly BSD).
+    pub fn to_command(&self) -> io::Result<Command> {
+        if !cfg!(any(
+            target_os = "linux",
+            target_os = "freebsd",
+            target_os = "dragonfly"
+        )) {
+            return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+
+        let mut env: Vec<(OsString, OsString)> = if self.env_clear {
+            Vec::new()
+        } else {
+            std::env::vars_os().collect()
+        };
+        for (key, val) in self.envs.iter() {
+            env.retain(|(k, _)| k != key);
+            if let Some(val) = val {
+                env.push((key.clone(), val.clone()));
+            }
+        }
+
+        // Work out the interpreter chain, like the kernel does. Each script is passed to its
+        // interpreter as /dev/fd/N (after clearing FD_CLOEXEC in the child), since its path can't
+        // be trusted to refer to the same file.
+        let mut files = Vec::new();
+        let mut argv: Vec<OsString> = std::iter::once(self.arg0.clone())
+            .chain(self.args.iter().cloned())
+            .collect();
+        let mut exe = self.exe;
+        let mut exe_file = exe.file.try_clone()?;
+
+        while let Some(interp) = exe.interpreter.as_ref() {
+            let mut new_argv = vec![interp.path.clone().into_os_string()];
+            new_argv.extend(interp.arg.clone());
+            new_argv.push(format!("/dev/fd/{}", exe_file.as_raw_fd()).into());
+            new_argv.extend(argv.drain(1..));
+            argv = new_argv;
+
+            files.push(exe_file);
+            exe = &interp.exe;
+            exe_file = exe.file.try_clone()?;
+        }
+
+        let c_argv = to_cstrings(argv.iter().map(|arg| arg.as_bytes().to_vec()))?;
+        let c_envp = to_cstrings(env.iter().map(|(key, val)| {
+            let mut var = key.as_bytes().to_vec();
+            var.push(b'=');
+            var.extend_from_slice(val.as_bytes());
+            var
+        }))?;
+
+        let exec = ExecState {
+            #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+            argv_ptrs: to_ptrs(&c_argv),
+            #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+            envp_ptrs: to_ptrs(&c_envp),
+            _argv: c_argv,
+            _envp: c_envp,
+            #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+            file: exe_file,
+            scripts: files,
+            cwd: self.cwd.as_ref().map(crate::util::dup_dir).transpose()?,
+        };
+
+        let mut cmd = Command::new(&self.exe.path);
+        cmd.arg0(&self.arg0).args(&self.args);
+        if self.env_clear {
+            cmd.env_clear();
+        }
+        for (key, val) in self.envs.iter() {
+            match val {
+                Some(val) => cmd.env(key, val),
+                None => cmd.env_remove(key),
+            };
+        }
+
+        unsafe {
+            cmd.pre_exec(move || exec.exec());
+        }
+
+        Ok(cmd)
+    }
+}
+
+/// Everything needed to call `execveat()` in the child (prepared in advance, since allocating
+/// after `fork()` is not safe).
+///
+/// The pointers and the executable itself are only needed on platforms that can execute a file
+/// descriptor (elsewhere, `ExecCommand::to_command()` fails before this is built).
+struct ExecState {
+    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+    argv_ptrs: Vec<*const libc::c_char>,
+    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+    envp_ptrs: Vec<*const libc::c_char>,
+    // Keep the strings that the pointers refer to alive
+    _argv: Vec<CString>,
+    _envp: Vec<CString>,
+    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+    file: fs::File,
+    scripts: Vec<fs::File>,
+    cwd: Option<Dir>,
+}
+
+// The raw pointers refer to the owned strings, which are never modified
+unsafe impl Send for ExecState {}
+unsafe impl Sync for ExecState {}
+
+impl ExecState {
+    /// Replace the current process (only returns on error).
+    fn exec(&self) -> io::Result<()> {
+        if let Some(cwd) = self.cwd.as_ref() {
+            crate::command::fchdir(cwd)?;
+        }
+
+        for script in self.scripts.iter() {
+            if unsafe { libc::fcntl(script.as_raw_fd(), libc::F_SETFD, 0) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            unsafe {
+                libc::syscall(
+                    libc::SYS_execveat,
+                    self.file.as_raw_fd(),
+                    b"\0".as_ptr() as *const libc::c_char,
+                    self.argv_ptrs.as_ptr(),
+                    self.envp_ptrs.as_ptr(),
+                    libc::AT_EMPTY_PATH,
+                );
+            }
+
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ENOSYS) {
+                return Err(err);
+            }
+        }
+
+        #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+        unsafe {
+            libc::fexecve(
+                self.file.as_raw_fd(),
+                self.argv_ptrs.as_ptr(),
+                self.envp_ptrs.as_ptr(),
+            );
+            return Err(io::Error::last_os_error());
+        }
+
+        #[allow(unreachable_code)]
+        Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+    }
+}
+
+fn to_cstrings<I: Iterator<Item = Vec<u8>>>(strings: I) -> io::Result<Vec<CString>> {
+    strings
+        .map(|s| CString::new(s).map_err(io::Error::from))
+        .collect()
+}
+
+#[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "dragonfly"))]
+fn to_ptrs(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect()
+}
+
+/// Open the executable at `path`, and (recursively) the interpreter named in its `#!` line, if
+/// any. Interpreter paths are resolved inside `dir` too.
+pub fn open_executable<D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+    depth: usize,
+) -> Result<Executable, Error> {
+    let file = match crate::open_file(
+        dir,
+        "open_executable_secure",
+        path,
+        lookup_flags,
+        libc::O_RDONLY | libc::O_NOCTTY,
+        0,
+    ) {
+        Ok(file) => file,
+        // It may be executable without being readable (in which case it can't be a script)
+        #[cfg(target_os = "linux")]
+        Err(e) if e.raw_os_error() == Some(libc::EACCES) => crate::open_file(
+            dir,
+            "open_executable_secure",
+            path,
+            lookup_flags,
+            libc::O_PATH,
+            0,
+        )?,
+        Err(e) => return Err(e),
+    };
+
+    let st = crate::util::fstat(file.as_raw_fd())
+        .map_err(|e| Error::from(e).context("open_executable_secure", path))?;
+    if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+        return Err(Error::from(io::Error::from_raw_os_error(libc::EACCES))
+            .context("open_executable_secure", path));
+    }
+
+    let interpreter = match read_shebang(&file)
+        .map_err(|e| Error::from(e).context("open_executable_secure", path))?
+    {
+        Some((interp_path, arg)) => {
+            if depth >= MAX_INTERPRETERS {
+                // (Not a symlink loop, so this isn't ErrorKind::SymlinkLoop)
+                return Err(
+                    Error::from_raw_os_error(crate::ErrorKind::Other, libc::ELOOP)
+                        .context("open_executable_secure", path),
+                );
+            }
+
+            let exe = open_executable(dir, &interp_path, lookup_flags, depth + 1)?;
+            Some(Box::new(Interpreter {
+                path: interp_path,
+                arg,
+                exe,
+            }))
+        }
+        None => None,
+    };
+
+    Ok(Executable {
+        path: path.to_path_buf(),
+        file,
+        interpreter,
+    })
+}
+
+/// If the file starts with a `#!` line, parse the interpreter and the optional argument (which,
+/// as on Linux, is the rest of the line with surrounding whitespace removed).
+fn read_shebang(file: &fs::File) -> io::Result<Option<(PathBuf, Option<OsString>)>> {
+    let mut buf = [0; SHEBANG_BUF_SIZE];
+
+    // pread() so the file offset isn't changed (the interpreter may share it)
+    let n = match file.read_at(&mut buf, 0) {
+        Ok(n) => n,
+        // An O_PATH file descriptor
+        Err(e) if e.raw_os_error() == Some(libc::EBADF) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let buf = &buf[..n];
+    if !buf.starts_with(b"#!") {
+        return Ok(None);
+    }
+
+    let line = &buf[2..];
+    let line = match line.iter().position(|&c| c == b'\n') {
+        Some(i) => &line[..i],
+        // The line was truncated
+        None if n == SHEBANG_BUF_SIZE => return Err(io::Error::from_raw_os_error(libc::ENOEXEC)),
+        None => line,
+    };
+
+    let is_space = |c: &u8| *c == b' ' || *c == b'\t';
+    let line = trim(line, is_space);
+
+    let (interp, arg) = match line.iter().position(is_space) {
+        Some(i) => (&line[..i], trim(&line[i..], is_space)),
+        None => (line, &b""[..]),
+    };
+
+    if interp.is_empty() {
+        return Err(io::Error::from_raw_os_error(libc::ENOEXEC));
+    }
+
+    Ok(Some((
+        PathBuf::from(OsStr::from_bytes(interp)),
+        if arg.is_empty() {
+            None
+        } else {
+            Some(OsStr::from_bytes(arg).to_os_string())
+        },
+    )))
+}
+
+fn trim<F: Fn(&u8) -> bool>(mut s: &[u8], f: F) -> &[u8] {
+    while s.first().is_some_and(&f) {
+        s = &s[1..];
+    }
+    while s.last().is_some_and(&f) {
+        s = &s[..s.len() - 1];
+    }
+    s
+}
@@ -0,0 +1,185 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::prelude::*;
+
+use openat::{Dir, SimpleType};
+
+/// The default size of the buffer passed to `getdents64()`.
+pub const DEFAULT_BUF_SIZE: usize = 32 * 1024;
+
+// The offsets of the fields in a `struct linux_dirent64`
+const D_INO_OFFSET: usize = 0;
+const D_RECLEN_OFFSET: usize = 16;
+const D_TYPE_OFFSET: usize = 18;
+const D_NAME_OFFSET: usize = 19;
+
+/// An entry returned by `getdents64()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RawDirEntry {
+    ino: u64,
+    d_type: u8,
+    name: Box<[u8]>,
+}
+
+impl RawDirEntry {
+    /// The inode number of this entry.
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// The raw `d_type` of this entry (one of the `libc::DT_*` constants).
+    pub fn raw_type(&self) -> u8 {
+        self.d_type
+    }
+
+    /// The type of this entry, or `None` if it is unknown (`DT_UNKNOWN`).
+    pub fn file_type(&self) -> Option<SimpleType> {
+        match self.d_type {
+            libc::DT_UNKNOWN => None,
+            libc::DT_REG => Some(SimpleType::File),
+            libc::DT_DIR => Some(SimpleType::Dir),
+            libc::DT_LNK => Some(SimpleType::Symlink),
+            _ => Some(SimpleType::Other),
+        }
+    }
+
+    /// The name of this entry.
+    pub fn file_name(&self) -> &OsStr {
+        OsStr::from_bytes(&self.name)
+    }
+
+    pub(crate) fn into_file_name(self) -> Box<[u8]> {
+        self.name
+    }
+}
+
+/// The buffer state of a `getdents64()` loop, separated from the file descriptor so that it can be
+/// shared.
+#[derive(Debug)]
+pub(crate) struct GetdentsBuf {
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    done: bool,
+}
+
+impl GetdentsBuf {
+    pub fn new(buf_size: usize) -> Self {
+        Self {
+            // It must be at least large enough to hold one entry with a NAME_MAX name
+            buf: vec![0; std::cmp::max(buf_size, 512)],
+            pos: 0,
+            len: 0,
+            done: false,
+        }
+    }
+
+    fn fill(&mut self, fd: RawFd) -> io::Result<()> {
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                fd,
+                self.buf.as_mut_ptr(),
+                self.buf.len(),
+            )
+        };
+
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        self.pos = 0;
+        self.len = n as usize;
+        if n == 0 {
+            self.done = true;
+        }
+        Ok(())
+    }
+
+    /// Read the next entry (skipping `.` and `..`) from the directory referred to by `fd`.
+    pub fn next(&mut self, fd: RawFd) -> Option<io::Result<RawDirEntry>> {
+        loop {
+            if self.done {
+                return None;
+            }
+
+            if self.pos >= self.len {
+                if let Err(e) = self.fill(fd) {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                continue;
+            }
+
+            let rec = &self.buf[self.pos..self.len];
+
+            let reclen = u16::from_ne_bytes([rec[D_RECLEN_OFFSET], rec[D_RECLEN_OFFSET + 1]]);
+            self.pos += reclen as usize;
+
+            let mut ino = [0; 8];
+            ino.copy_from_slice(&rec[D_INO_OFFSET..D_INO_OFFSET + 8]);
+
+            // The name is NUL-terminated (and then padded) within the record
+            let name = &rec[D_NAME_OFFSET..reclen as usize];
+            let name = &name[..name.iter().position(|&c| c == 0).unwrap_or(name.len())];
+
+            if name == b"." || name == b".." {
+                continue;
+            }
+
+            return Some(Ok(RawDirEntry {
+                ino: u64::from_ne_bytes(ino),
+                d_type: rec[D_TYPE_OFFSET],
+                name: name.into(),
+            }));
+        }
+    }
+}
+
+/// An iterator that lists a directory using the `getdents64()` system call.
+///
+/// This reads the directory directly from the file descriptor that it was given (unlike
+/// `openat::Dir::list_self()`, which goes through `fdopendir()`), in batches of the configured
+/// buffer size. The `.` and `..` entries are skipped.
+///
+/// Created by
+/// [`DirSecureExt::list_dir_raw_secure()`](trait.DirSecureExt.html#method.list_dir_raw_secure),
+/// or from an existing directory with [`Getdents::new()`](#method.new).
+#[derive(Debug)]
+pub struct Getdents {
+    dir: Dir,
+    buf: GetdentsBuf,
+}
+
+impl Getdents {
+    /// Create an iterator over the entries in the given directory, using a buffer of the given
+    /// size (in bytes).
+    ///
+    /// The directory must have been opened for reading (i.e. not with `O_PATH`); otherwise,
+    /// iteration will fail with `EBADF`. Iteration starts at the current offset of the file
+    /// descriptor.
+    pub fn new(dir: Dir, buf_size: usize) -> Self {
+        Self {
+            dir,
+            buf: GetdentsBuf::new(buf_size),
+        }
+    }
+
+    /// The directory that is being listed.
+    pub fn dir(&self) -> &Dir {
+        &self.dir
+    }
+
+    /// Consume this iterator and return the directory.
+    pub fn into_dir(self) -> Dir {
+        self.dir
+    }
+}
+
+impl Iterator for Getdents {
+    type Item = io::Result<RawDirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.next(self.dir.as_raw_fd())
+    }
+}
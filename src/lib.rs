@@ -3,16 +3,65 @@ use std::fs;
 use std::io;
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use bitflags::bitflags;
 use openat::Dir;
 
+mod access;
+#[cfg(feature = "tokio")]
+mod async_dir;
+mod backend;
+mod command;
 mod constants;
+mod copy;
+mod dir_entry;
+mod error;
+mod exec;
+#[cfg(target_os = "linux")]
+mod getdents;
+mod link_chain;
+mod lock;
+mod mount;
 mod open;
+#[cfg(target_os = "linux")]
+mod path_handle;
+#[cfg(target_os = "linux")]
+mod procfs;
+mod reopen;
+mod retry;
+pub mod secure_fs;
+mod symlink_policy;
 mod util;
 
 #[cfg(target_os = "linux")]
 mod openat2;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+
+pub use backend::{backend, set_backend, Backend};
+pub use command::CommandSecureExt;
+pub use copy::{copy_file_secure, CopyOptions};
+pub use dir_entry::{DirEntry, DirIter};
+pub use error::{Error, ErrorKind};
+pub use exec::{ExecCommand, Executable};
+#[cfg(target_os = "linux")]
+pub use getdents::{Getdents, RawDirEntry};
+pub use link_chain::SymlinkHop;
+pub use lock::{FileLock, LockKind};
+#[cfg(target_os = "linux")]
+pub use path_handle::PathHandle;
+#[cfg(target_os = "linux")]
+pub use procfs::{ProcfsBase, ProcfsHandle};
+pub use reopen::reopen;
+pub use retry::{retry_count, retry_policy, set_retry_policy, RetryPolicy};
+pub use symlink_policy::SymlinkPolicy;
+
+#[cfg(feature = "tokio")]
+pub use async_dir::{AsyncSecureDir, ReadDir, Walk, WalkEntry};
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub use uring::{OpenRequest, SecureUring};
 
 bitflags! {
     #[derive(Default)]
@@ -22,127 +71,216 @@ bitflags! {
         /// Don't cross filesystem boundaries.
         ///
         /// On Linux, this may or may not include bind mounts by default.
+        ///
+        /// When this is handled by the manual resolver, every component that is opened is
+        /// `fstat()`ed to compare its device ID with the root directory's.
         const NO_XDEV = 8;
         /// When used with `NO_XDEV` on Linux, this indicates that crossing bind mounts
         /// must be allowed (crossing other filesystem boundaries is still prohibited).
         ///
         /// WARNING: This may decrease performance.
         const XDEV_BIND_OK = 16;
+        /// After the file has been opened, verify (by walking up the directory tree with `..`)
+        /// that it is still beneath the root directory, and fail with `EXDEV` if it is not.
+        ///
+        /// This closes the race described in the documentation of
+        /// [`DirSecureExt::open_file_secure()`] where a directory is concurrently moved out of the
+        /// root directory during resolution. It is only needed (and only has an effect) when the
+        /// manual implementation is used; `openat2()` already provides this guarantee.
+        ///
+        /// If the opened file is not a directory, the directory containing it is checked instead.
+        ///
+        /// WARNING: This may decrease performance, especially for deeply nested paths.
+        ///
+        /// [`DirSecureExt::open_file_secure()`]: ./trait.DirSecureExt.html#method.open_file_secure
+        const VERIFY_BENEATH = 32;
+        /// Resolve the path with both `openat2()` and the manual implementation, and check that
+        /// they opened the same file (or failed with the same error). If they disagree, fail with
+        /// [`ErrorKind::BackendMismatch`].
+        ///
+        /// This is intended for testing. It only has an effect on Linux, when `openat2()` is
+        /// available and can implement the other lookup flags. If `openat2()` fails, the manual
+        /// implementation is called with the original flags, so if it incorrectly succeeds it may
+        /// create or truncate a file.
+        ///
+        /// WARNING: This will decrease performance.
+        ///
+        /// [`ErrorKind::BackendMismatch`]: ./enum.ErrorKind.html#variant.BackendMismatch
+        const PARANOID = 64;
     }
 }
 
-pub trait DirSecureExt {
-    fn parent_secure(&self) -> io::Result<Option<Dir>>;
+/// Security-focused extension methods for directory file descriptors.
+///
+/// This is implemented for `openat::Dir`, `std::fs::File`, `OwnedFd`, `BorrowedFd`, and (on
+/// Linux) [`PathHandle`] (and for `cap_std::fs::Dir` if the `cap-std` feature is enabled). For the
+/// types that can refer to any kind of file, the file descriptor must refer to a directory;
+/// otherwise, the operations will fail with `ENOTDIR`.
+///
+/// [`PathHandle`]: ./struct.PathHandle.html
+pub trait DirSecureExt: AsRawFd {
+    /// Open the parent directory.
+    ///
+    /// This is the same as `dir.sub_dir("..")`, except that it returns `Ok(None)` if the returned
+    /// directory would be the same as this directory (for example, if the directory is open to
+    /// `/`).
+    fn parent_secure(&self) -> Result<Option<Dir>, Error> {
+        error::with_context("parent_secure", Path::new(".."), || {
+            let parent = util::borrow_dir(self)
+                .sub_dir(unsafe { CStr::from_bytes_with_nul_unchecked(b"..\0") })?;
 
-    fn sub_dir_secure<P: AsRef<Path>>(&self, p: P, lookup_flags: LookupFlags) -> io::Result<Dir>;
+            Ok(if util::same_dir(self, &parent)? {
+                None
+            } else {
+                Some(parent)
+            })
+        })
+    }
 
-    fn new_file_secure<P: AsRef<Path>>(
-        &self,
-        p: P,
-        mode: libc::mode_t,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File>;
-    fn update_file_secure<P: AsRef<Path>>(
-        &self,
-        p: P,
-        mode: libc::mode_t,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File>;
-    fn open_file_secure<P: AsRef<Path>>(
-        &self,
-        p: P,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File>;
-    fn write_file_secure<P: AsRef<Path>>(
-        &self,
-        p: P,
-        mode: libc::mode_t,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File>;
-    fn append_file_secure<P: AsRef<Path>>(
-        &self,
-        p: P,
-        mode: libc::mode_t,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File>;
+    /// Open the parent directory, without going above `root`.
+    ///
+    /// This returns `Ok(None)` if this directory is `root`, and fails with `EXDEV`
+    /// ([`ErrorKind::EscapeAttempt`]) if this directory is not beneath `root` (see
+    /// [`is_descendant_of`]). The parent directory is checked again after it is opened, in case
+    /// this directory was moved out of `root` in the meantime.
+    ///
+    /// [`ErrorKind::EscapeAttempt`]: ./enum.ErrorKind.html#variant.EscapeAttempt
+    /// [`is_descendant_of`]: #method.is_descendant_of
+    fn parent_within<R: AsRawFd + ?Sized>(&self, root: &R) -> Result<Option<Dir>, Error> {
+        error::with_context("parent_within", Path::new(".."), || {
+            if open::depth_beneath(root.as_raw_fd(), self.as_raw_fd())? == 0 {
+                return Ok(None);
+            }
 
-    fn create_dir_secure<P: AsRef<Path>>(
-        &self,
-        path: P,
-        mode: libc::mode_t,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<()>;
+            let parent = util::borrow_dir(self)
+                .sub_dir(unsafe { CStr::from_bytes_with_nul_unchecked(b"..\0") })?;
+            open::depth_beneath(root.as_raw_fd(), parent.as_raw_fd())?;
 
-    fn remove_dir_secure<P: AsRef<Path>>(
-        &self,
-        path: P,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<()>;
-    fn remove_file_secure<P: AsRef<Path>>(
-        &self,
-        path: P,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<()>;
+            Ok(Some(parent))
+        })
+    }
 
-    fn list_dir_secure<P: AsRef<Path>>(
-        &self,
-        path: P,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<openat::DirIter>;
+    /// Check whether this directory is `root` or one of its descendants.
+    ///
+    /// This walks up the directory tree from this directory with `..` lookups, comparing the
+    /// device and inode numbers of each directory with those of `root`, until it either finds
+    /// `root` or reaches the root of the filesystem. It cannot be fooled by renames (or
+    /// symbolic links) the way that comparing paths can, though if a directory is moved while
+    /// this is running the result may be out of date by the time it returns.
+    fn is_descendant_of<R: AsRawFd + ?Sized>(&self, root: &R) -> Result<bool, Error> {
+        match open::depth_beneath(root.as_raw_fd(), self.as_raw_fd()) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::EscapeAttempt => Ok(false),
+            Err(e) => Err(e.context("is_descendant_of", Path::new(".."))),
+        }
+    }
 
-    fn metadata_secure<P: AsRef<Path>>(
+    /// Check whether the given path refers to the root of a mount (for example, a bind-mounted
+    /// volume).
+    ///
+    /// The path is resolved as if by [`sub_dir_secure`] (so it must refer to a directory). On
+    /// Linux, this uses `STATX_ATTR_MOUNT_ROOT` or compares mount IDs with the parent directory,
+    /// which detects bind mounts from the same filesystem; on older kernels and other platforms,
+    /// it compares device numbers with the parent directory (like [`LookupFlags::NO_XDEV`]), which
+    /// does not.
+    ///
+    /// [`sub_dir_secure`]: #method.sub_dir_secure
+    /// [`LookupFlags::NO_XDEV`]: ./struct.LookupFlags.html#associatedconstant.NO_XDEV
+    fn is_mount_point_secure<P: AsRef<Path>>(
         &self,
         path: P,
         lookup_flags: LookupFlags,
-    ) -> io::Result<openat::Metadata>;
+    ) -> Result<bool, Error> {
+        let path = path.as_ref();
 
-    fn read_link_secure<P: AsRef<Path>>(
-        &self,
-        path: P,
-        lookup_flags: LookupFlags,
-    ) -> io::Result<PathBuf>;
+        error::with_context("is_mount_point_secure", path, || {
+            let dir = open_sub_dir(self, path, lookup_flags)?;
+            Ok(mount::is_mount_point(dir.as_raw_fd())?)
+        })
+    }
 
-    fn symlink_secure<P: AsRef<Path>, R: openat::AsPath>(
+    /// Open an executable file, so that it can be run without resolving its path again.
+    ///
+    /// The path is resolved as if by [`open_file_secure`]. If the file is a script (starting with
+    /// `#!`), the interpreter named on the `#!` line is also opened, with its path resolved in
+    /// the same way (relative to this directory, not the real root directory), and so on for up to
+    /// 4 levels of interpreters (beyond that, this fails with `ELOOP`, but with
+    /// [`ErrorKind::Other`] rather than [`ErrorKind::SymlinkLoop`]).
+    ///
+    /// Use [`Executable::command()`] to run it. Scripts are passed to their interpreters as
+    /// `/dev/fd/N`, so that must be available to the interpreter.
+    ///
+    /// This fails with `EACCES` if the path does not refer to a regular file. It does not check
+    /// that the file is executable; that happens when it is run.
+    ///
+    /// [`open_file_secure`]: #method.open_file_secure
+    /// [`Executable::command()`]: ./struct.Executable.html#method.command
+    /// [`ErrorKind::Other`]: ./enum.ErrorKind.html#variant.Other
+    /// [`ErrorKind::SymlinkLoop`]: ./enum.ErrorKind.html#variant.SymlinkLoop
+    fn open_executable_secure<P: AsRef<Path>>(
         &self,
         path: P,
-        value: R,
         lookup_flags: LookupFlags,
-    ) -> io::Result<()>;
+    ) -> Result<Executable, Error> {
+        exec::open_executable(self, path.as_ref(), lookup_flags, 0)
+    }
 
-    fn local_rename_secure<P: AsRef<Path>, R: AsRef<Path>>(
+    /// Open a subdirectory.
+    ///
+    /// See the documentation of [`open_file_secure`] for security information.
+    ///
+    /// [`open_file_secure`]: #method.open_file_secure
+    fn sub_dir_secure<P: AsRef<Path>>(
         &self,
-        old: P,
-        new: R,
+        p: P,
         lookup_flags: LookupFlags,
-    ) -> io::Result<()>;
-}
+    ) -> Result<Dir, Error> {
+        let p = p.as_ref();
+        open_sub_dir(self, p, lookup_flags).map_err(|e| e.context("sub_dir_secure", p))
+    }
 
-impl DirSecureExt for Dir {
-    /// Open the parent directory.
+    /// Open a [`PathHandle`] (an `O_PATH` file descriptor) referring to the given path, which may
+    /// be any type of file.
     ///
-    /// This is the same as `dir.sub_dir("..")`, except that it returns `Ok(None)` if the returned
-    /// directory would be the same as this directory (for example, if the directory is open to
-    /// `/`).
-    fn parent_secure(&self) -> io::Result<Option<Dir>> {
-        let parent = self.sub_dir(unsafe { CStr::from_bytes_with_nul_unchecked(b"..\0") })?;
+    /// If the path refers to a symbolic link, it is followed; see
+    /// [`open_path_nofollow_secure`] to get a handle to the link itself.
+    ///
+    /// See the documentation of [`open_file_secure`] for security information.
+    ///
+    /// [`PathHandle`]: ./struct.PathHandle.html
+    /// [`open_path_nofollow_secure`]: #method.open_path_nofollow_secure
+    /// [`open_file_secure`]: #method.open_file_secure
+    #[cfg(target_os = "linux")]
+    fn open_path_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<PathHandle, Error> {
+        let p = p.as_ref();
+        let fd = open::open_file_secure(self, p, lookup_flags, libc::O_PATH, 0)
+            .map_err(|e| e.context("open_path_secure", p))?;
 
-        Ok(if util::same_dir(self, &parent)? {
-            None
-        } else {
-            Some(parent)
-        })
+        Ok(PathHandle::from_fd(fd))
     }
 
-    /// Open a subdirectory.
+    /// Like [`open_path_secure`], but if the final component of the path is a symbolic link,
+    /// return a handle to the link itself instead of following it.
     ///
-    /// See the documentation of [`open_file_secure`] for security information.
+    /// (Symbolic links in other components of the path are still followed, unless
+    /// `LookupFlags::NO_SYMLINKS` is passed.)
     ///
-    /// [`open_file_secure`]: #method.open_file_secure
-    fn sub_dir_secure<P: AsRef<Path>>(&self, p: P, lookup_flags: LookupFlags) -> io::Result<Dir> {
-        let fd =
-            open::open_file_secure(self, p.as_ref(), lookup_flags, constants::BASE_DIR_FLAGS, 0)?;
+    /// [`open_path_secure`]: #method.open_path_secure
+    #[cfg(target_os = "linux")]
+    fn open_path_nofollow_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<PathHandle, Error> {
+        let p = p.as_ref();
+        let fd = open::open_file_secure(self, p, lookup_flags, libc::O_PATH | libc::O_NOFOLLOW, 0)
+            .map_err(|e| e.context("open_path_nofollow_secure", p))?;
 
-        Ok(unsafe { Dir::from_raw_fd(fd) })
+        Ok(PathHandle::from_fd(fd))
     }
 
     /// Atomically create a file and open it for writing. If it exists, fail with an error.
@@ -155,16 +293,15 @@ impl DirSecureExt for Dir {
         p: P,
         mode: libc::mode_t,
         lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File> {
-        let fd = open::open_file_secure(
+    ) -> Result<fs::File, Error> {
+        open_file(
             self,
+            "new_file_secure",
             p.as_ref(),
             lookup_flags,
             libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY,
             mode,
-        )?;
-
-        Ok(unsafe { fs::File::from_raw_fd(fd) })
+        )
     }
 
     /// Open a file for both reading and writing, creating it if it does not exist.
@@ -177,16 +314,87 @@ impl DirSecureExt for Dir {
         p: P,
         mode: libc::mode_t,
         lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File> {
-        let fd = open::open_file_secure(
+    ) -> Result<fs::File, Error> {
+        open_file(
             self,
+            "update_file_secure",
             p.as_ref(),
             lookup_flags,
             libc::O_CREAT | libc::O_RDWR,
             mode,
-        )?;
+        )
+    }
 
-        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    /// Open a file for both reading and writing (creating it if it does not exist, like
+    /// [`update_file_secure`]), and lock it, waiting for any conflicting locks to be released.
+    ///
+    /// See [`FileLock`] for details of the lock; it is released when the returned guard is
+    /// dropped. See the documentation of [`open_file_secure`] for security information.
+    ///
+    /// [`update_file_secure`]: #method.update_file_secure
+    /// [`FileLock`]: ./struct.FileLock.html
+    /// [`open_file_secure`]: #method.open_file_secure
+    fn lock_file_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        mode: libc::mode_t,
+        kind: LockKind,
+        lookup_flags: LookupFlags,
+    ) -> Result<FileLock, Error> {
+        lock::lock_file(
+            self,
+            "lock_file_secure",
+            p.as_ref(),
+            mode,
+            kind,
+            lock::LockWait::Block,
+            lookup_flags,
+        )
+    }
+
+    /// Like [`lock_file_secure`], but fail with `EWOULDBLOCK` instead of waiting if a conflicting
+    /// lock is held.
+    ///
+    /// [`lock_file_secure`]: #method.lock_file_secure
+    fn try_lock_file_secure<P: AsRef<Path>>(
+        &self,
+        p: P,
+        mode: libc::mode_t,
+        kind: LockKind,
+        lookup_flags: LookupFlags,
+    ) -> Result<FileLock, Error> {
+        lock::lock_file(
+            self,
+            "try_lock_file_secure",
+            p.as_ref(),
+            mode,
+            kind,
+            lock::LockWait::NonBlock,
+            lookup_flags,
+        )
+    }
+
+    /// Like [`lock_file_secure`], but fail with `ETIMEDOUT` if the lock cannot be acquired within
+    /// the given timeout.
+    ///
+    /// [`lock_file_secure`]: #method.lock_file_secure
+    fn lock_file_secure_timeout<P: AsRef<Path>>(
+        &self,
+        p: P,
+        mode: libc::mode_t,
+        kind: LockKind,
+        timeout: Duration,
+        lookup_flags: LookupFlags,
+    ) -> Result<FileLock, Error> {
+        lock::lock_file(
+            self,
+            "lock_file_secure_timeout",
+            p.as_ref(),
+            mode,
+            kind,
+            lock::LockWait::Timeout(timeout),
+            lookup_flags,
+        )
     }
 
     /// Open a file as read-only.
@@ -201,8 +409,9 @@ impl DirSecureExt for Dir {
     ///
     /// # Race conditions
     ///
-    /// Some race conditions may cause `EAGAIN` failures. The caller may wish to retry in this
-    /// case.
+    /// Some race conditions may cause `EAGAIN` failures (reported with [`ErrorKind::Race`]). These
+    /// are retried automatically according to the current [`RetryPolicy`]; if the error persists,
+    /// it is returned to the caller.
     ///
     /// As far as the author is aware, the only race condition that could allow escaping this
     /// directory is if files and/or directories are being concurrently moved between this
@@ -222,15 +431,26 @@ impl DirSecureExt for Dir {
     /// some point during the concurrent modification. This function will not allow opening a file
     /// that was *never* in this directory (for example, `d/e`).
     ///
+    /// Passing `LookupFlags::VERIFY_BENEATH` closes this hole: the operation fails with `EXDEV`
+    /// ([`ErrorKind::EscapeAttempt`]) if the opened file is no longer beneath this directory.
+    ///
+    /// [`ErrorKind::EscapeAttempt`]: ./enum.ErrorKind.html#variant.EscapeAttempt
     /// [`LookupFlags`]: ./struct.LookupFlags.html
+    /// [`ErrorKind::Race`]: ./enum.ErrorKind.html#variant.Race
+    /// [`RetryPolicy`]: ./struct.RetryPolicy.html
     fn open_file_secure<P: AsRef<Path>>(
         &self,
         p: P,
         lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File> {
-        let fd = open::open_file_secure(self, p.as_ref(), lookup_flags, libc::O_RDONLY, 0)?;
-
-        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    ) -> Result<fs::File, Error> {
+        open_file(
+            self,
+            "open_file_secure",
+            p.as_ref(),
+            lookup_flags,
+            libc::O_RDONLY,
+            0,
+        )
     }
 
     /// Open a file for writing, creating it if it does not exist and truncating it if it does.
@@ -243,16 +463,15 @@ impl DirSecureExt for Dir {
         p: P,
         mode: libc::mode_t,
         lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File> {
-        let fd = open::open_file_secure(
+    ) -> Result<fs::File, Error> {
+        open_file(
             self,
+            "write_file_secure",
             p.as_ref(),
             lookup_flags,
             libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC,
             mode,
-        )?;
-
-        Ok(unsafe { fs::File::from_raw_fd(fd) })
+        )
     }
 
     /// Open a file for appending, creating it if it does not exist.
@@ -265,16 +484,15 @@ impl DirSecureExt for Dir {
         p: P,
         mode: libc::mode_t,
         lookup_flags: LookupFlags,
-    ) -> io::Result<fs::File> {
-        let fd = open::open_file_secure(
+    ) -> Result<fs::File, Error> {
+        open_file(
             self,
+            "append_file_secure",
             p.as_ref(),
             lookup_flags,
             libc::O_CREAT | libc::O_WRONLY | libc::O_APPEND,
             mode,
-        )?;
-
-        Ok(unsafe { fs::File::from_raw_fd(fd) })
+        )
     }
 
     fn create_dir_secure<P: AsRef<Path>>(
@@ -282,98 +500,282 @@ impl DirSecureExt for Dir {
         path: P,
         mode: libc::mode_t,
         lookup_flags: LookupFlags,
-    ) -> io::Result<()> {
-        let (subdir, fname) = prepare_inner_operation(self, path.as_ref(), lookup_flags)?;
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
 
-        if let Some(fname) = fname {
-            subdir.as_ref().unwrap_or(self).create_dir(fname, mode)
-        } else {
-            Err(std::io::Error::from_raw_os_error(libc::EEXIST))
-        }
+        error::with_context("create_dir_secure", path, || {
+            let root = util::borrow_dir(self);
+            let (subdir, fname) = prepare_inner_operation(self, path, lookup_flags)?;
+
+            if let Some(fname) = fname {
+                subdir
+                    .as_ref()
+                    .unwrap_or(&root)
+                    .create_dir(fname, mode)
+                    .map_err(|e| error::at_final(path, e))
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EEXIST).into())
+            }
+        })
     }
 
     fn remove_dir_secure<P: AsRef<Path>>(
         &self,
         path: P,
         lookup_flags: LookupFlags,
-    ) -> io::Result<()> {
-        let (subdir, fname) = prepare_inner_operation(self, path.as_ref(), lookup_flags)?;
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
 
-        if let Some(fname) = fname {
-            subdir.as_ref().unwrap_or(self).remove_dir(fname)
-        } else {
-            let is_same = if let Some(subdir) = subdir.as_ref() {
-                util::same_dir(self, subdir)?
-            } else {
-                true
-            };
+        error::with_context("remove_dir_secure", path, || {
+            let root = util::borrow_dir(self);
+            let (subdir, fname) = prepare_inner_operation(self, path, lookup_flags)?;
 
-            Err(std::io::Error::from_raw_os_error(if is_same {
-                libc::EBUSY
+            if let Some(fname) = fname {
+                subdir
+                    .as_ref()
+                    .unwrap_or(&root)
+                    .remove_dir(fname)
+                    .map_err(|e| error::at_final(path, e))
             } else {
-                libc::ENOTEMPTY
-            }))
-        }
+                let is_same = if let Some(subdir) = subdir.as_ref() {
+                    util::same_dir(self, subdir)?
+                } else {
+                    true
+                };
+
+                Err(io::Error::from_raw_os_error(if is_same {
+                    libc::EBUSY
+                } else {
+                    libc::ENOTEMPTY
+                })
+                .into())
+            }
+        })
     }
 
     fn remove_file_secure<P: AsRef<Path>>(
         &self,
         path: P,
         lookup_flags: LookupFlags,
-    ) -> io::Result<()> {
-        let (subdir, fname) = prepare_inner_operation(self, path.as_ref(), lookup_flags)?;
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
 
-        if let Some(fname) = fname {
-            subdir.as_ref().unwrap_or(self).remove_file(fname)
-        } else {
-            Err(std::io::Error::from_raw_os_error(libc::EISDIR))
-        }
+        error::with_context("remove_file_secure", path, || {
+            let root = util::borrow_dir(self);
+            let (subdir, fname) = prepare_inner_operation(self, path, lookup_flags)?;
+
+            if let Some(fname) = fname {
+                subdir
+                    .as_ref()
+                    .unwrap_or(&root)
+                    .remove_file(fname)
+                    .map_err(|e| error::at_final(path, e))
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EISDIR).into())
+            }
+        })
     }
 
-    #[allow(clippy::needless_return)]
+    /// List the entries in a directory.
+    ///
+    /// The entries that are returned keep the directory open, so they can be opened, removed, etc.
+    /// without resolving the path again. See [`DirEntry`] for details.
+    ///
+    /// [`DirEntry`]: ./struct.DirEntry.html
     fn list_dir_secure<P: AsRef<Path>>(
         &self,
         path: P,
         lookup_flags: LookupFlags,
-    ) -> io::Result<openat::DirIter> {
-        let subdir = self.sub_dir_secure(path, lookup_flags)?;
+    ) -> Result<DirIter, Error> {
+        let path = path.as_ref();
 
-        // list_self() is currently broken on Linux
-        #[cfg(target_os = "linux")]
-        return subdir.list_dir(".");
+        error::with_context("list_dir_secure", path, || {
+            let dir = open_dir_for_listing(self, path, lookup_flags)?;
+            let origin = dir_entry::Origin {
+                root: Arc::new(util::dup_dir(&util::borrow_dir(self))?),
+                path: path.to_path_buf(),
+            };
 
-        #[cfg(not(target_os = "linux"))]
-        return subdir.list_self();
+            DirIter::with_origin(dir, Some(Arc::new(origin))).map_err(Error::from)
+        })
+    }
+
+    /// List the entries in a directory with the `getdents64()` system call, using a buffer of
+    /// the given size (in bytes).
+    ///
+    /// Unlike [`list_dir_secure`], the entries only contain the raw information returned by the
+    /// kernel (inode number, type, and name). A large buffer reduces the number of system calls
+    /// needed to list huge directories.
+    ///
+    /// See the documentation of [`open_file_secure`] for security information.
+    ///
+    /// [`list_dir_secure`]: #method.list_dir_secure
+    /// [`open_file_secure`]: #method.open_file_secure
+    #[cfg(target_os = "linux")]
+    fn list_dir_raw_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        buf_size: usize,
+        lookup_flags: LookupFlags,
+    ) -> Result<Getdents, Error> {
+        let path = path.as_ref();
+
+        error::with_context("list_dir_raw_secure", path, || {
+            Ok(Getdents::new(
+                open_dir_for_listing(self, path, lookup_flags)?,
+                buf_size,
+            ))
+        })
     }
 
     fn metadata_secure<P: AsRef<Path>>(
         &self,
         path: P,
         lookup_flags: LookupFlags,
-    ) -> io::Result<openat::Metadata> {
-        let (subdir, fname) = prepare_inner_operation(self, path.as_ref(), lookup_flags)?;
+    ) -> Result<openat::Metadata, Error> {
+        let path = path.as_ref();
 
-        let subdir = subdir.as_ref().unwrap_or(self);
+        error::with_context("metadata_secure", path, || {
+            metadata_nofollow(self, path, lookup_flags)
+        })
+    }
 
-        if let Some(fname) = fname {
-            subdir.metadata(fname)
-        } else {
-            subdir.self_metadata()
-        }
+    /// Check whether the calling process can access the given path with the given `mode` (a
+    /// combination of `libc::R_OK`, `libc::W_OK`, and `libc::X_OK`, or `libc::F_OK`), like
+    /// `faccessat()`.
+    ///
+    /// Symbolic links are followed (including in the final component), within the root
+    /// directory. If `effective` is `true`, the check uses the effective user and group IDs (like
+    /// `AT_EACCESS`); otherwise, it uses the real IDs (like `access()`).
+    ///
+    /// This returns `Ok(false)` if access is denied (`EACCES`, `EPERM`, `EROFS`, or `ETXTBSY`).
+    /// Other errors (for example, if the path does not exist) are returned as errors.
+    ///
+    /// On Linux, the path is resolved to an `O_PATH` file descriptor, which is checked with
+    /// `faccessat2(AT_EMPTY_PATH)`. On kernels without `faccessat2()` (before 5.8), or if a seccomp
+    /// filter blocks it with `EPERM`, the permission bits, owner, and group of the file are
+    /// checked in userspace instead, which does not take ACLs into account. On other platforms,
+    /// `faccessat(AT_SYMLINK_NOFOLLOW)` is called on the directory containing the target.
+    ///
+    /// Like `access()`, this is inherently racy; it should not be used to decide whether to open
+    /// a file.
+    fn access_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        mode: libc::c_int,
+        lookup_flags: LookupFlags,
+        effective: bool,
+    ) -> Result<bool, Error> {
+        let path = path.as_ref();
+
+        error::with_context("access_secure", path, || {
+            access::access(self, path, mode, lookup_flags, effective)
+        })
+    }
+
+    /// Check whether the given path exists, following symbolic links (including the final
+    /// component).
+    ///
+    /// This returns `Ok(false)` only if the path (or the target of a symbolic link) does not exist
+    /// (`ENOENT`). Any other error, including symbolic link loops, escape attempts rejected by the
+    /// lookup flags, and permission errors, is returned as an error.
+    ///
+    /// See [`symlink_exists_secure`] to check for the final component without following it.
+    ///
+    /// [`symlink_exists_secure`]: #method.symlink_exists_secure
+    fn try_exists_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<bool, Error> {
+        let path = path.as_ref();
+
+        error::with_context("try_exists_secure", path, || {
+            let hops = match link_chain::read_link_chain(self, path, lookup_flags) {
+                Ok(hops) => hops,
+                Err(e) if e.raw_os_error() == Some(libc::ENOENT) => return Ok(false),
+                Err(e) => return Err(e),
+            };
+
+            match hops.last() {
+                Some(hop) => {
+                    let final_path = link_chain::next_path(hop.path(), hop.target());
+
+                    // Errors are attributed to the last component of the original path
+                    not_found_ok(metadata_nofollow(self, &final_path, lookup_flags))
+                        .map_err(|e| e.set_component(path.components().count().checked_sub(1)))
+                }
+                None => not_found_ok(metadata_nofollow(self, path, lookup_flags)),
+            }
+        })
+    }
+
+    /// Check whether the given path exists, without following a symbolic link in the final
+    /// component (so a dangling symbolic link exists).
+    ///
+    /// Errors are handled the same way as by [`try_exists_secure`].
+    ///
+    /// [`try_exists_secure`]: #method.try_exists_secure
+    fn symlink_exists_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<bool, Error> {
+        let path = path.as_ref();
+
+        error::with_context("symlink_exists_secure", path, || {
+            not_found_ok(metadata_nofollow(self, path, lookup_flags))
+        })
     }
 
     fn read_link_secure<P: AsRef<Path>>(
         &self,
         path: P,
         lookup_flags: LookupFlags,
-    ) -> io::Result<PathBuf> {
-        let (subdir, fname) = prepare_inner_operation(self, path.as_ref(), lookup_flags)?;
+    ) -> Result<PathBuf, Error> {
+        let path = path.as_ref();
 
-        if let Some(fname) = fname {
-            subdir.as_ref().unwrap_or(self).read_link(fname)
-        } else {
-            Err(std::io::Error::from_raw_os_error(libc::EINVAL))
-        }
+        error::with_context("read_link_secure", path, || {
+            let root = util::borrow_dir(self);
+            let (subdir, fname) = prepare_inner_operation(self, path, lookup_flags)?;
+
+            if let Some(fname) = fname {
+                subdir
+                    .as_ref()
+                    .unwrap_or(&root)
+                    .read_link(fname)
+                    .map_err(|e| error::at_final(path, e))
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EINVAL).into())
+            }
+        })
+    }
+
+    /// Follow the chain of symbolic links starting at `path`, returning every link in the chain
+    /// (and its target) until something that is not a symbolic link is reached.
+    ///
+    /// Each target is interpreted relative to this directory, exactly as
+    /// [`open_file_secure`] would interpret it, but nothing is opened except the directories
+    /// containing the links. If following the next link would exceed the symlink limit (which
+    /// is 0 if `LookupFlags::NO_SYMLINKS` is passed), this fails with `ELOOP`. Like
+    /// [`open_file_secure`], this counts every symbolic link followed, including the ones in the
+    /// directories leading up to each link, so those directories are always looked up with the
+    /// manual resolver (which can count them) instead of `openat2()`.
+    ///
+    /// If `path` is not a symbolic link, this returns an empty list. If the chain ends at a
+    /// nonexistent path, the links up to that point are returned.
+    ///
+    /// [`open_file_secure`]: #method.open_file_secure
+    fn read_link_chain_secure<P: AsRef<Path>>(
+        &self,
+        path: P,
+        lookup_flags: LookupFlags,
+    ) -> Result<Vec<SymlinkHop>, Error> {
+        let path = path.as_ref();
+
+        error::with_context("read_link_chain_secure", path, || {
+            link_chain::read_link_chain(self, path, lookup_flags)
+        })
     }
 
     fn symlink_secure<P: AsRef<Path>, R: openat::AsPath>(
@@ -381,14 +783,59 @@ impl DirSecureExt for Dir {
         path: P,
         value: R,
         lookup_flags: LookupFlags,
-    ) -> io::Result<()> {
-        let (subdir, fname) = prepare_inner_operation(self, path.as_ref(), lookup_flags)?;
+    ) -> Result<(), Error> {
+        let path = path.as_ref();
 
-        if let Some(fname) = fname {
-            subdir.as_ref().unwrap_or(self).symlink(fname, value)
-        } else {
-            Err(std::io::Error::from_raw_os_error(libc::EEXIST))
-        }
+        error::with_context("symlink_secure", path, || {
+            let root = util::borrow_dir(self);
+            let (subdir, fname) = prepare_inner_operation(self, path, lookup_flags)?;
+
+            if let Some(fname) = fname {
+                subdir
+                    .as_ref()
+                    .unwrap_or(&root)
+                    .symlink(fname, value)
+                    .map_err(|e| error::at_final(path, e))
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EEXIST).into())
+            }
+        })
+    }
+
+    /// Create a symbolic link at `path` pointing to `target`, after checking `target` against
+    /// the given [`SymlinkPolicy`].
+    ///
+    /// If the target is rejected by the policy, this fails with `EXDEV`
+    /// ([`ErrorKind::EscapeAttempt`]).
+    ///
+    /// [`SymlinkPolicy`]: ./enum.SymlinkPolicy.html
+    /// [`ErrorKind::EscapeAttempt`]: ./enum.ErrorKind.html#variant.EscapeAttempt
+    fn symlink_secure_with_policy<P: AsRef<Path>, R: AsRef<Path>>(
+        &self,
+        path: P,
+        target: R,
+        policy: SymlinkPolicy,
+        lookup_flags: LookupFlags,
+    ) -> Result<(), Error> {
+        let (path, target) = (path.as_ref(), target.as_ref());
+
+        error::with_context("symlink_secure_with_policy", path, || {
+            let root = util::borrow_dir(self);
+            let (subdir, fname) = prepare_inner_operation(self, path, lookup_flags)?;
+
+            if let Some(fname) = fname {
+                let subdir = subdir.as_ref().unwrap_or(&root);
+
+                symlink_policy::check_target(self, subdir, target, policy)
+                    .map_err(|e| e.at_component(path.components().count().checked_sub(1)))?;
+
+                subdir
+                    .symlink(fname, target)
+                    .map_err(|e| error::at_final(path, e))
+            } else {
+                Err(io::Error::from_raw_os_error(libc::EEXIST).into())
+            }
+        })
     }
 
     fn local_rename_secure<P: AsRef<Path>, R: AsRef<Path>>(
@@ -396,94 +843,197 @@ impl DirSecureExt for Dir {
         old: P,
         new: R,
         lookup_flags: LookupFlags,
-    ) -> io::Result<()> {
+    ) -> Result<(), Error> {
         rename_secure(self, old, self, new, lookup_flags)
     }
 }
 
-pub fn hardlink_secure<P: AsRef<Path>, R: AsRef<Path>>(
-    old_dir: &Dir,
+impl DirSecureExt for Dir {}
+
+impl DirSecureExt for fs::File {}
+
+impl DirSecureExt for OwnedFd {}
+
+impl DirSecureExt for BorrowedFd<'_> {}
+
+#[cfg(target_os = "linux")]
+impl DirSecureExt for PathHandle {}
+
+#[cfg(feature = "cap-std")]
+impl DirSecureExt for cap_std::fs::Dir {}
+
+pub fn hardlink_secure<D1, D2, P, R>(
+    old_dir: &D1,
     old: P,
-    new_dir: &Dir,
+    new_dir: &D2,
     new: R,
     lookup_flags: LookupFlags,
-) -> io::Result<()> {
-    let (old_subdir, old_fname) = prepare_inner_operation(old_dir, old.as_ref(), lookup_flags)?;
-    let old_subdir = old_subdir.as_ref().unwrap_or(old_dir);
+) -> Result<(), Error>
+where
+    D1: AsRawFd + ?Sized,
+    D2: AsRawFd + ?Sized,
+    P: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let (old, new) = (old.as_ref(), new.as_ref());
 
-    let old_fname = if let Some(old_fname) = old_fname {
-        old_fname
-    } else {
-        return Err(std::io::Error::from_raw_os_error(
-            if util::same_dir(old_dir, &old_subdir)? {
-                libc::EBUSY
-            } else {
-                // As far as I can tell, there is no safe, cross-platform, race-free way to handle this case.
-                libc::ENOTSUP
-            },
-        ));
-    };
+    let old_root = util::borrow_dir(old_dir);
+    let (old_subdir, old_fname) = prepare_source(old_dir, old, lookup_flags)
+        .map_err(|e| e.context("hardlink_secure", old))?;
+    let old_subdir = old_subdir.as_ref().unwrap_or(&old_root);
 
-    let (new_subdir, new_fname) = prepare_inner_operation(new_dir, new.as_ref(), lookup_flags)?;
-    let new_subdir = new_subdir.as_ref().unwrap_or(new_dir);
+    error::with_context("hardlink_secure", new, || {
+        let new_root = util::borrow_dir(new_dir);
+        let (new_subdir, new_fname) = prepare_inner_operation(new_dir, new, lookup_flags)?;
+        let new_subdir = new_subdir.as_ref().unwrap_or(&new_root);
 
-    if let Some(new_fname) = new_fname {
-        openat::hardlink(old_subdir, old_fname, new_subdir, new_fname)
-    } else {
-        // The "new" path cannot exist
-        Err(std::io::Error::from_raw_os_error(libc::EEXIST))
-    }
+        if let Some(new_fname) = new_fname {
+            Ok(openat::hardlink(
+                old_subdir, old_fname, new_subdir, new_fname,
+            )?)
+        } else {
+            // The "new" path cannot exist
+            Err(io::Error::from_raw_os_error(libc::EEXIST).into())
+        }
+    })
 }
 
-pub fn rename_secure<P: AsRef<Path>, R: AsRef<Path>>(
-    old_dir: &Dir,
+pub fn rename_secure<D1, D2, P, R>(
+    old_dir: &D1,
     old: P,
-    new_dir: &Dir,
+    new_dir: &D2,
     new: R,
     lookup_flags: LookupFlags,
-) -> io::Result<()> {
-    let (old_subdir, old_fname) = prepare_inner_operation(old_dir, old.as_ref(), lookup_flags)?;
-    let old_subdir = old_subdir.as_ref().unwrap_or(old_dir);
+) -> Result<(), Error>
+where
+    D1: AsRawFd + ?Sized,
+    D2: AsRawFd + ?Sized,
+    P: AsRef<Path>,
+    R: AsRef<Path>,
+{
+    let (old, new) = (old.as_ref(), new.as_ref());
+
+    let old_root = util::borrow_dir(old_dir);
+    let (old_subdir, old_fname) =
+        prepare_source(old_dir, old, lookup_flags).map_err(|e| e.context("rename_secure", old))?;
+    let old_subdir = old_subdir.as_ref().unwrap_or(&old_root);
 
-    let old_fname = if let Some(old_fname) = old_fname {
-        old_fname
+    error::with_context("rename_secure", new, || {
+        let new_root = util::borrow_dir(new_dir);
+        let (new_subdir, new_fname) = prepare_inner_operation(new_dir, new, lookup_flags)?;
+        let new_subdir = new_subdir.as_ref().unwrap_or(&new_root);
+
+        if let Some(new_fname) = new_fname {
+            Ok(openat::rename(
+                old_subdir, old_fname, new_subdir, new_fname,
+            )?)
+        } else {
+            Err(
+                io::Error::from_raw_os_error(if util::same_dir(new_dir, new_subdir)? {
+                    libc::EBUSY
+                } else {
+                    // We rewound up a directory; that means that the directory isn't empty
+                    libc::ENOTEMPTY
+                })
+                .into(),
+            )
+        }
+    })
+}
+
+/// Prepare the "old" path of a rename or hardlink operation, which must name a file.
+fn prepare_source<'a, D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &'a Path,
+    lookup_flags: LookupFlags,
+) -> Result<(Option<Dir>, &'a OsStr), Error> {
+    let (subdir, fname) = prepare_inner_operation(dir, path, lookup_flags)?;
+
+    if let Some(fname) = fname {
+        Ok((subdir, fname))
     } else {
-        return Err(std::io::Error::from_raw_os_error(
-            if util::same_dir(old_dir, &old_subdir)? {
-                libc::EBUSY
-            } else {
-                // As far as I can tell, there is no safe, cross-platform, race-free way to handle this case.
-                libc::ENOTSUP
-            },
-        ));
-    };
+        let is_same = if let Some(subdir) = subdir.as_ref() {
+            util::same_dir(dir, subdir)?
+        } else {
+            true
+        };
 
-    let (new_subdir, new_fname) = prepare_inner_operation(new_dir, new.as_ref(), lookup_flags)?;
-    let new_subdir = new_subdir.as_ref().unwrap_or(new_dir);
+        Err(io::Error::from_raw_os_error(if is_same {
+            libc::EBUSY
+        } else {
+            // As far as I can tell, there is no safe, cross-platform, race-free way to handle this case.
+            libc::ENOTSUP
+        })
+        .into())
+    }
+}
+
+/// Get the metadata of `path`, without following a symbolic link in the final component.
+fn metadata_nofollow<D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+) -> Result<openat::Metadata, Error> {
+    let root = util::borrow_dir(dir);
+    let (subdir, fname) = prepare_inner_operation(dir, path, lookup_flags)?;
+
+    let subdir = subdir.as_ref().unwrap_or(&root);
 
-    if let Some(new_fname) = new_fname {
-        openat::rename(old_subdir, old_fname, new_subdir, new_fname)
+    if let Some(fname) = fname {
+        subdir.metadata(fname).map_err(|e| error::at_final(path, e))
     } else {
-        Err(std::io::Error::from_raw_os_error(
-            if util::same_dir(new_dir, &new_subdir)? {
-                libc::EBUSY
-            } else {
-                // We rewound up a directory; that means that the directory isn't empty
-                libc::ENOTEMPTY
-            },
-        ))
+        Ok(subdir.self_metadata()?)
+    }
+}
+
+/// Convert the result of a `stat()`-like operation to whether the file exists.
+fn not_found_ok<T>(res: Result<T, Error>) -> Result<bool, Error> {
+    match res {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(false),
+        Err(e) => Err(e),
     }
 }
 
-fn prepare_inner_operation<'a>(
-    dir: &Dir,
+fn prepare_inner_operation<'a, D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &'a Path,
+    lookup_flags: LookupFlags,
+) -> Result<(Option<Dir>, Option<&'a OsStr>), Error> {
+    prepare_inner_operation_counting(dir, path, lookup_flags, None)
+}
+
+/// Like `prepare_inner_operation()`, but if `n_symlinks` is given, the parent directory is opened
+/// with the manual resolver, which counts the symbolic links that it follows in `n_symlinks`
+/// (against the same limit).
+fn prepare_inner_operation_counting<'a, D: AsRawFd + ?Sized>(
+    dir: &D,
     mut path: &'a Path,
     lookup_flags: LookupFlags,
-) -> io::Result<(Option<Dir>, Option<&'a OsStr>)> {
+    mut n_symlinks: Option<&mut usize>,
+) -> Result<(Option<Dir>, Option<&'a OsStr>), Error> {
+    let mut open_parent = |parent: &Path| match n_symlinks.as_deref_mut() {
+        Some(n_symlinks) => open::open_file_fallback_counting(
+            dir,
+            parent,
+            lookup_flags,
+            constants::BASE_DIR_FLAGS,
+            0,
+            n_symlinks,
+        )
+        .map(|fd| unsafe { Dir::from_raw_fd(fd) }),
+        None => open_sub_dir(dir, parent, lookup_flags),
+    };
+
+    // The number of leading components that were stripped, so that error component indices still
+    // refer to the original path
+    let mut n_stripped = 0;
+
     match path.strip_prefix("/") {
         Ok(p) => {
             // Trim the "/" prefix
             path = p;
+            n_stripped = 1;
 
             if path.as_os_str().is_empty() {
                 // Just "/"
@@ -495,7 +1045,7 @@ fn prepare_inner_operation<'a>(
         Err(_) => {
             if path.as_os_str().is_empty() {
                 // Empty path -> ENOENT
-                return Err(std::io::Error::from_raw_os_error(libc::ENOENT));
+                return Err(io::Error::from_raw_os_error(libc::ENOENT).into());
             }
         }
     }
@@ -512,7 +1062,8 @@ fn prepare_inner_operation<'a>(
             // Though it might be empty, in which case we just reuse the existing directory
             Ok((None, Some(fname)))
         } else {
-            Ok((Some(dir.sub_dir_secure(parent, lookup_flags)?), Some(fname)))
+            let subdir = open_parent(parent).map_err(|e| e.shift_component(n_stripped))?;
+            Ok((Some(subdir), Some(fname)))
         }
     } else {
         debug_assert!(path.ends_with(".."));
@@ -520,6 +1071,48 @@ fn prepare_inner_operation<'a>(
         // So this is a path like "a/b/..". We can't really get a (containing directory, filename)
         // pair out of this.
 
-        Ok((Some(dir.sub_dir_secure(path, lookup_flags)?), None))
+        let subdir = open_parent(path).map_err(|e| e.shift_component(n_stripped))?;
+        Ok((Some(subdir), None))
     }
 }
+
+/// Open a directory so that its entries can be read (i.e. not with `O_PATH`).
+fn open_dir_for_listing<D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+) -> Result<Dir, Error> {
+    let fd = open::open_file_secure(
+        dir,
+        path,
+        lookup_flags,
+        libc::O_RDONLY | libc::O_DIRECTORY,
+        0,
+    )?;
+
+    Ok(unsafe { Dir::from_raw_fd(fd) })
+}
+
+fn open_sub_dir<D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+) -> Result<Dir, Error> {
+    let fd = open::open_file_secure(dir, path, lookup_flags, constants::BASE_DIR_FLAGS, 0)?;
+
+    Ok(unsafe { Dir::from_raw_fd(fd) })
+}
+
+fn open_file<D: AsRawFd + ?Sized>(
+    dir: &D,
+    op: &'static str,
+    path: &Path,
+    lookup_flags: LookupFlags,
+    final_flags: libc::c_int,
+    mode: libc::mode_t,
+) -> Result<fs::File, Error> {
+    let fd = open::open_file_secure(dir, path, lookup_flags, final_flags, mode)
+        .map_err(|e| e.context(op, path))?;
+
+    Ok(unsafe { fs::File::from_raw_fd(fd) })
+}
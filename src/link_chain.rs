@@ -0,0 +1,139 @@
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::{util, Error, ErrorKind, LookupFlags};
+
+/// One step in a chain of symbolic links, as returned by
+/// [`DirSecureExt::read_link_chain_secure()`](trait.DirSecureExt.html#method.read_link_chain_secure).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct SymlinkHop {
+    path: PathBuf,
+    target: PathBuf,
+}
+
+impl SymlinkHop {
+    /// The path (relative to the root directory) at which the symbolic link was found.
+    ///
+    /// For every hop after the first, this is the previous link's target, joined onto the path of
+    /// the directory containing the previous link (if the target is relative). It is not
+    /// normalized, since `..` components can only be interpreted correctly during resolution.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The raw contents of the symbolic link.
+    pub fn target(&self) -> &Path {
+        &self.target
+    }
+}
+
+/// Get the path that the symlink at `path` with the given target resolves to (relative to the
+/// root).
+pub(crate) fn next_path(path: &Path, target: &Path) -> PathBuf {
+    let mut next = if target.has_root() {
+        target.to_path_buf()
+    } else {
+        path.parent().unwrap_or_else(|| Path::new("")).join(target)
+    };
+
+    // A trailing slash on the path still applies after following the link
+    if path.as_os_str().as_bytes().ends_with(b"/") && !next.as_os_str().as_bytes().ends_with(b"/") {
+        next.as_mut_os_string().push("/");
+    }
+
+    next
+}
+
+pub(crate) fn read_link_chain<D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+) -> Result<Vec<SymlinkHop>, Error> {
+    let n_symlinks_max = if lookup_flags.contains(LookupFlags::NO_SYMLINKS) {
+        0
+    } else {
+        util::get_symloop_max().unwrap_or(crate::constants::DEFAULT_SYMLOOP_MAX)
+    };
+
+    // Errors after the first hop are attributed to the last component of the original path
+    let final_index = path.components().count().checked_sub(1);
+
+    let mut hops: Vec<SymlinkHop> = Vec::new();
+    let mut cur = path.to_path_buf();
+    // Shared with the lookups of the directories containing each link, so that every symlink
+    // followed (not just the hops) counts against the limit
+    let mut n_symlinks = 0;
+    // Each lookup starts from the root again, so symlinks in the leading directories that were
+    // already counted by the previous lookup are followed again, and must not be counted twice
+    let mut n_recounted = 0;
+
+    let root = util::borrow_dir(dir);
+
+    loop {
+        let first = hops.is_empty();
+
+        let n_start = n_symlinks - n_recounted;
+        let mut n_lookup = n_start;
+        let read_res =
+            crate::prepare_inner_operation_counting(dir, &cur, lookup_flags, Some(&mut n_lookup))
+                .and_then(|(subdir, fname)| {
+                    let fname = match fname {
+                        // Strip any trailing slash so that the link itself is read
+                        Some(fname) => OsStr::from_bytes(
+                            fname
+                                .as_bytes()
+                                .strip_suffix(b"/")
+                                .unwrap_or_else(|| fname.as_bytes()),
+                        ),
+                        // A path like "/" or "a/.."; definitely not a symlink
+                        None => return Err(io::Error::from_raw_os_error(libc::EINVAL).into()),
+                    };
+
+                    subdir
+                        .as_ref()
+                        .unwrap_or(&root)
+                        .read_link(fname)
+                        .map_err(|e| crate::error::at_final(&cur, e))
+                });
+
+        let target = match read_res {
+            Ok(target) => target,
+
+            // Reached something that isn't a symlink
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(hops),
+
+            // The chain ends in a dangling link
+            Err(e)
+                if !first
+                    && matches!(e.raw_os_error(), Some(libc::ENOENT) | Some(libc::ENOTDIR)) =>
+            {
+                return Ok(hops)
+            }
+
+            Err(e) if first => return Err(e),
+            Err(e) => return Err(e.set_component(final_index)),
+        };
+
+        // Following this link would exceed the budget
+        if n_lookup >= n_symlinks_max {
+            return Err(
+                Error::from_raw_os_error(ErrorKind::SymlinkLoop, libc::ELOOP)
+                    .at_component(final_index),
+            );
+        }
+        n_symlinks = n_lookup + 1;
+
+        // Unless the target is absolute, the next lookup goes through this link's directory again
+        n_recounted = if target.has_root() {
+            0
+        } else {
+            n_lookup - n_start
+        };
+
+        let next = next_path(&cur, &target);
+        hops.push(SymlinkHop { path: cur, target });
+        cur = next;
+    }
+}
@@ -0,0 +1,208 @@
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::{open, Error, LookupFlags};
+
+/// The kind of lock to acquire with
+/// [`DirSecureExt::lock_file_secure()`](trait.DirSecureExt.html#method.lock_file_secure).
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum LockKind {
+    /// A shared (read) lock. Any number of shared locks can be held at once.
+    Shared,
+    /// An exclusive (write) lock. This conflicts with all other locks.
+    Exclusive,
+}
+
+/// How long to wait for a conflicting lock to be released.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum LockWait {
+    Block,
+    NonBlock,
+    Timeout(Duration),
+}
+
+/// A lock held on a file opened with
+/// [`DirSecureExt::lock_file_secure()`](trait.DirSecureExt.html#method.lock_file_secure) (or one
+/// of its variants).
+///
+/// On Linux, this is an open file description lock (`F_OFD_SETLK`); on other platforms, it is a
+/// `flock()` lock. Either way, the lock is associated with the open file, not the process, so it
+/// conflicts with locks taken through other opens of the same file (even in the same process).
+///
+/// The lock is released when this is dropped.
+#[derive(Debug)]
+pub struct FileLock {
+    file: fs::File,
+    kind: LockKind,
+}
+
+impl FileLock {
+    /// The kind of lock that is held.
+    pub fn kind(&self) -> LockKind {
+        self.kind
+    }
+
+    /// The locked file (which is open for reading and writing).
+    pub fn file(&self) -> &fs::File {
+        &self.file
+    }
+
+    /// Release the lock and return the file.
+    pub fn unlock(self) -> io::Result<fs::File> {
+        let this = std::mem::ManuallyDrop::new(self);
+        let file = unsafe { std::ptr::read(&this.file) };
+
+        unlock(file.as_raw_fd())?;
+        Ok(file)
+    }
+}
+
+impl AsRawFd for FileLock {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Closing the file would release the lock anyway, but only if there are no duplicates of
+        // the file descriptor
+        let _ = unlock(self.file.as_raw_fd());
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn ofd_lock(fd: RawFd, l_type: libc::c_int, wait: bool) -> io::Result<()> {
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = l_type as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    // l_start, l_len, and l_pid are all 0, which locks the entire file (and is required for OFD
+    // locks)
+
+    let cmd = if wait {
+        libc::F_OFD_SETLKW
+    } else {
+        libc::F_OFD_SETLK
+    };
+
+    loop {
+        if unsafe { libc::fcntl(fd, cmd, &lock) } == 0 {
+            return Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINTR) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn try_lock(fd: RawFd, kind: LockKind, wait: bool) -> io::Result<()> {
+    ofd_lock(
+        fd,
+        match kind {
+            LockKind::Shared => libc::F_RDLCK,
+            LockKind::Exclusive => libc::F_WRLCK,
+        },
+        wait,
+    )
+}
+
+#[cfg(target_os = "linux")]
+fn unlock(fd: RawFd) -> io::Result<()> {
+    ofd_lock(fd, libc::F_UNLCK, false)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn flock(fd: RawFd, op: libc::c_int) -> io::Result<()> {
+    loop {
+        if unsafe { libc::flock(fd, op) } == 0 {
+            return Ok(());
+        }
+
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINTR) {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn try_lock(fd: RawFd, kind: LockKind, wait: bool) -> io::Result<()> {
+    let op = match kind {
+        LockKind::Shared => libc::LOCK_SH,
+        LockKind::Exclusive => libc::LOCK_EX,
+    };
+
+    flock(fd, if wait { op } else { op | libc::LOCK_NB })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unlock(fd: RawFd) -> io::Result<()> {
+    flock(fd, libc::LOCK_UN)
+}
+
+fn is_contended(err: &io::Error) -> bool {
+    // fcntl() may fail with either EAGAIN or EACCES; flock() fails with EWOULDBLOCK (which is the
+    // same as EAGAIN on every supported platform)
+    matches!(err.raw_os_error(), Some(libc::EAGAIN) | Some(libc::EACCES))
+}
+
+fn acquire(fd: RawFd, kind: LockKind, wait: LockWait) -> io::Result<()> {
+    match wait {
+        LockWait::Block => try_lock(fd, kind, true),
+
+        LockWait::NonBlock => try_lock(fd, kind, false).map_err(|e| {
+            if is_contended(&e) {
+                io::Error::from_raw_os_error(libc::EWOULDBLOCK)
+            } else {
+                e
+            }
+        }),
+
+        LockWait::Timeout(timeout) => {
+            // Neither fcntl() nor flock() supports a timeout, so poll
+            let deadline = Instant::now() + timeout;
+            let mut delay = Duration::from_millis(1);
+
+            loop {
+                match try_lock(fd, kind, false) {
+                    Err(e) if is_contended(&e) => (),
+                    res => return res,
+                }
+
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(io::Error::from_raw_os_error(libc::ETIMEDOUT));
+                }
+
+                std::thread::sleep(std::cmp::min(delay, deadline - now));
+                delay = std::cmp::min(delay * 2, Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+/// Open (creating if necessary) and lock the given file.
+pub(crate) fn lock_file<D: AsRawFd + ?Sized>(
+    dir: &D,
+    op: &'static str,
+    path: &Path,
+    mode: libc::mode_t,
+    kind: LockKind,
+    wait: LockWait,
+    lookup_flags: LookupFlags,
+) -> Result<FileLock, Error> {
+    let fd = open::open_file_secure(dir, path, lookup_flags, libc::O_CREAT | libc::O_RDWR, mode)
+        .map_err(|e| e.context(op, path))?;
+    let file = unsafe { fs::File::from_raw_fd(fd) };
+
+    acquire(file.as_raw_fd(), kind, wait).map_err(|e| Error::from(e).context(op, path))?;
+
+    Ok(FileLock { file, kind })
+}
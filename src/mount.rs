@@ -0,0 +1,120 @@
+use std::ffi::CStr;
+use std::io;
+use std::os::unix::prelude::*;
+
+use crate::util;
+
+/// Check whether the directory `fd` is the root of a mount.
+///
+/// On Linux, this uses `statx()`: the `STATX_ATTR_MOUNT_ROOT` attribute (Linux 5.8+), or else a
+/// comparison of the mount IDs of the directory and its parent (Linux 5.8+ as well, but some
+/// filesystems report one and not the other). On older kernels, and on other platforms, this falls
+/// back on comparing the device numbers of the directory and its parent, which won't detect bind
+/// mounts from the same filesystem.
+pub fn is_mount_point(fd: RawFd) -> io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    match is_mount_point_statx(fd) {
+        Ok(Some(res)) => return Ok(res),
+        Ok(None) => (),
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => (),
+        Err(e) => return Err(e),
+    }
+
+    is_mount_point_dev(fd)
+}
+
+fn dotdot() -> &'static CStr {
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"..\0") }
+}
+
+/// Check by comparing the device numbers of the directory and its parent.
+fn is_mount_point_dev(fd: RawFd) -> io::Result<bool> {
+    let st = util::fstat(fd)?;
+
+    let mut parent_st = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatat(fd, dotdot().as_ptr(), &mut parent_st, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The root directory is its own parent (and it's always a mount point)
+    Ok(st.st_dev != parent_st.st_dev || util::same_stat(&st, &parent_st))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn statx(
+    fd: RawFd,
+    path: &CStr,
+    flags: libc::c_int,
+    mask: libc::c_uint,
+) -> io::Result<libc::statx> {
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::syscall(libc::SYS_statx, fd, path.as_ptr(), flags, mask, &mut stx) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(stx)
+    }
+}
+
+/// Check using `statx()`, returning `None` if the kernel doesn't report enough information.
+#[cfg(target_os = "linux")]
+fn is_mount_point_statx(fd: RawFd) -> io::Result<Option<bool>> {
+    let empty = unsafe { CStr::from_bytes_with_nul_unchecked(b"\0") };
+    let mask = libc::STATX_TYPE | libc::STATX_INO | libc::STATX_MNT_ID;
+
+    let stx = statx(
+        fd,
+        empty,
+        libc::AT_EMPTY_PATH | libc::AT_SYMLINK_NOFOLLOW,
+        mask,
+    )?;
+
+    let mount_root = libc::STATX_ATTR_MOUNT_ROOT as u64;
+    if stx.stx_attributes_mask & mount_root != 0 {
+        return Ok(Some(stx.stx_attributes & mount_root != 0));
+    }
+
+    if stx.stx_mask & libc::STATX_MNT_ID == 0 {
+        return Ok(None);
+    }
+
+    let parent_stx = statx(fd, dotdot(), libc::AT_SYMLINK_NOFOLLOW, mask)?;
+    if parent_stx.stx_mask & libc::STATX_MNT_ID == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(
+        stx.stx_mnt_id != parent_stx.stx_mnt_id
+            || (stx.stx_ino == parent_stx.stx_ino
+                && stx.stx_dev_major == parent_stx.stx_dev_major
+                && stx.stx_dev_minor == parent_stx.stx_dev_minor),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_mount_point() {
+        let root = openat::Dir::open("/").unwrap();
+        assert!(is_mount_point(root.as_raw_fd()).unwrap());
+        assert!(is_mount_point_dev(root.as_raw_fd()).unwrap());
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(tmpdir.path().join("a")).unwrap();
+        let dir = openat::Dir::open(&tmpdir.path().join("a")).unwrap();
+        assert!(!is_mount_point(dir.as_raw_fd()).unwrap());
+        assert!(!is_mount_point_dev(dir.as_raw_fd()).unwrap());
+
+        // procfs is always a separate filesystem
+        #[cfg(target_os = "linux")]
+        if let Ok(proc_dir) = openat::Dir::open("/proc") {
+            assert!(is_mount_point(proc_dir.as_raw_fd()).unwrap());
+            assert!(is_mount_point_dev(proc_dir.as_raw_fd()).unwrap());
+            if let Some(res) = is_mount_point_statx(proc_dir.as_raw_fd()).unwrap() {
+                assert!(res);
+            }
+        }
+    }
+}
@@ -1,93 +1,485 @@
-use std::collections::LinkedList;
-use std::ffi::{CStr, CString};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, OsStr};
 use std::fs;
 use std::io;
 use std::os::unix::prelude::*;
-use std::path::{Component, Path};
+use std::path::Path;
 
 use openat::Dir;
+use smallvec::SmallVec;
 
-use crate::LookupFlags;
+use crate::backend::{self, Backend};
+use crate::{Error, ErrorKind, LookupFlags};
 
 #[cfg(target_os = "linux")]
 use crate::openat2;
 
-fn open_file_base(
+/// Build the `openat2()` arguments that implement the given lookup flags, or `None` if
+/// `openat2()` cannot be used to implement them.
+#[cfg(target_os = "linux")]
+pub fn openat2_how(
+    lookup_flags: LookupFlags,
+    final_flags: libc::c_int,
+    mode: libc::mode_t,
+) -> Option<openat2::OpenHow> {
+    if lookup_flags.contains(LookupFlags::NO_XDEV)
+        && lookup_flags.contains(LookupFlags::XDEV_BIND_OK)
+    {
+        return None;
+    }
+
+    let mut open_how = openat2::OpenHow::new(final_flags);
+    open_how.mode = Some(mode);
+    // Disable magic link resolution by default -- no good can come
+    // from magic links!
+    open_how.resolve_flags = openat2::ResolveFlags::NO_MAGICLINKS | openat2::ResolveFlags::IN_ROOT;
+
+    if lookup_flags.contains(LookupFlags::NO_SYMLINKS) {
+        open_how
+            .resolve_flags
+            .insert(openat2::ResolveFlags::NO_SYMLINKS);
+    }
+    if lookup_flags.contains(LookupFlags::NO_XDEV) {
+        open_how
+            .resolve_flags
+            .insert(openat2::ResolveFlags::NO_XDEV);
+    }
+
+    // Skip openat2() if we already know it won't work (or the fallback was explicitly requested)
+    if !backend::openat2_usable(open_how.resolve_flags) {
+        return None;
+    }
+
+    Some(open_how)
+}
+
+/// Returns whether the given error from `openat2()` (called with the given arguments) means that
+/// we should fall back on the manual implementation.
+///
+/// This also records what the error says about `openat2()` support, so later calls can skip it.
+#[cfg(target_os = "linux")]
+pub fn openat2_should_fallback(how: &openat2::OpenHow, err: &io::Error) -> bool {
+    backend::openat2_failed(how.resolve_flags, err)
+}
+
+/// Classify an error from `openat2()` (called with the arguments from `openat2_how()`).
+///
+/// `openat2()` doesn't tell us which component failed, so the component index is left unset.
+#[cfg(target_os = "linux")]
+pub fn openat2_error(lookup_flags: LookupFlags, err: io::Error) -> Error {
+    let kind = match err.raw_os_error() {
+        // With RESOLVE_IN_ROOT, EXDEV means either that a filesystem boundary was crossed with
+        // RESOLVE_NO_XDEV, or that the lookup would have escaped the root
+        Some(libc::EXDEV) if lookup_flags.contains(LookupFlags::NO_XDEV) => ErrorKind::CrossDevice,
+        Some(libc::EXDEV) => ErrorKind::EscapeAttempt,
+        // The kernel returns EAGAIN if a concurrent rename or mount was detected during a scoped
+        // lookup
+        Some(libc::EAGAIN) => ErrorKind::Race,
+        _ => return err.into(),
+    };
+
+    Error::new(kind, err)
+}
+
+pub fn open_file_secure<D: AsRawFd + ?Sized>(
+    root_dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+    final_flags: libc::c_int,
+    mode: libc::mode_t,
+) -> Result<RawFd, Error> {
+    crate::retry::retry(|| open_file_once(root_dir, path, lookup_flags, final_flags, mode))
+}
+
+fn open_file_once<D: AsRawFd + ?Sized>(
+    root_dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+    final_flags: libc::c_int,
+    mode: libc::mode_t,
+) -> Result<RawFd, Error> {
+    #[cfg(target_os = "linux")]
+    if let Some(open_how) = openat2_how(lookup_flags, final_flags, mode) {
+        if lookup_flags.contains(LookupFlags::PARANOID) && backend::backend() == Backend::Auto {
+            return open_file_paranoid(root_dir, path, lookup_flags, final_flags, mode, &open_how);
+        }
+
+        match openat2::openat2(Some(root_dir.as_raw_fd()), path, &open_how) {
+            Ok(fd) => {
+                backend::openat2_succeeded();
+                return Ok(fd);
+            }
+            Err(e) if openat2_should_fallback(&open_how, &e) => (),
+            Err(e) => return Err(openat2_error(lookup_flags, e)),
+        }
+    }
+
+    if backend::backend() == Backend::ForceOpenat2 {
+        // Either openat2() is unavailable on this platform, or it can't implement these lookup
+        // flags
+        #[cfg(target_os = "linux")]
+        let errno = libc::ENOTSUP;
+        #[cfg(not(target_os = "linux"))]
+        let errno = libc::ENOSYS;
+
+        return Err(io::Error::from_raw_os_error(errno).into());
+    }
+
+    open_file_fallback(root_dir, path, lookup_flags, final_flags, mode)
+}
+
+/// Open the file with both `openat2()` and the manual implementation, and check that the results
+/// match.
+#[cfg(target_os = "linux")]
+fn open_file_paranoid<D: AsRawFd + ?Sized>(
+    root_dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+    final_flags: libc::c_int,
+    mode: libc::mode_t,
+    open_how: &openat2::OpenHow,
+) -> Result<RawFd, Error> {
+    let mismatch = |msg: String| Error::new(ErrorKind::BackendMismatch, io::Error::other(msg));
+
+    match openat2::openat2(Some(root_dir.as_raw_fd()), path, open_how) {
+        Ok(fd) => {
+            backend::openat2_succeeded();
+            let file = unsafe { fs::File::from_raw_fd(fd) };
+
+            // The file may have just been created or truncated, so only look it up (without
+            // opening it) to avoid repeating any side effects
+            let check_flags = libc::O_PATH | (final_flags & (libc::O_DIRECTORY | libc::O_NOFOLLOW));
+
+            let check_fd = match open_file_fallback(root_dir, path, lookup_flags, check_flags, 0) {
+                Ok(check_fd) => unsafe { fs::File::from_raw_fd(check_fd) },
+                Err(e) if e.kind() == ErrorKind::Race => return Err(e),
+                Err(e) => {
+                    return Err(mismatch(format!(
+                        "openat2() succeeded, but the fallback failed: {}",
+                        e
+                    )))
+                }
+            };
+
+            let st = crate::util::fstat(file.as_raw_fd())?;
+            let check_st = crate::util::fstat(check_fd.as_raw_fd())?;
+            if !crate::util::same_stat(&st, &check_st) {
+                return Err(mismatch(format!(
+                    "openat2() opened inode {}:{}, but the fallback opened inode {}:{}",
+                    st.st_dev, st.st_ino, check_st.st_dev, check_st.st_ino,
+                )));
+            }
+
+            Ok(file.into_raw_fd())
+        }
+
+        Err(e) if openat2_should_fallback(open_how, &e) => {
+            open_file_fallback(root_dir, path, lookup_flags, final_flags, mode)
+        }
+
+        Err(e) => {
+            let err = openat2_error(lookup_flags, e);
+            if err.kind() == ErrorKind::Race {
+                return Err(err);
+            }
+
+            // Errors from opening the file itself (like EACCES, EEXIST, or EISDIR) can't be
+            // reproduced by only looking it up, so only errors from the lookup are compared
+            if !matches!(
+                err.raw_os_error(),
+                Some(libc::ENOENT | libc::ENOTDIR | libc::ELOOP | libc::EXDEV)
+            ) {
+                return Err(err);
+            }
+
+            // Like above, only look the file up, so that the check can't create or truncate a
+            // file that openat2() refused to. With O_PATH, O_NOFOLLOW opens a symlink instead of
+            // failing with ELOOP, so account for that.
+            let check_flags = libc::O_PATH | (final_flags & (libc::O_DIRECTORY | libc::O_NOFOLLOW));
+
+            let check = match open_file_fallback(root_dir, path, lookup_flags, check_flags, 0) {
+                Err(e) if e.kind() == ErrorKind::Race => return Err(e),
+                Err(e) => Err(e),
+                Ok(check_fd) => {
+                    let check_fd = unsafe { fs::File::from_raw_fd(check_fd) };
+                    let st = crate::util::fstat(check_fd.as_raw_fd())?;
+                    if st.st_mode & libc::S_IFMT == libc::S_IFLNK {
+                        Err(Error::from_raw_os_error(
+                            ErrorKind::SymlinkLoop,
+                            libc::ELOOP,
+                        ))
+                    } else {
+                        Ok(())
+                    }
+                }
+            };
+
+            match check {
+                Ok(()) => Err(mismatch(format!(
+                    "openat2() failed, but the fallback succeeded: {}",
+                    err
+                ))),
+                Err(e) if e.raw_os_error() != err.raw_os_error() => Err(mismatch(format!(
+                    "openat2() and the fallback failed with different errors: {}; {}",
+                    err, e
+                ))),
+                Err(_) => Err(err),
+            }
+        }
+    }
+}
+
+/// The size of the stack buffer for a single path component (plus the terminating NUL). Longer
+/// components are copied to the heap instead; the filesystem decides how long they can be.
+const NAME_BUF_SIZE: usize = 256;
+
+/// The most buffer space that will be kept around between calls.
+const MAX_RETAINED_BUF: usize = 16 * 1024;
+
+thread_local! {
+    // Reused between calls to avoid allocating
+    static PATH_BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Component {
+    Root,
+    Parent,
+    /// A filename, stored in the path buffer at the given range
+    Name(usize, usize),
+}
+
+/// A component that still needs to be resolved, along with the index of the component of the
+/// original path that it came from (components from symlink targets get the index of the
+/// symlink), for error reporting.
+#[derive(Copy, Clone, Debug)]
+struct Pending {
+    component: Component,
+    index: usize,
+}
+
+/// Push the components of `buf[start..end]` onto the stack (in reverse order, so they can be
+/// popped off in order).
+///
+/// If `index` is `None`, each component is given its own index; otherwise they are all given
+/// `index`.
+fn push_components(
+    buf: &[u8],
+    start: usize,
+    end: usize,
+    index: Option<usize>,
+    stack: &mut SmallVec<[Pending; 16]>,
+) {
+    let path = Path::new(OsStr::from_bytes(&buf[start..end]));
+
+    let mut cur_index = path.components().count();
+
+    for component in path.components().rev() {
+        cur_index -= 1;
+
+        let component = match component {
+            std::path::Component::RootDir => Component::Root,
+            std::path::Component::ParentDir => Component::Parent,
+            std::path::Component::Normal(fname) => {
+                let fname_start = fname.as_bytes().as_ptr() as usize - buf.as_ptr() as usize;
+                Component::Name(fname_start, fname_start + fname.len())
+            }
+            std::path::Component::CurDir => continue,
+            // This is a Unix-only crate
+            std::path::Component::Prefix(_) => unreachable!(),
+        };
+
+        stack.push(Pending {
+            component,
+            index: index.unwrap_or(cur_index),
+        });
+    }
+}
+
+/// Returns whether the final component of the given path has to be a directory (i.e. whether it
+/// ends with a slash or "/.").
+fn requires_dir(path: &[u8]) -> bool {
+    path.ends_with(b"/") || path.ends_with(b"/.")
+}
+
+/// Returns whether the final component of the given path is a name (not "." or "..") followed by
+/// a slash.
+fn slashed_name(path: &[u8]) -> bool {
+    let trimmed = match path.iter().rposition(|&c| c != b'/') {
+        Some(end) if end + 1 < path.len() => &path[..=end],
+        _ => return false,
+    };
+    let name = trimmed.rsplit(|&c| c == b'/').next().unwrap_or(trimmed);
+    name != b"." && name != b".."
+}
+
+/// Returns whether `fd` (opened with the given flags plus `O_NOFOLLOW`) refers to a symlink that
+/// should have been followed.
+fn opened_symlink(fd: RawFd, flags: libc::c_int) -> bool {
+    #[cfg(target_os = "linux")]
+    if flags & (libc::O_PATH | libc::O_NOFOLLOW) == libc::O_PATH {
+        return crate::util::fstat(fd).is_ok_and(|st| st.st_mode & libc::S_IFMT == libc::S_IFLNK);
+    }
+
+    let _ = (fd, flags);
+    false
+}
+
+/// Copy a filename into a buffer and NUL-terminate it (or into a new `CString` if it doesn't fit).
+fn name_to_cstr<'a>(
+    name: &[u8],
+    name_buf: &'a mut [u8; NAME_BUF_SIZE],
+) -> io::Result<Cow<'a, CStr>> {
+    if name.len() >= NAME_BUF_SIZE {
+        return Ok(Cow::Owned(CString::new(name)?));
+    }
+
+    name_buf[..name.len()].copy_from_slice(name);
+    name_buf[name.len()] = 0;
+
+    Ok(Cow::Borrowed(unsafe {
+        CStr::from_bytes_with_nul_unchecked(&name_buf[..=name.len()])
+    }))
+}
+
+fn open_name(
     dirfd: RawFd,
     fname: &CStr,
     flags: libc::c_int,
     mode: libc::mode_t,
-) -> io::Result<fs::File> {
+) -> io::Result<RawFd> {
     let fd = unsafe { libc::openat(dirfd, fname.as_ptr(), flags, mode as libc::c_int) };
 
     if fd < 0 {
         Err(io::Error::last_os_error())
     } else {
-        Ok(unsafe { fs::File::from_raw_fd(fd) })
+        Ok(fd)
     }
 }
 
-fn map_component_cstring(component: Component) -> io::Result<Option<CString>> {
-    Ok(match component {
-        Component::CurDir => None,
-        Component::RootDir => Some(CString::new(*b"/").unwrap()),
-        Component::ParentDir => Some(CString::new(*b"..").unwrap()),
-        Component::Normal(fname) => Some(CString::new(fname.as_bytes())?),
-        // This is a Unix-only crate
-        Component::Prefix(_) => unreachable!(),
-    })
+/// Read the target of the given symlink and append it to `buf`, returning the range where it was
+/// stored.
+fn read_link_into(dirfd: RawFd, fname: &CStr, buf: &mut Vec<u8>) -> io::Result<(usize, usize)> {
+    let start = buf.len();
+    let mut size = libc::PATH_MAX as usize;
+
+    loop {
+        buf.reserve(size);
+
+        let n = unsafe {
+            libc::readlinkat(
+                dirfd,
+                fname.as_ptr(),
+                buf.as_mut_ptr().add(start) as *mut libc::c_char,
+                size,
+            )
+        };
+
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let n = n as usize;
+        if n < size {
+            unsafe {
+                buf.set_len(start + n);
+            }
+            return Ok((start, start + n));
+        }
+
+        // The target may have been truncated
+        size *= 2;
+    }
 }
 
-pub fn open_file_secure(
-    root_dir: &Dir,
+/// The manual implementation of `open_file_secure()`, used when `openat2()` is unavailable.
+pub fn open_file_fallback<D: AsRawFd + ?Sized>(
+    root_dir: &D,
     path: &Path,
     lookup_flags: LookupFlags,
-    mut final_flags: libc::c_int,
+    final_flags: libc::c_int,
     mode: libc::mode_t,
-) -> io::Result<RawFd> {
-    #[cfg(target_os = "linux")]
-    if !(lookup_flags.contains(LookupFlags::NO_XDEV)
-        && lookup_flags.contains(LookupFlags::XDEV_BIND_OK))
-    {
-        let mut open_how = openat2::OpenHow::new(final_flags);
-        open_how.mode = Some(mode);
-        // Disable magic link resolution by default -- no good can come
-        // from magic links!
-        open_how.resolve_flags =
-            openat2::ResolveFlags::NO_MAGICLINKS | openat2::ResolveFlags::IN_ROOT;
-
-        if lookup_flags.contains(LookupFlags::NO_SYMLINKS) {
-            open_how
-                .resolve_flags
-                .insert(openat2::ResolveFlags::NO_SYMLINKS);
-        }
-        if lookup_flags.contains(LookupFlags::NO_XDEV) {
-            open_how
-                .resolve_flags
-                .insert(openat2::ResolveFlags::NO_XDEV);
-        }
+) -> Result<RawFd, Error> {
+    open_file_fallback_counting(root_dir, path, lookup_flags, final_flags, mode, &mut 0)
+}
 
-        match openat2::openat2(Some(root_dir.as_raw_fd()), path, &open_how) {
-            Ok(fd) => return Ok(fd),
-            Err(e) => match e.raw_os_error().unwrap_or(0) {
-                // ENOSYS means the kernel doesn't support openat2(); E2BIG means it doesn't
-                // support the options that we passed
-                libc::ENOSYS | libc::E2BIG => (),
-                _ => return Err(e),
-            },
+/// Like `open_file_fallback()`, but `n_symlinks` symbolic links have already been followed (and
+/// count against the limit), and it is increased by the number followed during this lookup.
+pub fn open_file_fallback_counting<D: AsRawFd + ?Sized>(
+    root_dir: &D,
+    path: &Path,
+    lookup_flags: LookupFlags,
+    final_flags: libc::c_int,
+    mode: libc::mode_t,
+    n_symlinks: &mut usize,
+) -> Result<RawFd, Error> {
+    PATH_BUF.with(|buf| match buf.try_borrow_mut() {
+        Ok(mut buf) => {
+            buf.clear();
+            let res = resolve(
+                root_dir.as_raw_fd(),
+                path,
+                lookup_flags,
+                final_flags,
+                mode,
+                n_symlinks,
+                &mut buf,
+            );
+
+            if buf.capacity() > MAX_RETAINED_BUF {
+                *buf = Vec::new();
+            }
+
+            res
         }
+
+        // Should never happen, but just in case
+        Err(_) => resolve(
+            root_dir.as_raw_fd(),
+            path,
+            lookup_flags,
+            final_flags,
+            mode,
+            n_symlinks,
+            &mut Vec::new(),
+        ),
+    })
+}
+
+fn resolve(
+    root_fd: RawFd,
+    path: &Path,
+    lookup_flags: LookupFlags,
+    mut final_flags: libc::c_int,
+    mode: libc::mode_t,
+    n_symlinks_found: &mut usize,
+    buf: &mut Vec<u8>,
+) -> Result<RawFd, Error> {
+    let path = path.as_os_str().as_bytes();
+    if path.contains(&0) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "data provided contains a nul byte",
+        )
+        .into());
     }
 
+    // Only stat() the root directory once; the device ID of every component is compared against it.
+    // The components themselves still have to be fstat()ed as they are opened: the device ID of a
+    // new file descriptor can't be learned any other way, and checking only the final component
+    // would miss paths that cross a mount point and come back out through "..". (".." components
+    // reuse the saved parent directories, which were already checked.)
     let root_dev = if lookup_flags.contains(LookupFlags::NO_XDEV) {
-        root_dir.self_metadata()?.stat().st_dev as u64
+        Some(crate::util::fstat(root_fd)?.st_dev)
     } else {
-        u64::MAX
+        None
     };
 
-    let mut curdir = None;
-    let mut parents: Vec<Dir> = Vec::new();
+    // None means the root directory
+    let mut curdir: Option<Dir> = None;
+    let mut parents: SmallVec<[Dir; 8]> = SmallVec::new();
 
-    let mut n_symlinks_found = 0;
     let n_symlinks_max = if lookup_flags.contains(LookupFlags::NO_SYMLINKS) {
         // Effectively disables symlink resolution
         0
@@ -95,146 +487,301 @@ pub fn open_file_secure(
         crate::util::get_symloop_max().unwrap_or(crate::constants::DEFAULT_SYMLOOP_MAX)
     };
 
-    let mut components = LinkedList::new();
-    for component in path.components() {
-        if let Some(fname) = map_component_cstring(component)? {
-            components.push_back(fname);
-        }
+    // A trailing slash (or "/.") means that the final component has to be a directory. Since
+    // those get dropped when the path is split into components, handle them here.
+    let mut must_be_dir = requires_dir(path);
+    if must_be_dir {
+        final_flags |= libc::O_DIRECTORY;
     }
+    // Like open(), O_CREAT never creates a directory. If the final component is a name followed
+    // by a slash, that fails with EISDIR before the name is even looked up; otherwise (for
+    // example with "/."), the directory is looked up first, and it fails with EISDIR if it exists.
+    let creating = final_flags & libc::O_CREAT == libc::O_CREAT;
+    let mut final_slashed_name = slashed_name(path);
 
-    while let Some(fname) = components.pop_front() {
-        if fname.as_bytes() == b"/" {
-            parents.clear();
-            curdir = None;
-        } else if fname.as_bytes() == b".." {
-            curdir = parents.pop();
-        } else {
-            let cur_flags = if components.is_empty() {
-                final_flags
-            } else {
-                crate::constants::BASE_DIR_FLAGS
-            };
+    buf.extend_from_slice(path);
 
-            let open_err = match open_file_base(
-                curdir.as_ref().unwrap_or(root_dir).as_raw_fd(),
-                &fname,
-                cur_flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
-                mode,
-            ) {
-                Ok(file) => {
-                    if lookup_flags.contains(LookupFlags::NO_XDEV)
-                        && file.metadata()?.dev() != root_dev
-                    {
-                        return Err(io::Error::from_raw_os_error(libc::EXDEV));
-                    }
+    let mut stack = SmallVec::new();
+    push_components(buf, 0, path.len(), None, &mut stack);
 
-                    if components.is_empty() {
-                        // Final component
-                        return Ok(file.into_raw_fd());
-                    } else {
-                        // Save the previous directory
-                        if let Some(olddir) = curdir {
-                            parents.push(olddir);
-                        } else {
-                            // If curdir is None, then parents should be empty
-                            debug_assert!(parents.is_empty());
-                        }
+    let mut name_buf = [0; NAME_BUF_SIZE];
 
-                        // Advance to the new directory
-                        curdir = Some(unsafe { Dir::from_raw_fd(file.into_raw_fd()) });
-                        None
-                    }
-                }
-                Err(e) => Some(e),
-            };
+    while let Some(Pending { component, index }) = stack.pop() {
+        let (start, end) = match component {
+            Component::Root => {
+                parents.clear();
+                curdir = None;
+                continue;
+            }
+            Component::Parent => {
+                curdir = parents.pop();
+                continue;
+            }
+            Component::Name(start, end) => (start, end),
+        };
 
-            if let Some(open_err) = open_err {
-                // An error occurred
+        let fname = name_to_cstr(&buf[start..end], &mut name_buf)
+            .map_err(|e| Error::from(e).at_component(Some(index)))?;
+        let fname = fname.as_ref();
+        let dirfd = curdir.as_ref().map_or(root_fd, |d| d.as_raw_fd());
 
-                let open_errno = open_err.raw_os_error().unwrap_or(0);
+        let cur_flags = if !stack.is_empty() {
+            crate::constants::BASE_DIR_FLAGS
+        } else if creating && must_be_dir {
+            if final_slashed_name {
+                return Err(Error::from_raw_os_error(ErrorKind::Other, libc::EISDIR)
+                    .at_component(Some(index)));
+            }
+            // Only look it up
+            crate::constants::BASE_DIR_FLAGS
+        } else {
+            final_flags
+        };
 
-                #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
-                let open_errno = if open_errno == libc::EMLINK {
-                    libc::ELOOP
-                } else {
-                    open_errno
-                };
+        let open_err = match open_name(
+            dirfd,
+            fname,
+            cur_flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+            mode,
+        ) {
+            Ok(fd) if stack.is_empty() && opened_symlink(fd, cur_flags) => {
+                // With O_PATH, O_NOFOLLOW opens the symlink itself instead of failing with ELOOP
+                unsafe {
+                    libc::close(fd);
+                }
+                io::Error::from_raw_os_error(libc::ELOOP)
+            }
+            Ok(fd) => {
+                let file = unsafe { fs::File::from_raw_fd(fd) };
 
-                #[cfg(target_os = "netbsd")]
-                let open_errno = if open_errno == libc::EFTYPE {
-                    libc::ELOOP
+                let verify_beneath_here =
+                    stack.is_empty() && lookup_flags.contains(LookupFlags::VERIFY_BENEATH);
+                // Shared between the NO_XDEV and VERIFY_BENEATH checks so it's only done once
+                let st = if root_dev.is_some() || verify_beneath_here {
+                    Some(crate::util::fstat(fd)?)
                 } else {
-                    open_errno
+                    None
                 };
 
-                if open_errno == libc::ELOOP || open_errno == libc::ENOTDIR {
-                    // The path may be a symbolic link.
-                    // If open_errno is ELOOP, it definitely is.
-                    // If open_errno is ENOTDIR, then it *might* be. Or it could just be a regular
-                    // file (or a block/character special, etc.).
-
-                    // Let's try to `readlink()` it.
-
-                    let target = match curdir
-                        .as_ref()
-                        .unwrap_or(root_dir)
-                        .read_link(fname.as_c_str())
-                    {
-                        // Successfully read the symlink
-                        Ok(t) => t,
-
-                        // EINVAL means it's not a symlink
-                        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
-                            return Err(if open_errno == libc::ENOTDIR {
-                                // All we knew was that it wasn't a directory, so it's probably
-                                // another file type.
-                                open_err
-                            } else {
-                                // We got ELOOP, indicating it *was* a symlink. Then we got EINVAL,
-                                // indicating that it *wasn't* a symlink.
-                                // This probably means a race condition. Let's pass up EAGAIN.
-                                io::Error::from_raw_os_error(libc::EAGAIN)
-                            });
-                        }
-
-                        // Pass other errors up
-                        Err(e) => return Err(e),
-                    };
-
-                    // If we got here, we know it's definitely a symlink.
-
-                    // Manually implement the maximum link count check.
-                    // n_symlinks_max is 0 if we were given the NO_SYMLINKS lookup flag, so this
-                    // implicitly handles that case too.
-                    if n_symlinks_found >= n_symlinks_max {
-                        return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                if let (Some(root_dev), Some(st)) = (root_dev, st.as_ref()) {
+                    if st.st_dev != root_dev {
+                        return Err(
+                            Error::from_raw_os_error(ErrorKind::CrossDevice, libc::EXDEV)
+                                .at_component(Some(index)),
+                        );
                     }
-                    n_symlinks_found += 1;
+                }
 
-                    // If we were doing the final lookup and the symbolic link target ends with a
-                    // '/', that means the final file has to be a directory.
-                    // So add O_DIRECTORY to the flags.
-                    if components.is_empty() && target.as_os_str().as_bytes().ends_with(b"/") {
-                        final_flags |= libc::O_DIRECTORY;
+                if stack.is_empty() {
+                    // Final component
+                    if creating && must_be_dir {
+                        return Err(Error::from_raw_os_error(ErrorKind::Other, libc::EISDIR)
+                            .at_component(Some(index)));
                     }
-
-                    // Add the other elements to the queue
-                    // The ordering is weird, but basically we add them in order at the front
-                    for target_component in target.components().rev() {
-                        if let Some(fname) = map_component_cstring(target_component)? {
-                            components.push_front(fname);
-                        }
+                    if let (true, Some(st)) = (verify_beneath_here, st) {
+                        // We can only walk up from a directory, so if this isn't one check the
+                        // directory that it was opened in
+                        let start_fd = if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+                            fd
+                        } else {
+                            dirfd
+                        };
+                        verify_beneath(root_fd, start_fd)
+                            .map_err(|e| e.at_component(Some(index)))?;
                     }
+
+                    return Ok(file.into_raw_fd());
+                }
+
+                // Save the previous directory
+                if let Some(olddir) = curdir.take() {
+                    parents.push(olddir);
                 } else {
-                    return Err(open_err);
+                    // If curdir is None, then parents should be empty
+                    debug_assert!(parents.is_empty());
                 }
+
+                // Advance to the new directory
+                curdir = Some(unsafe { Dir::from_raw_fd(file.into_raw_fd()) });
+                continue;
             }
+            Err(e) => e,
+        };
+
+        // An error occurred
+
+        let open_errno = open_err.raw_os_error().unwrap_or(0);
+
+        #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+        let open_errno = if open_errno == libc::EMLINK {
+            libc::ELOOP
+        } else {
+            open_errno
+        };
+
+        #[cfg(target_os = "netbsd")]
+        let open_errno = if open_errno == libc::EFTYPE {
+            libc::ELOOP
+        } else {
+            open_errno
+        };
+
+        if open_errno != libc::ELOOP && open_errno != libc::ENOTDIR {
+            return Err(Error::from(open_err).at_component(Some(index)));
         }
+
+        // The path may be a symbolic link.
+        // If open_errno is ELOOP, it definitely is.
+        // If open_errno is ENOTDIR, then it *might* be. Or it could just be a regular file (or a
+        // block/character special, etc.).
+
+        // Let's try to `readlink()` it.
+
+        let (target_start, target_end) = match read_link_into(dirfd, fname, buf) {
+            // Successfully read the symlink
+            Ok(range) => range,
+
+            // EINVAL means it's not a symlink
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                return Err(if open_errno == libc::ENOTDIR {
+                    // All we knew was that it wasn't a directory, so it's probably another file
+                    // type.
+                    Error::new(ErrorKind::NotADirectory, open_err)
+                } else {
+                    // We got ELOOP, indicating it *was* a symlink. Then we got EINVAL, indicating
+                    // that it *wasn't* a symlink.
+                    // This probably means a race condition. Let's pass up EAGAIN.
+                    Error::from_raw_os_error(ErrorKind::Race, libc::EAGAIN)
+                }
+                .at_component(Some(index)));
+            }
+
+            // Pass other errors up
+            Err(e) => return Err(Error::from(e).at_component(Some(index))),
+        };
+
+        // If we got here, we know it's definitely a symlink.
+
+        // Manually implement the maximum link count check.
+        // n_symlinks_max is 0 if we were given the NO_SYMLINKS lookup flag, so this implicitly
+        // handles that case too.
+        if *n_symlinks_found >= n_symlinks_max {
+            return Err(
+                Error::from_raw_os_error(ErrorKind::SymlinkLoop, libc::ELOOP)
+                    .at_component(Some(index)),
+            );
+        }
+        *n_symlinks_found += 1;
+
+        // If we were doing the final lookup and the symbolic link target ends with a '/', that
+        // means the final file has to be a directory.
+        // So add O_DIRECTORY to the flags.
+        if stack.is_empty() {
+            if requires_dir(&buf[target_start..target_end]) {
+                must_be_dir = true;
+                final_flags |= libc::O_DIRECTORY;
+            }
+            final_slashed_name = slashed_name(&buf[target_start..target_end]);
+        }
+
+        // Add the components of the target to the front of the queue
+        push_components(buf, target_start, target_end, Some(index), &mut stack);
+    }
+
+    if creating {
+        // The path ended with "." or ".." (or was just "/"), so it's a directory
+        return Err(Error::from_raw_os_error(ErrorKind::Other, libc::EISDIR));
     }
 
     if let Some(d) = curdir {
+        if lookup_flags.contains(LookupFlags::VERIFY_BENEATH) {
+            verify_beneath(root_fd, d.as_raw_fd())?;
+        }
+
         Ok(d.into_raw_fd())
     } else {
-        Ok(root_dir.try_clone()?.into_raw_fd())
+        let fd = unsafe { libc::fcntl(root_fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            Err(io::Error::last_os_error().into())
+        } else {
+            Ok(fd)
+        }
+    }
+}
+
+/// Check that the directory `fd` is the root directory or one of its descendants.
+fn verify_beneath(root_fd: RawFd, fd: RawFd) -> Result<(), Error> {
+    depth_beneath(root_fd, fd).map(drop)
+}
+
+/// Return how many levels the directory `fd` is below the root directory, by walking up the
+/// directory tree with `..` until either the root directory or the root of the filesystem is
+/// reached. Fails with `EXDEV` if `fd` is not beneath the root directory.
+pub fn depth_beneath(root_fd: RawFd, fd: RawFd) -> Result<usize, Error> {
+    let dotdot = unsafe { CStr::from_bytes_with_nul_unchecked(b"..\0") };
+
+    let root_st = crate::util::fstat(root_fd)?;
+    let mut st = crate::util::fstat(fd)?;
+    // None means `fd`
+    let mut curdir: Option<Dir> = None;
+    let mut depth = 0;
+
+    while !crate::util::same_stat(&st, &root_st) {
+        let parent_fd = open_name(
+            curdir.as_ref().map_or(fd, |d| d.as_raw_fd()),
+            dotdot,
+            crate::constants::BASE_DIR_FLAGS | libc::O_CLOEXEC,
+            0,
+        )?;
+        let parent = unsafe { Dir::from_raw_fd(parent_fd) };
+
+        let parent_st = crate::util::fstat(parent_fd)?;
+        if crate::util::same_stat(&parent_st, &st) {
+            // We reached the root of the filesystem (or of a chroot) without finding the root
+            // directory
+            return Err(Error::from_raw_os_error(
+                ErrorKind::EscapeAttempt,
+                libc::EXDEV,
+            ));
+        }
+
+        curdir = Some(parent);
+        st = parent_st;
+        depth += 1;
+    }
+
+    Ok(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_beneath() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmpdir.path().join("a/b/c")).unwrap();
+        std::fs::create_dir(tmpdir.path().join("d")).unwrap();
+
+        let root = Dir::open(&tmpdir.path().join("a")).unwrap();
+        let sub = Dir::open(&tmpdir.path().join("a/b/c")).unwrap();
+
+        assert_eq!(
+            depth_beneath(root.as_raw_fd(), root.as_raw_fd()).unwrap(),
+            0
+        );
+        assert_eq!(depth_beneath(root.as_raw_fd(), sub.as_raw_fd()).unwrap(), 2);
+
+        for outside in [tmpdir.path(), &tmpdir.path().join("d"), Path::new("/")].iter() {
+            let outside = Dir::open(*outside).unwrap();
+            let err = verify_beneath(root.as_raw_fd(), outside.as_raw_fd()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::EscapeAttempt);
+            assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+        }
+
+        // Simulate the directory being moved out of the root after it was opened
+        std::fs::rename(tmpdir.path().join("a/b"), tmpdir.path().join("d/b")).unwrap();
+        let err = verify_beneath(root.as_raw_fd(), sub.as_raw_fd()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::EscapeAttempt);
     }
 }
@@ -5,8 +5,8 @@ use std::path::Path;
 
 use bitflags::bitflags;
 
-const O_CREAT: i32 = libc::O_CREAT as i32;
-const O_TMPFILE: i32 = libc::O_TMPFILE as i32;
+const O_CREAT: i32 = libc::O_CREAT;
+const O_TMPFILE: i32 = libc::O_TMPFILE;
 
 // This is correct for every architecture except alpha, which
 // Rust does not support
@@ -52,10 +52,10 @@ impl OpenHow {
 
 #[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 #[repr(C)]
-struct RawOpenHow {
-    flags: u64,
-    mode: u64,
-    resolve: u64,
+pub struct RawOpenHow {
+    pub flags: u64,
+    pub mode: u64,
+    pub resolve: u64,
 }
 
 impl From<&OpenHow> for RawOpenHow {
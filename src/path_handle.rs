@@ -0,0 +1,202 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::PathBuf;
+
+use crate::{util, Error, ProcfsBase, ProcfsHandle};
+
+/// A handle that refers to a file without granting access to its contents (an `O_PATH` file
+/// descriptor).
+///
+/// This can refer to any type of file (including symbolic links, if they were not followed), and
+/// can be obtained without read permission on the file. It "pins" the inode: it will keep
+/// referring to the same file even if the file is renamed or replaced.
+///
+/// Created by
+/// [`DirSecureExt::open_path_secure()`](trait.DirSecureExt.html#method.open_path_secure) and
+/// [`DirSecureExt::open_path_nofollow_secure()`](trait.DirSecureExt.html#method.open_path_nofollow_secure).
+///
+/// If the handle refers to a directory, it can also be used as the root for other `*_secure`
+/// operations.
+#[derive(Debug)]
+pub struct PathHandle {
+    file: fs::File,
+}
+
+impl PathHandle {
+    pub(crate) fn from_fd(fd: RawFd) -> Self {
+        Self {
+            file: unsafe { fs::File::from_raw_fd(fd) },
+        }
+    }
+
+    /// Query the metadata of the file (without following symbolic links).
+    pub fn metadata(&self) -> io::Result<fs::Metadata> {
+        self.file.metadata()
+    }
+
+    /// Read the target of the symbolic link that this handle refers to.
+    ///
+    /// This fails with `EINVAL` if the handle does not refer to a symbolic link.
+    pub fn read_link(&self) -> io::Result<PathBuf> {
+        let st = util::fstat(self.file.as_raw_fd())?;
+        if st.st_mode & libc::S_IFMT != libc::S_IFLNK {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        // An empty path makes readlinkat() operate on the file descriptor itself
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+
+        loop {
+            let n = unsafe {
+                libc::readlinkat(
+                    self.file.as_raw_fd(),
+                    b"\0".as_ptr() as *const libc::c_char,
+                    buf.as_mut_ptr() as *mut libc::c_char,
+                    buf.len(),
+                )
+            };
+
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            } else if (n as usize) < buf.len() {
+                buf.truncate(n as usize);
+                return Ok(PathBuf::from(OsString::from_vec(buf)));
+            }
+
+            // The target may have been truncated
+            buf.resize(buf.len() * 2, 0);
+        }
+    }
+
+    /// Get the value of an extended attribute of the file.
+    ///
+    /// Extended attributes can't be accessed through an `O_PATH` file descriptor, so this calls
+    /// `getxattr()` on the file's magic link in procfs (`fd/N`, in the `fd` directory opened
+    /// through [`ProcfsHandle::shared()`]). This doesn't need read permission on the file, and
+    /// works for any type of file; for a symbolic link, the attributes of the link itself are
+    /// returned.
+    ///
+    /// [`ProcfsHandle::shared()`]: ./struct.ProcfsHandle.html#method.shared
+    pub fn get_xattr<N: AsRef<OsStr>>(&self, name: N) -> io::Result<Vec<u8>> {
+        let name = CString::new(name.as_ref().as_bytes())?;
+        let (_fd_dir, path) = self.procfs_path()?;
+
+        read_sized(|buf, size| unsafe {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buf as *mut libc::c_void, size)
+        })
+    }
+
+    /// List the names of the extended attributes of the file.
+    ///
+    /// See [`get_xattr()`](#method.get_xattr) for how the file is accessed.
+    pub fn list_xattr(&self) -> io::Result<Vec<OsString>> {
+        let (_fd_dir, path) = self.procfs_path()?;
+
+        let buf = read_sized(|buf, size| unsafe {
+            libc::listxattr(path.as_ptr(), buf as *mut libc::c_char, size)
+        })?;
+
+        Ok(buf
+            .split(|&c| c == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| OsStr::from_bytes(name).to_os_string())
+            .collect())
+    }
+
+    /// Get a path that refers to this file through its magic link in procfs, along with the
+    /// `fd` directory that the path goes through (which must be kept open while it is used).
+    ///
+    /// The `*xattr()` functions only take paths, so the directory (which was opened through the
+    /// hardened procfs handle) is named by its own `/proc/self/fd` entry. Following the magic
+    /// link (rather than using the `l*xattr()` functions) is what makes this refer to the file,
+    /// even if it is a symbolic link.
+    fn procfs_path(&self) -> io::Result<(fs::File, CString)> {
+        let fd_dir = ProcfsHandle::shared()?.open(
+            ProcfsBase::ProcThreadSelf,
+            "fd",
+            libc::O_PATH | libc::O_DIRECTORY,
+        )?;
+
+        let path = CString::new(format!(
+            "/proc/self/fd/{}/{}",
+            fd_dir.as_raw_fd(),
+            self.file.as_raw_fd()
+        ))?;
+        Ok((fd_dir, path))
+    }
+
+    /// Open the file again with the given flags (for example, `libc::O_RDONLY`), without
+    /// resolving its path again.
+    ///
+    /// This is the same as [`reopen(self, flags)`](fn.reopen.html).
+    pub fn reopen(&self, flags: libc::c_int) -> Result<fs::File, Error> {
+        crate::reopen(self, flags)
+    }
+
+    /// Convert this handle into a `Dir`.
+    ///
+    /// This does not check that the handle refers to a directory; if it does not, operations on
+    /// the `Dir` will fail with `ENOTDIR`.
+    pub fn into_dir(self) -> openat::Dir {
+        unsafe { openat::Dir::from_raw_fd(self.file.into_raw_fd()) }
+    }
+}
+
+/// Call `f` (which is `getxattr()`, `listxattr()`, or similar) to fill a buffer, growing the
+/// buffer as necessary.
+pub(crate) fn read_sized<F>(mut f: F) -> io::Result<Vec<u8>>
+where
+    F: FnMut(*mut u8, usize) -> libc::ssize_t,
+{
+    loop {
+        // Get the current size
+        let size = f(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0; size as usize];
+        let n = f(buf.as_mut_ptr(), buf.len());
+
+        if n >= 0 {
+            buf.truncate(n as usize);
+            return Ok(buf);
+        }
+
+        let err = io::Error::last_os_error();
+        // It grew in between the two calls
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return Err(err);
+        }
+    }
+}
+
+impl AsRawFd for PathHandle {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+impl AsFd for PathHandle {
+    #[inline]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl IntoRawFd for PathHandle {
+    #[inline]
+    fn into_raw_fd(self) -> RawFd {
+        self.file.into_raw_fd()
+    }
+}
+
+impl From<PathHandle> for OwnedFd {
+    #[inline]
+    fn from(handle: PathHandle) -> Self {
+        handle.file.into()
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::VecDeque;
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use crate::{backend, openat2, util};
+
+// These are correct for every architecture except alpha, which Rust does not support
+const SYS_OPEN_TREE: libc::c_long = 428;
+const SYS_FSOPEN: libc::c_long = 430;
+const SYS_FSCONFIG: libc::c_long = 431;
+const SYS_FSMOUNT: libc::c_long = 432;
+
+const OPEN_TREE_CLONE: libc::c_uint = 1;
+const FSOPEN_CLOEXEC: libc::c_uint = 1;
+const FSCONFIG_CMD_CREATE: libc::c_uint = 6;
+const FSMOUNT_CLOEXEC: libc::c_uint = 1;
+const MOUNT_ATTR_NOSUID: libc::c_uint = 0x2;
+const MOUNT_ATTR_NODEV: libc::c_uint = 0x4;
+const MOUNT_ATTR_NOEXEC: libc::c_uint = 0x8;
+
+/// The inode number of the root directory of every procfs instance.
+const PROC_ROOT_INO: libc::ino_t = 1;
+
+/// The maximum number of (non-magic) symbolic links followed by the manual resolver.
+const MAX_SYMLINKS: usize = 8;
+
+/// Set once `fsopen()` and `open_tree()` have both failed, so we don't keep retrying them.
+static NEW_MOUNT_API_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// The handle returned by `ProcfsHandle::shared()`.
+static SHARED_HANDLE: OnceLock<ProcfsHandle> = OnceLock::new();
+
+/// The directory that a path passed to [`ProcfsHandle`](struct.ProcfsHandle.html) is relative to.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ProcfsBase {
+    /// The root of procfs (`/proc`).
+    ProcRoot,
+    /// The directory of the current process (`/proc/self`).
+    ProcSelf,
+    /// The directory of the current thread (`/proc/thread-self`, or `/proc/self/task/<tid>` on
+    /// kernels that don't have it).
+    ProcThreadSelf,
+}
+
+/// A handle to a procfs instance that has been checked to be genuine.
+///
+/// If possible, this is a new, private procfs mount created with `fsopen()` (or a non-recursive
+/// clone of `/proc` created with `open_tree()`), which nothing can be mounted over. Otherwise, it
+/// is `/proc`, after checking that it is the root of a procfs mount.
+///
+/// Paths opened through the handle are resolved with `RESOLVE_NO_XDEV | RESOLVE_NO_MAGICLINKS`
+/// (or an equivalent manual resolver if `openat2()` is unavailable), so a malicious mount on top
+/// of part of `/proc` is detected, and "magic links" (like `/proc/self/fd/N`) are only followed as
+/// the final component by [`open_follow()`](#method.open_follow).
+#[derive(Debug)]
+pub struct ProcfsHandle {
+    root: fs::File,
+    dev: libc::dev_t,
+}
+
+impl ProcfsHandle {
+    /// Get a handle to procfs that is shared by the whole process, opening it (as if by
+    /// [`new()`](#method.new)) the first time this is called.
+    ///
+    /// Opening a handle may create a new procfs mount, so this should be preferred over `new()`
+    /// unless a separate handle is really needed. If opening the handle fails, the error is
+    /// returned and the next call tries again.
+    pub fn shared() -> io::Result<&'static Self> {
+        if let Some(handle) = SHARED_HANDLE.get() {
+            return Ok(handle);
+        }
+
+        // If several threads get here at once, only one of the handles is kept
+        let handle = Self::new()?;
+        Ok(SHARED_HANDLE.get_or_init(|| handle))
+    }
+
+    /// Open a new handle to procfs.
+    ///
+    /// This fails with `EXDEV` if `/proc` is not the root of a procfs mount (and a private
+    /// instance could not be created).
+    pub fn new() -> io::Result<Self> {
+        if !NEW_MOUNT_API_FAILED.load(Ordering::Relaxed) {
+            match new_procfs_mount().or_else(|_| clone_proc_mount()) {
+                Ok(root) => {
+                    if let Ok(handle) = Self::from_root(root) {
+                        return Ok(handle);
+                    }
+                }
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS) | Some(libc::EPERM)) => {
+                    NEW_MOUNT_API_FAILED.store(true, Ordering::Relaxed)
+                }
+                Err(_) => (),
+            }
+        }
+
+        let path = unsafe { CStr::from_bytes_with_nul_unchecked(b"/proc\0") };
+        Self::from_root(open_raw(
+            libc::AT_FDCWD,
+            path,
+            libc::O_PATH | libc::O_DIRECTORY | libc::O_NOFOLLOW,
+        )?)
+    }
+
+    fn from_root(root: fs::File) -> io::Result<Self> {
+        let mut stfs: libc::statfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatfs(root.as_raw_fd(), &mut stfs) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let st = util::fstat(root.as_raw_fd())?;
+
+        // It must be the root of a procfs mount (the types of f_type and PROC_SUPER_MAGIC differ
+        // between platforms)
+        #[allow(clippy::unnecessary_cast)]
+        let is_proc = stfs.f_type as i64 == libc::PROC_SUPER_MAGIC as i64;
+        if !is_proc || st.st_ino != PROC_ROOT_INO {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        Ok(Self {
+            root,
+            dev: st.st_dev,
+        })
+    }
+
+    /// Open a path (for example, `fdinfo/3`) relative to `base` with the given flags.
+    ///
+    /// Magic links are never followed, and the path may not contain `..` components or cross
+    /// into another mount. Ordinary symbolic links (like `/proc/self`) are followed unless
+    /// `O_NOFOLLOW` is passed (in which case only the final component is left unfollowed).
+    pub fn open<P: AsRef<Path>>(
+        &self,
+        base: ProcfsBase,
+        path: P,
+        flags: libc::c_int,
+    ) -> io::Result<fs::File> {
+        self.open_base(base, path.as_ref(), flags, false)
+    }
+
+    /// Like [`open()`](#method.open), but if the final component is a magic link (for example,
+    /// `fd/3`) it is followed.
+    ///
+    /// The file that is opened is not checked in any way; callers should verify that it is the
+    /// file they expected.
+    pub fn open_follow<P: AsRef<Path>>(
+        &self,
+        base: ProcfsBase,
+        path: P,
+        flags: libc::c_int,
+    ) -> io::Result<fs::File> {
+        self.open_base(base, path.as_ref(), flags, true)
+    }
+
+    /// Read the target of a (possibly magic) symbolic link relative to `base`.
+    ///
+    /// For example, reading `fd/3` relative to `ProcfsBase::ProcSelf` gives the path that file
+    /// descriptor 3 was opened with.
+    pub fn read_link<P: AsRef<Path>>(&self, base: ProcfsBase, path: P) -> io::Result<PathBuf> {
+        let link = self.open(base, path, libc::O_PATH | libc::O_NOFOLLOW)?;
+        util::borrow_dir(&link).read_link("")
+    }
+
+    fn open_base(
+        &self,
+        base: ProcfsBase,
+        path: &Path,
+        flags: libc::c_int,
+        follow_final: bool,
+    ) -> io::Result<fs::File> {
+        let base_path = match base {
+            ProcfsBase::ProcRoot => PathBuf::new(),
+            ProcfsBase::ProcSelf => PathBuf::from("self"),
+            ProcfsBase::ProcThreadSelf => {
+                match self.resolve(Path::new("thread-self"), libc::O_PATH, false) {
+                    Ok(_) => PathBuf::from("thread-self"),
+                    // Linux < 3.17
+                    Err(e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                        let tid = unsafe { libc::syscall(libc::SYS_gettid) };
+                        PathBuf::from(format!("self/task/{}", tid))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+
+        if path.is_absolute() {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        } else if path.components().any(|c| c == Component::ParentDir) {
+            return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        }
+
+        // With a trailing "/" or "/.", the final component is really a directory lookup in it, so
+        // magic links can't be followed
+        let bytes = path.as_os_str().as_bytes();
+        let follow_final = follow_final && !(bytes.ends_with(b"/") || bytes.ends_with(b"/."));
+
+        self.resolve(&base_path.join(path), flags, follow_final)
+    }
+
+    fn resolve(&self, path: &Path, flags: libc::c_int, follow_final: bool) -> io::Result<fs::File> {
+        let resolve_flags = openat2::ResolveFlags::NO_XDEV
+            | openat2::ResolveFlags::NO_MAGICLINKS
+            | openat2::ResolveFlags::BENEATH;
+
+        if backend::openat2_usable(resolve_flags) {
+            match self.resolve_openat2(path, flags, follow_final, resolve_flags) {
+                Ok(file) => {
+                    backend::openat2_succeeded();
+                    return Ok(file);
+                }
+                Err(e) => {
+                    if !backend::openat2_failed(resolve_flags, &e) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        self.resolve_fallback(path, flags, follow_final)
+    }
+
+    fn resolve_openat2(
+        &self,
+        path: &Path,
+        flags: libc::c_int,
+        follow_final: bool,
+        resolve_flags: openat2::ResolveFlags,
+    ) -> io::Result<fs::File> {
+        let openat2 = |dir: &fs::File, path: &Path, flags| {
+            let mut how = openat2::OpenHow::new(flags);
+            how.resolve_flags = resolve_flags;
+            openat2::openat2(Some(dir.as_raw_fd()), path, &how)
+                .map(|fd| unsafe { fs::File::from_raw_fd(fd) })
+        };
+
+        if !follow_final {
+            return openat2(&self.root, path, flags);
+        }
+
+        let (parent, name) = split_final(path)?;
+        let parent = openat2(&self.root, parent, libc::O_PATH | libc::O_DIRECTORY)?;
+        // Make sure nothing is mounted on top of the final component
+        openat2(
+            &parent,
+            Path::new(OsStr::from_bytes(name.as_bytes())),
+            libc::O_PATH | libc::O_NOFOLLOW,
+        )?;
+
+        open_raw(parent.as_raw_fd(), &name, flags)
+    }
+
+    fn resolve_fallback(
+        &self,
+        path: &Path,
+        flags: libc::c_int,
+        follow_final: bool,
+    ) -> io::Result<fs::File> {
+        let mut components = VecDeque::new();
+        push_components(&mut components, path)?;
+
+        let dot = unsafe { CStr::from_bytes_with_nul_unchecked(b".\0") };
+        let mut cur = open_raw(self.root.as_raw_fd(), dot, libc::O_PATH | libc::O_DIRECTORY)?;
+        let mut nlinks = 0;
+
+        while let Some(name) = components.pop_front() {
+            let name = CString::new(name.into_vec())?;
+            let last = components.is_empty();
+
+            let file = open_raw(cur.as_raw_fd(), &name, libc::O_PATH | libc::O_NOFOLLOW)?;
+            let st = util::fstat(file.as_raw_fd())?;
+            if st.st_dev != self.dev {
+                return Err(io::Error::from_raw_os_error(libc::EXDEV));
+            }
+
+            if last && follow_final {
+                return open_raw(cur.as_raw_fd(), &name, flags);
+            }
+
+            if st.st_mode & libc::S_IFMT == libc::S_IFLNK
+                && !(last && flags & libc::O_NOFOLLOW != 0)
+            {
+                nlinks += 1;
+                if nlinks > MAX_SYMLINKS {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+
+                // Magic links usually have absolute targets (or ones like "pipe:[1234]", which
+                // won't exist); either way, the device check above keeps us inside procfs.
+                let target = util::borrow_dir(&cur).read_link(name.as_c_str())?;
+                if target.is_absolute() {
+                    return Err(io::Error::from_raw_os_error(libc::ELOOP));
+                }
+
+                let mut target_components = VecDeque::new();
+                push_components(&mut target_components, &target)?;
+                target_components.extend(components);
+                components = target_components;
+                continue;
+            }
+
+            if last {
+                let file = open_raw(cur.as_raw_fd(), &name, flags | libc::O_NOFOLLOW)?;
+                if !util::same_stat(&st, &util::fstat(file.as_raw_fd())?) {
+                    return Err(io::Error::from_raw_os_error(libc::EAGAIN));
+                }
+                return Ok(file);
+            }
+
+            cur = file;
+        }
+
+        // An empty path refers to the root itself
+        open_raw(cur.as_raw_fd(), dot, flags)
+    }
+}
+
+impl AsRawFd for ProcfsHandle {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.root.as_raw_fd()
+    }
+}
+
+/// Add the components of `path` (which must be relative and must not contain `..`) to `queue`.
+fn push_components(queue: &mut VecDeque<OsString>, path: &Path) -> io::Result<()> {
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => queue.push_back(name.to_os_string()),
+            Component::CurDir => (),
+            _ => return Err(io::Error::from_raw_os_error(libc::EXDEV)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Split `path` into its parent and final component (which must be a normal file name).
+fn split_final(path: &Path) -> io::Result<(&Path, CString)> {
+    match path.components().next_back() {
+        Some(Component::Normal(name)) => Ok((
+            match path.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            },
+            CString::new(name.as_bytes())?,
+        )),
+        _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+    }
+}
+
+fn open_raw(dirfd: RawFd, path: &CStr, flags: libc::c_int) -> io::Result<fs::File> {
+    let fd = unsafe { libc::openat(dirfd, path.as_ptr(), flags | libc::O_CLOEXEC) };
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+}
+
+fn check_fd(res: libc::c_long) -> io::Result<fs::File> {
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { fs::File::from_raw_fd(res as RawFd) })
+    }
+}
+
+/// Create a new, detached procfs mount with `fsopen()`.
+fn new_procfs_mount() -> io::Result<fs::File> {
+    let fs_ctx = check_fd(unsafe {
+        libc::syscall(
+            SYS_FSOPEN,
+            b"proc\0".as_ptr() as *const libc::c_char,
+            FSOPEN_CLOEXEC,
+        )
+    })?;
+
+    if unsafe {
+        libc::syscall(
+            SYS_FSCONFIG,
+            fs_ctx.as_raw_fd(),
+            FSCONFIG_CMD_CREATE,
+            std::ptr::null::<libc::c_char>(),
+            std::ptr::null::<libc::c_void>(),
+            0,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+
+    check_fd(unsafe {
+        libc::syscall(
+            SYS_FSMOUNT,
+            fs_ctx.as_raw_fd(),
+            FSMOUNT_CLOEXEC,
+            MOUNT_ATTR_NOSUID | MOUNT_ATTR_NODEV | MOUNT_ATTR_NOEXEC,
+        )
+    })
+}
+
+/// Create a detached clone of the mount on `/proc` (without any mounts on top of parts of it)
+/// with `open_tree()`.
+fn clone_proc_mount() -> io::Result<fs::File> {
+    check_fd(unsafe {
+        libc::syscall(
+            SYS_OPEN_TREE,
+            libc::AT_FDCWD,
+            b"/proc\0".as_ptr() as *const libc::c_char,
+            OPEN_TREE_CLONE | libc::O_CLOEXEC as libc::c_uint,
+        )
+    })
+}
@@ -0,0 +1,127 @@
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+
+use crate::{util, Error, ErrorKind};
+#[cfg(target_os = "linux")]
+use crate::{ProcfsBase, ProcfsHandle};
+
+/// Open the file that `handle` refers to again, with the given flags (for example,
+/// `libc::O_RDWR`), without resolving it by name relative to the directory it was found in.
+///
+/// This is mainly useful to "upgrade" a handle obtained from the resolver that does not allow
+/// reading or writing (such as the `O_PATH` file descriptors returned by
+/// [`DirSecureExt::sub_dir_secure()`] and [`DirSecureExt::open_path_secure()`] on Linux).
+///
+/// On Linux, this opens `/proc/thread-self/fd/N` (through [`ProcfsHandle::shared()`]).
+/// Otherwise, or if procfs is unavailable, directories are reopened with `openat(fd, ".")`; on
+/// macOS, regular files are reopened by the path returned by `fcntl(F_GETPATH)` (and only
+/// truncated, if `O_TRUNC` is passed, after checking that the right file was opened). In all other
+/// cases this fails with `ENOTSUP`.
+///
+/// In every case, the reopened file is checked against `handle` (by device and inode number), and
+/// if they don't match this fails with `EAGAIN` ([`ErrorKind::Race`]).
+///
+/// `flags` should not include `O_CREAT`.
+///
+/// [`DirSecureExt::sub_dir_secure()`]: ./trait.DirSecureExt.html#method.sub_dir_secure
+/// [`DirSecureExt::open_path_secure()`]: ./trait.DirSecureExt.html#method.open_path_secure
+/// [`ErrorKind::Race`]: ./enum.ErrorKind.html#variant.Race
+/// [`ProcfsHandle::shared()`]: ./struct.ProcfsHandle.html#method.shared
+pub fn reopen<F: AsRawFd + ?Sized>(handle: &F, flags: libc::c_int) -> Result<fs::File, Error> {
+    let fd = handle.as_raw_fd();
+    let st = util::fstat(fd).map_err(|e| Error::from(e).with_operation("reopen"))?;
+
+    reopen_unverified(fd, &st, flags)
+        .and_then(|file| {
+            if util::same_stat(&st, &util::fstat(file.as_raw_fd())?) {
+                Ok(file)
+            } else {
+                Err(Error::from_raw_os_error(ErrorKind::Race, libc::EAGAIN))
+            }
+        })
+        .map_err(|e| e.with_operation("reopen"))
+}
+
+fn reopen_unverified(fd: RawFd, st: &libc::stat, flags: libc::c_int) -> Result<fs::File, Error> {
+    #[cfg(target_os = "linux")]
+    if let Some(file) = reopen_procfs(fd, flags)? {
+        return Ok(file);
+    }
+
+    if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+        let dot = unsafe { CStr::from_bytes_with_nul_unchecked(b".\0") };
+        return Ok(open_at(fd, dot, flags | libc::O_DIRECTORY)?);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        // Opening a FIFO or a device by name could have side effects on whatever file has been
+        // put in its place by the time it is opened
+        if st.st_mode & libc::S_IFMT != libc::S_IFREG {
+            return Err(io::Error::from_raw_os_error(libc::ENOTSUP).into());
+        }
+
+        // Look up the current path of the file and open it (without following symlinks)
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        if unsafe { libc::fcntl(fd, libc::F_GETPATH, buf.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let path = CStr::from_bytes_until_nul(&buf)
+            .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        // Only truncate once it's known to be the right file
+        let file = open_at(
+            libc::AT_FDCWD,
+            path,
+            (flags & !libc::O_TRUNC) | libc::O_NOFOLLOW,
+        )?;
+        if !util::same_stat(st, &util::fstat(file.as_raw_fd())?) {
+            return Err(Error::from_raw_os_error(ErrorKind::Race, libc::EAGAIN));
+        }
+        if flags & libc::O_TRUNC != 0 {
+            file.set_len(0)?;
+        }
+        return Ok(file);
+    }
+
+    #[allow(unreachable_code)]
+    Err(io::Error::from_raw_os_error(libc::ENOTSUP).into())
+}
+
+fn open_at(dirfd: RawFd, path: &CStr, flags: libc::c_int) -> io::Result<fs::File> {
+    let fd = unsafe { libc::openat(dirfd, path.as_ptr(), flags | libc::O_CLOEXEC) };
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+}
+
+/// Reopen the file through `/proc/thread-self/fd`, returning `None` if procfs is unavailable (or
+/// is not genuine).
+#[cfg(target_os = "linux")]
+fn reopen_procfs(fd: RawFd, flags: libc::c_int) -> io::Result<Option<fs::File>> {
+    // Any failure to reach the fd directory (no procfs, a fake one, hidepid, an LSM denial, etc.)
+    // just means that procfs can't be used
+    let procfs = match ProcfsHandle::shared() {
+        Ok(procfs) => procfs,
+        Err(_) => return Ok(None),
+    };
+    if procfs
+        .open(
+            ProcfsBase::ProcThreadSelf,
+            "fd",
+            libc::O_PATH | libc::O_DIRECTORY,
+        )
+        .is_err()
+    {
+        return Ok(None);
+    }
+
+    procfs
+        .open_follow(ProcfsBase::ProcThreadSelf, format!("fd/{}", fd), flags)
+        .map(Some)
+}
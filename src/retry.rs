@@ -0,0 +1,205 @@
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::{Error, ErrorKind};
+
+static MAX_ATTEMPTS: AtomicU32 = AtomicU32::new(RetryPolicy::DEFAULT.max_attempts);
+static INITIAL_BACKOFF_NS: AtomicU64 =
+    AtomicU64::new(RetryPolicy::DEFAULT.initial_backoff.as_nanos() as u64);
+static MAX_BACKOFF_NS: AtomicU64 =
+    AtomicU64::new(RetryPolicy::DEFAULT.max_backoff.as_nanos() as u64);
+
+static RETRY_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Controls how path resolution is retried when a race condition is detected.
+///
+/// Path resolution fails with `EAGAIN` ([`ErrorKind::Race`]) if a concurrent modification of the
+/// directory tree is detected (for example, a rename or mount racing with a lookup on Linux). By
+/// default, such failures are retried a few times before being returned to the caller.
+///
+/// The policy is process-wide; see [`set_retry_policy()`]. It applies to the path resolution
+/// performed by every `*_secure` operation.
+///
+/// [`ErrorKind::Race`]: ./enum.ErrorKind.html#variant.Race
+/// [`set_retry_policy()`]: ./fn.set_retry_policy.html
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of times the resolution will be attempted (including the first
+    /// attempt). `0` is treated the same as `1`, i.e. never retry.
+    pub max_attempts: u32,
+    /// How long to sleep before the first retry. The delay doubles after each retry, up to
+    /// `max_backoff`.
+    ///
+    /// If this is zero, the thread yields before retrying instead of sleeping.
+    pub initial_backoff: Duration,
+    /// The maximum delay between retries.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    const DEFAULT: Self = Self {
+        max_attempts: 4,
+        initial_backoff: Duration::from_micros(10),
+        max_backoff: Duration::from_millis(1),
+    };
+
+    /// A policy that never retries.
+    pub const NEVER: Self = Self {
+        max_attempts: 1,
+        initial_backoff: Duration::from_secs(0),
+        max_backoff: Duration::from_secs(0),
+    };
+
+    /// Create a policy that makes at most `max_attempts` attempts, yielding (but not sleeping)
+    /// between them.
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts,
+            initial_backoff: Duration::from_secs(0),
+            max_backoff: Duration::from_secs(0),
+        }
+    }
+
+    /// Set the initial and maximum delays between retries.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Set the process-wide [`RetryPolicy`](struct.RetryPolicy.html).
+///
+/// Operations that are already in progress may continue to use the old policy.
+pub fn set_retry_policy(policy: RetryPolicy) {
+    MAX_ATTEMPTS.store(policy.max_attempts, Ordering::Relaxed);
+    INITIAL_BACKOFF_NS.store(duration_to_ns(policy.initial_backoff), Ordering::Relaxed);
+    MAX_BACKOFF_NS.store(duration_to_ns(policy.max_backoff), Ordering::Relaxed);
+}
+
+/// Get the current process-wide [`RetryPolicy`](struct.RetryPolicy.html).
+pub fn retry_policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: MAX_ATTEMPTS.load(Ordering::Relaxed),
+        initial_backoff: Duration::from_nanos(INITIAL_BACKOFF_NS.load(Ordering::Relaxed)),
+        max_backoff: Duration::from_nanos(MAX_BACKOFF_NS.load(Ordering::Relaxed)),
+    }
+}
+
+/// Get the total number of times path resolution has been retried (in this process) because of a
+/// race condition.
+///
+/// The number of retries performed for a failed operation is also available from
+/// [`Error::retries()`](struct.Error.html#method.retries).
+pub fn retry_count() -> u64 {
+    RETRY_COUNT.load(Ordering::Relaxed)
+}
+
+fn duration_to_ns(d: Duration) -> u64 {
+    u64::try_from(d.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Call `f` (which performs a path resolution), retrying according to the current policy if it
+/// fails because of a race condition.
+pub fn retry<T, F>(f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    retry_with(retry_policy(), f)
+}
+
+fn retry_with<T, F>(policy: RetryPolicy, mut f: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let mut backoff = policy.initial_backoff;
+    let mut retries = 0;
+
+    loop {
+        match f() {
+            Err(e) if e.kind() == ErrorKind::Race && retries + 1 < policy.max_attempts => {
+                RETRY_COUNT.fetch_add(1, Ordering::Relaxed);
+                retries += 1;
+
+                if backoff.is_zero() {
+                    std::thread::yield_now();
+                } else {
+                    std::thread::sleep(backoff);
+                    backoff = std::cmp::min(backoff * 2, policy.max_backoff);
+                }
+            }
+
+            res => return res.map_err(|e| e.with_retries(retries)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn race() -> Error {
+        Error::from_raw_os_error(ErrorKind::Race, libc::EAGAIN)
+    }
+
+    #[test]
+    fn test_retry_with() {
+        let mut calls = 0;
+        let res = retry_with(RetryPolicy::new(3), || {
+            calls += 1;
+            if calls < 3 {
+                Err(race())
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(res.unwrap(), 3);
+
+        let mut calls = 0;
+        let err = retry_with(
+            RetryPolicy::new(3).backoff(Duration::from_micros(1), Duration::from_micros(2)),
+            || -> Result<(), Error> {
+                calls += 1;
+                Err(race())
+            },
+        )
+        .unwrap_err();
+        assert_eq!(calls, 3);
+        assert_eq!(err.retries(), 2);
+        assert_eq!(err.raw_os_error(), Some(libc::EAGAIN));
+
+        // Other errors are never retried
+        let mut calls = 0;
+        let err = retry_with(RetryPolicy::new(3), || -> Result<(), Error> {
+            calls += 1;
+            Err(std::io::Error::from_raw_os_error(libc::ENOENT).into())
+        })
+        .unwrap_err();
+        assert_eq!(calls, 1);
+        assert_eq!(err.retries(), 0);
+
+        for &policy in &[RetryPolicy::NEVER, RetryPolicy::new(0)] {
+            let mut calls = 0;
+            retry_with(policy, || -> Result<(), Error> {
+                calls += 1;
+                Err(race())
+            })
+            .unwrap_err();
+            assert_eq!(calls, 1);
+        }
+    }
+
+    #[test]
+    fn test_retry_count() {
+        let before = retry_count();
+        retry_with(RetryPolicy::new(2), || -> Result<(), Error> { Err(race()) }).unwrap_err();
+        assert!(retry_count() > before);
+    }
+}
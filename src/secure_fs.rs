@@ -0,0 +1,411 @@
+//! Free functions mirroring the API of `std::fs`, rooted at a directory.
+//!
+//! Each function takes the root directory as its first argument, followed by the same arguments
+//! as its `std::fs` counterpart. Paths are resolved with the `*_secure` methods of
+//! [`DirSecureExt`] (with empty [`LookupFlags`]), so `/`, `..`, and symlinks cannot be used to
+//! escape the root directory.
+//!
+//! Like their `std::fs` counterparts, these functions return plain `io::Error`s (the extra
+//! information carried by [`Error`] is discarded).
+//!
+//! [`Error`]: ../struct.Error.html
+//! [`DirSecureExt`]: ../trait.DirSecureExt.html
+//! [`LookupFlags`]: ../struct.LookupFlags.html
+
+use std::ffi::{CString, OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+
+use openat::{Dir, SimpleType};
+
+use crate::{DirSecureExt, Error, LookupFlags};
+
+/// Read the entire contents of a file into a bytes vector.
+pub fn read<D: DirSecureExt + ?Sized, P: AsRef<Path>>(root: &D, path: P) -> io::Result<Vec<u8>> {
+    let mut file = root
+        .open_file_secure(path, LookupFlags::empty())
+        .map_err(Error::into_io_error)?;
+
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Read the entire contents of a file into a string.
+pub fn read_to_string<D: DirSecureExt + ?Sized, P: AsRef<Path>>(
+    root: &D,
+    path: P,
+) -> io::Result<String> {
+    let mut file = root
+        .open_file_secure(path, LookupFlags::empty())
+        .map_err(Error::into_io_error)?;
+
+    let mut buf = String::new();
+    file.read_to_string(&mut buf)?;
+    Ok(buf)
+}
+
+/// Write a slice as the entire contents of a file, creating it if it does not exist and
+/// truncating it if it does.
+pub fn write<D: DirSecureExt + ?Sized, P: AsRef<Path>, C: AsRef<[u8]>>(
+    root: &D,
+    path: P,
+    contents: C,
+) -> io::Result<()> {
+    root.write_file_secure(path, 0o666, LookupFlags::empty())
+        .map_err(Error::into_io_error)?
+        .write_all(contents.as_ref())
+}
+
+/// Copy the contents of one file to another, then copy the permission bits of the original file
+/// to the destination file.
+///
+/// Returns the number of bytes copied.
+pub fn copy<D: DirSecureExt + ?Sized, P: AsRef<Path>, Q: AsRef<Path>>(
+    root: &D,
+    from: P,
+    to: Q,
+) -> io::Result<u64> {
+    let mut src = root
+        .open_file_secure(from, LookupFlags::empty())
+        .map_err(Error::into_io_error)?;
+
+    let src_meta = src.metadata()?;
+    if !src_meta.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the source path is not an existing regular file",
+        ));
+    }
+    let perms = src_meta.permissions();
+
+    let mut dst = root
+        .write_file_secure(
+            to,
+            (perms.mode() & 0o7777) as libc::mode_t,
+            LookupFlags::empty(),
+        )
+        .map_err(Error::into_io_error)?;
+    let n = io::copy(&mut src, &mut dst)?;
+    dst.set_permissions(perms)?;
+
+    Ok(n)
+}
+
+/// Create a new, empty directory.
+pub fn create_dir<D: DirSecureExt + ?Sized, P: AsRef<Path>>(root: &D, path: P) -> io::Result<()> {
+    root.create_dir_secure(path, 0o777, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Recursively create a directory and all of its parent components if they are missing.
+pub fn create_dir_all<D: DirSecureExt + ?Sized, P: AsRef<Path>>(
+    root: &D,
+    path: P,
+) -> io::Result<()> {
+    let path = path.as_ref();
+
+    match root
+        .create_dir_secure(path, 0o777, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+    {
+        Ok(()) => return Ok(()),
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => (),
+        Err(_) if is_dir(root, path) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => create_dir_all(root, parent)?,
+        _ => return Err(io::Error::from_raw_os_error(libc::ENOENT)),
+    }
+
+    match root
+        .create_dir_secure(path, 0o777, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+    {
+        Ok(()) => Ok(()),
+        Err(_) if is_dir(root, path) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Remove an empty directory.
+pub fn remove_dir<D: DirSecureExt + ?Sized, P: AsRef<Path>>(root: &D, path: P) -> io::Result<()> {
+    root.remove_dir_secure(path, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Remove a directory after removing all of its contents.
+///
+/// If `path` refers to a symbolic link, the link itself is removed and its target is left
+/// untouched.
+pub fn remove_dir_all<D: DirSecureExt + ?Sized, P: AsRef<Path>>(
+    root: &D,
+    path: P,
+) -> io::Result<()> {
+    let path = path.as_ref();
+
+    let (subdir, fname) = crate::prepare_inner_operation(root, path, LookupFlags::empty())
+        .map_err(Error::into_io_error)?;
+    let root_dir = crate::util::borrow_dir(root);
+    let subdir = subdir.as_ref().unwrap_or(&root_dir);
+
+    let fname = if let Some(fname) = fname {
+        fname
+    } else {
+        // Let remove_dir_secure() figure out the correct error
+        return root
+            .remove_dir_secure(path, LookupFlags::empty())
+            .map_err(Error::into_io_error);
+    };
+
+    // Strip the trailing slashes; otherwise the kernel would follow the final component if it
+    // is a symlink.
+    let mut fname_bytes = fname.as_bytes();
+    let had_slash = fname_bytes.ends_with(b"/");
+    while fname_bytes.ends_with(b"/") {
+        fname_bytes = &fname_bytes[..fname_bytes.len() - 1];
+    }
+    let fname = Path::new(OsStr::from_bytes(fname_bytes));
+
+    match subdir.metadata(fname)?.simple_type() {
+        SimpleType::Dir => (),
+        SimpleType::Symlink if !had_slash => return subdir.remove_file(fname),
+        _ => return Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+    }
+
+    remove_dir_contents(open_for_listing(subdir, fname.as_os_str())?)?;
+    subdir.remove_dir(fname)
+}
+
+fn remove_dir_contents(dir: Dir) -> io::Result<()> {
+    for entry in crate::DirIter::new(dir)? {
+        let entry = entry?;
+        let dir = entry.dir();
+
+        let is_dir = match entry.file_type() {
+            Some(ftype) => ftype == SimpleType::Dir,
+            None => dir.metadata(entry.file_name())?.is_dir(),
+        };
+
+        if is_dir {
+            // This doesn't follow symlinks, so it can't escape
+            remove_dir_contents(open_for_listing(dir, entry.file_name())?)?;
+            dir.remove_dir(entry.file_name())?;
+        } else {
+            dir.remove_file(entry.file_name())?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove a file (or a symbolic link).
+pub fn remove_file<D: DirSecureExt + ?Sized, P: AsRef<Path>>(root: &D, path: P) -> io::Result<()> {
+    root.remove_file_secure(path, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Rename a file or directory, replacing the destination if it exists.
+pub fn rename<D: DirSecureExt + ?Sized, P: AsRef<Path>, Q: AsRef<Path>>(
+    root: &D,
+    from: P,
+    to: Q,
+) -> io::Result<()> {
+    root.local_rename_secure(from, to, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Create a new hard link `link` pointing to `original`.
+pub fn hard_link<D: DirSecureExt + ?Sized, P: AsRef<Path>, Q: AsRef<Path>>(
+    root: &D,
+    original: P,
+    link: Q,
+) -> io::Result<()> {
+    crate::hardlink_secure(root, original, root, link, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Create a new symbolic link `link` whose contents are `original`.
+///
+/// Note that the order of the arguments matches `std::os::unix::fs::symlink()`, which is the
+/// reverse of [`DirSecureExt::symlink_secure()`].
+///
+/// [`DirSecureExt::symlink_secure()`]: ../trait.DirSecureExt.html#tymethod.symlink_secure
+pub fn symlink<D: DirSecureExt + ?Sized, P: AsRef<Path>, Q: AsRef<Path>>(
+    root: &D,
+    original: P,
+    link: Q,
+) -> io::Result<()> {
+    root.symlink_secure(link, original.as_ref(), LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Read the contents of a symbolic link.
+pub fn read_link<D: DirSecureExt + ?Sized, P: AsRef<Path>>(
+    root: &D,
+    path: P,
+) -> io::Result<PathBuf> {
+    root.read_link_secure(path, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Return an iterator over the entries in a directory.
+pub fn read_dir<D: DirSecureExt + ?Sized, P: AsRef<Path>>(
+    root: &D,
+    path: P,
+) -> io::Result<crate::DirIter> {
+    root.list_dir_secure(path, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Query the metadata of a file, following symbolic links.
+pub fn metadata<D: DirSecureExt + ?Sized, P: AsRef<Path>>(
+    root: &D,
+    path: P,
+) -> io::Result<openat::Metadata> {
+    metadata_follow(root, path.as_ref()).map_err(Error::into_io_error)
+}
+
+/// Query the metadata of a file without following the final symbolic link.
+pub fn symlink_metadata<D: DirSecureExt + ?Sized, P: AsRef<Path>>(
+    root: &D,
+    path: P,
+) -> io::Result<openat::Metadata> {
+    root.metadata_secure(path, LookupFlags::empty())
+        .map_err(Error::into_io_error)
+}
+
+/// Return the canonical form of a path, with all `.` and `..` components and symbolic links
+/// resolved.
+///
+/// The returned path is absolute, but relative to `root`; i.e. `/` refers to `root` itself.
+pub fn canonicalize<D: DirSecureExt + ?Sized, P: AsRef<Path>>(
+    root: &D,
+    path: P,
+) -> io::Result<PathBuf> {
+    canonicalize_inner(root, path.as_ref()).map_err(Error::into_io_error)
+}
+
+/// Check whether a path exists (following symbolic links).
+///
+/// Errors (including permission errors) are treated as the path not existing.
+pub fn exists<D: DirSecureExt + ?Sized, P: AsRef<Path>>(root: &D, path: P) -> bool {
+    metadata(root, path).is_ok()
+}
+
+fn is_dir<D: DirSecureExt + ?Sized>(root: &D, path: &Path) -> bool {
+    metadata(root, path).map(|m| m.is_dir()).unwrap_or(false)
+}
+
+#[cfg(target_os = "linux")]
+fn metadata_follow<D: DirSecureExt + ?Sized>(
+    root: &D,
+    path: &Path,
+) -> Result<openat::Metadata, Error> {
+    let file = crate::open_file(
+        root,
+        "metadata",
+        path,
+        LookupFlags::empty(),
+        libc::O_PATH,
+        0,
+    )?;
+    Ok(crate::util::borrow_dir(&file).self_metadata()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn metadata_follow<D: DirSecureExt + ?Sized>(
+    root: &D,
+    path: &Path,
+) -> Result<openat::Metadata, Error> {
+    // Without O_PATH, opening the file could block (e.g. on a FIFO) or fail (if it isn't
+    // readable), so find where the final symlink (if any) leads and stat that instead
+    crate::metadata_nofollow(root, &final_path(root, path)?, LookupFlags::empty())
+        .map_err(|e| e.context("metadata", path))
+}
+
+/// Get the path that `path` refers to after following any symbolic links in the final component.
+fn final_path<D: DirSecureExt + ?Sized>(root: &D, path: &Path) -> Result<PathBuf, Error> {
+    let hops = root.read_link_chain_secure(path, LookupFlags::empty())?;
+
+    Ok(match hops.last() {
+        Some(hop) => crate::link_chain::next_path(hop.path(), hop.target()),
+        None => path.to_path_buf(),
+    })
+}
+
+fn canonicalize_inner<D: DirSecureExt + ?Sized>(root: &D, path: &Path) -> Result<PathBuf, Error> {
+    let final_path = final_path(root, path)?;
+
+    crate::error::with_context("canonicalize", path, || {
+        let root_dir = crate::util::borrow_dir(root);
+        let (subdir, fname) =
+            crate::prepare_inner_operation(root, &final_path, LookupFlags::empty())?;
+        let subdir = match subdir {
+            Some(subdir) => subdir,
+            None => crate::util::dup_dir(&root_dir)?,
+        };
+
+        // Find the directory to walk up from, and the name of the file within it (if it's not a
+        // directory)
+        let (dir, fname) = match fname {
+            Some(fname) if subdir.metadata(fname)?.is_dir() => (subdir.sub_dir(fname)?, None),
+            Some(fname) => (subdir, Some(fname)),
+            None => (subdir, None),
+        };
+
+        let mut names = Vec::new();
+        let mut cur = dir;
+        while let Some(parent) = cur.parent_within(root)? {
+            names.push(name_in_parent(&parent, &cur)?);
+            cur = parent;
+        }
+
+        let mut canonical = PathBuf::from("/");
+        canonical.extend(names.iter().rev());
+        canonical.extend(fname);
+        Ok(canonical)
+    })
+}
+
+/// Find the name of the directory `child` in its parent directory `parent`.
+fn name_in_parent(parent: &Dir, child: &Dir) -> io::Result<OsString> {
+    let child_st = crate::util::fstat(child.as_raw_fd())?;
+
+    for entry in crate::DirIter::new(open_for_listing(parent, OsStr::new("."))?)? {
+        let entry = entry?;
+        if entry
+            .file_type()
+            .is_none_or(|ftype| ftype == SimpleType::Dir)
+            && crate::util::same_stat(parent.metadata(entry.file_name())?.stat(), &child_st)
+        {
+            return Ok(entry.file_name().to_os_string());
+        }
+    }
+
+    // It was removed (or moved to a different directory) while we were looking
+    Err(io::Error::from_raw_os_error(libc::ENOENT))
+}
+
+/// Open the directory `name` in `dir` (without following symbolic links) so that it can be
+/// listed.
+fn open_for_listing(dir: &Dir, name: &OsStr) -> io::Result<Dir> {
+    let name = CString::new(name.as_bytes())?;
+
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    };
+
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { Dir::from_raw_fd(fd) })
+    }
+}
@@ -0,0 +1,64 @@
+use std::os::unix::prelude::*;
+use std::path::{Component, Path};
+
+use crate::{open, Error, ErrorKind};
+
+/// Restricts the targets of the symbolic links created by
+/// [`DirSecureExt::symlink_secure_with_policy()`](trait.DirSecureExt.html#method.symlink_secure_with_policy).
+///
+/// The target is checked lexically (without resolving any symbolic links it may contain)
+/// against the location of the directory where the link is being created. The `*_secure`
+/// operations always resolve symbolic links within the root directory; these policies are meant
+/// to keep links safe for consumers that *don't* use this crate.
+#[derive(Copy, Clone, Debug, Default, Eq, Hash, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Allow any target.
+    #[default]
+    Any,
+    /// The target must be a relative path.
+    RelativeOnly,
+    /// The target must be a relative path, and its `..` components must not take it above the
+    /// root directory.
+    WithinRoot,
+    /// The target must be a relative path, and its `..` components must not take it above the
+    /// directory containing the link.
+    WithinParent,
+}
+
+fn rejected() -> Error {
+    Error::from_raw_os_error(ErrorKind::EscapeAttempt, libc::EXDEV)
+}
+
+/// Check whether a symbolic link pointing to `target` may be created in the directory `parent`
+/// (which should be beneath the directory `root`).
+pub(crate) fn check_target<R, D>(
+    root: &R,
+    parent: &D,
+    target: &Path,
+    policy: SymlinkPolicy,
+) -> Result<(), Error>
+where
+    R: AsRawFd + ?Sized,
+    D: AsRawFd + ?Sized,
+{
+    let mut depth = match policy {
+        SymlinkPolicy::Any => return Ok(()),
+        _ if target.has_root() => return Err(rejected()),
+        SymlinkPolicy::RelativeOnly => return Ok(()),
+        // The path that was used to get to `parent` may have contained symlinks, so check how
+        // deep it actually is
+        SymlinkPolicy::WithinRoot => open::depth_beneath(root.as_raw_fd(), parent.as_raw_fd())?,
+        SymlinkPolicy::WithinParent => 0,
+    };
+
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir if depth == 0 => return Err(rejected()),
+            Component::ParentDir => depth -= 1,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,666 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::prelude::*;
+use std::path::{Path, PathBuf};
+
+use io_uring::{opcode, squeue, types, IoUring, Probe};
+use openat::Dir;
+
+use crate::{error, open, openat2, util, DirSecureExt, Error, ErrorKind, LookupFlags};
+
+/// A request to open a file with [`SecureUring::open_many()`].
+///
+/// [`SecureUring::open_many()`]: struct.SecureUring.html#method.open_many
+#[derive(Clone, Debug)]
+pub struct OpenRequest {
+    path: PathBuf,
+    flags: libc::c_int,
+    mode: libc::mode_t,
+    lookup_flags: LookupFlags,
+}
+
+impl OpenRequest {
+    /// Create a new request to open the given path as read-only.
+    pub fn new<P: AsRef<Path>>(path: P, lookup_flags: LookupFlags) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            flags: libc::O_RDONLY,
+            mode: 0,
+            lookup_flags,
+        }
+    }
+
+    /// Set the flags that will be passed to `open()` (for example, `O_WRONLY | O_CREAT`).
+    ///
+    /// `O_CLOEXEC` is always added.
+    pub fn flags(mut self, flags: libc::c_int) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set the mode used if a file is created.
+    pub fn mode(mut self, mode: libc::mode_t) -> Self {
+        self.mode = mode;
+        self
+    }
+}
+
+/// The buffers referenced by a batch of submission queue entries.
+///
+/// These must stay alive until the kernel has finished processing every entry.
+#[derive(Default)]
+struct Batch {
+    paths: Vec<CString>,
+    hows: Vec<types::OpenHow>,
+    dirs: Vec<Dir>,
+    statx_bufs: Vec<libc::statx>,
+    entries: Vec<(usize, squeue::Entry)>,
+}
+
+/// Perform batches of secure operations using `io_uring`.
+///
+/// Each request in a batch is performed as if by the corresponding method of [`DirSecureExt`].
+/// If `io_uring` is unavailable (for example, because the kernel is too old or a seccomp filter
+/// or the `kernel.io_uring_disabled` sysctl blocks it), or if the kernel does not support a
+/// particular operation, the requests are performed synchronously instead.
+///
+/// The requests in a batch must be independent of each other. They are submitted together and
+/// may complete in any order, and for the operations other than `open_many()`, the parent
+/// directory of every path is resolved synchronously before anything is submitted. So (for
+/// example) `create_dir_many(&dir, &["a", "a/b"], ...)` fails with `ENOENT` for `a/b` if `a` did
+/// not already exist; split dependent requests into separate batches.
+///
+/// [`DirSecureExt`]: ./trait.DirSecureExt.html
+pub struct SecureUring {
+    ring: Option<IoUring>,
+    probe: Probe,
+}
+
+impl SecureUring {
+    /// Create a new `io_uring` instance with the given number of submission queue entries.
+    ///
+    /// If `io_uring` is unavailable, this does *not* fail; instead, all operations will be
+    /// performed synchronously.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut probe = Probe::new();
+
+        let ring = match IoUring::new(entries) {
+            Ok(ring) => {
+                ring.submitter().register_probe(&mut probe)?;
+                Some(ring)
+            }
+            Err(e) => match e.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EPERM) | Some(libc::EACCES) => None,
+                _ => return Err(e),
+            },
+        };
+
+        Ok(Self { ring, probe })
+    }
+
+    /// Returns whether `io_uring` is actually being used.
+    pub fn is_available(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    fn supports(&self, code: u8) -> bool {
+        self.ring.is_some() && self.probe.is_supported(code)
+    }
+
+    /// Submit every entry in the batch and wait for them all to complete.
+    ///
+    /// Returns `(index, result)` pairs for every entry that completed. Entries that were not
+    /// completed (because of a submission error) are omitted, and the caller should fall back on
+    /// performing them synchronously.
+    fn run_batch(&mut self, batch: &mut Batch) -> Vec<(usize, i32)> {
+        let mut results = Vec::with_capacity(batch.entries.len());
+
+        let ring = match self.ring.as_mut() {
+            Some(ring) => ring,
+            None => return results,
+        };
+
+        let mut pending = batch.entries.iter();
+        let mut in_flight = 0;
+
+        loop {
+            {
+                let mut sq = ring.submission();
+                while !sq.is_full() {
+                    match pending.next() {
+                        Some((i, entry)) => {
+                            // SAFETY: The buffers referenced by the entry are kept alive in
+                            // `batch`, which is leaked below if we can't wait for them.
+                            unsafe { sq.push(&entry.clone().user_data(*i as u64)) }
+                                .expect("submission queue is full");
+                            in_flight += 1;
+                        }
+                        None => break,
+                    }
+                }
+            }
+
+            if in_flight == 0 {
+                break;
+            }
+
+            match ring.submit_and_wait(1) {
+                Ok(_) => (),
+                Err(e)
+                    if matches!(
+                        e.raw_os_error(),
+                        Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY)
+                    ) => {}
+                Err(_) => {
+                    // We don't know what state the kernel is in, so we can't safely free the
+                    // buffers. Give up on this ring entirely.
+                    self.ring = None;
+                    std::mem::forget(std::mem::take(batch));
+                    return results;
+                }
+            }
+
+            for cqe in ring.completion() {
+                results.push((cqe.user_data() as usize, cqe.result()));
+                in_flight -= 1;
+            }
+        }
+
+        results
+    }
+
+    /// Open several files at once, using `IORING_OP_OPENAT2`.
+    ///
+    /// See [`DirSecureExt::open_file_secure()`] for security information.
+    ///
+    /// [`DirSecureExt::open_file_secure()`]: ./trait.DirSecureExt.html#method.open_file_secure
+    pub fn open_many<D: AsRawFd + ?Sized>(
+        &mut self,
+        dir: &D,
+        requests: &[OpenRequest],
+    ) -> Vec<Result<fs::File, Error>> {
+        let mut results: Vec<Option<Result<fs::File, Error>>> =
+            requests.iter().map(|_| None).collect();
+
+        if self.supports(opcode::OpenAt2::CODE) {
+            let mut batch = Batch::default();
+            let mut indices = Vec::new();
+
+            let mut open_hows = vec![None; requests.len()];
+
+            for (i, req) in requests.iter().enumerate() {
+                // Paranoid lookups need to be checked against the fallback, so they are done
+                // synchronously
+                if req.lookup_flags.contains(LookupFlags::PARANOID) {
+                    continue;
+                }
+
+                let how = match open::openat2_how(req.lookup_flags, req.flags, req.mode) {
+                    Some(how) => {
+                        let raw_how = openat2::RawOpenHow::from(&how);
+                        open_hows[i] = Some(how);
+                        raw_how
+                    }
+                    None => continue,
+                };
+
+                match CString::new(req.path.as_os_str().as_bytes()) {
+                    Ok(path) => {
+                        batch.paths.push(path);
+                        batch.hows.push(
+                            types::OpenHow::new()
+                                .flags(how.flags)
+                                .mode(how.mode)
+                                .resolve(how.resolve),
+                        );
+                        indices.push(i);
+                    }
+                    Err(e) => {
+                        results[i] =
+                            Some(Err(Error::from(io::Error::from(e))
+                                .context("open_file_secure", &req.path)))
+                    }
+                }
+            }
+
+            // `batch.hows` must not be modified after this point
+            for (j, &i) in indices.iter().enumerate() {
+                let entry = opcode::OpenAt2::new(
+                    types::Fd(dir.as_raw_fd()),
+                    batch.paths[j].as_ptr(),
+                    &batch.hows[j],
+                )
+                .build();
+                batch.entries.push((i, entry));
+            }
+
+            for (i, res) in self.run_batch(&mut batch) {
+                if res >= 0 {
+                    results[i] = Some(Ok(unsafe { fs::File::from_raw_fd(res) }));
+                } else {
+                    let err = io::Error::from_raw_os_error(-res);
+                    let how = open_hows[i].as_ref().unwrap();
+                    if !open::openat2_should_fallback(how, &err) {
+                        let req = &requests[i];
+                        let err = open::openat2_error(req.lookup_flags, err);
+
+                        // Races are retried synchronously (according to the retry policy)
+                        if err.kind() != ErrorKind::Race {
+                            results[i] = Some(Err(err.context("open_file_secure", &req.path)));
+                        }
+                    }
+                }
+            }
+        }
+
+        results
+            .into_iter()
+            .zip(requests)
+            .map(|(res, req)| {
+                res.unwrap_or_else(|| {
+                    let fd = open::open_file_secure(
+                        dir,
+                        &req.path,
+                        req.lookup_flags,
+                        req.flags,
+                        req.mode,
+                    )
+                    .map_err(|e| e.context("open_file_secure", &req.path))?;
+                    Ok(unsafe { fs::File::from_raw_fd(fd) })
+                })
+            })
+            .collect()
+    }
+
+    /// Run an operation that takes a (directory, filename) pair on several paths at once.
+    #[allow(clippy::too_many_arguments)]
+    fn run_inner_many<D, P, B, S>(
+        &mut self,
+        dir: &D,
+        paths: &[P],
+        lookup_flags: LookupFlags,
+        op: &'static str,
+        code: u8,
+        build: B,
+        sync: S,
+    ) -> Vec<Result<(), Error>>
+    where
+        D: AsRawFd + ?Sized,
+        P: AsRef<Path>,
+        B: Fn(types::Fd, *const libc::c_char) -> squeue::Entry,
+        S: Fn(&Path) -> Result<(), Error>,
+    {
+        let mut results: Vec<Option<Result<(), Error>>> = paths.iter().map(|_| None).collect();
+
+        if self.supports(code) {
+            let mut batch = Batch::default();
+
+            for (i, path) in paths.iter().enumerate() {
+                let (subdir, fname) =
+                    match crate::prepare_inner_operation(dir, path.as_ref(), lookup_flags) {
+                        Ok((subdir, Some(fname))) => (subdir, fname),
+                        // Let the synchronous implementation figure out the correct error
+                        Ok((_, None)) => continue,
+                        Err(e) => {
+                            results[i] = Some(Err(e.context(op, path.as_ref())));
+                            continue;
+                        }
+                    };
+
+                let fname = match CString::new(fname.as_bytes()) {
+                    Ok(fname) => fname,
+                    Err(e) => {
+                        results[i] = Some(Err(
+                            error::at_final(path.as_ref(), e.into()).context(op, path.as_ref())
+                        ));
+                        continue;
+                    }
+                };
+
+                let dirfd = subdir.as_ref().map_or(dir.as_raw_fd(), |d| d.as_raw_fd());
+                batch
+                    .entries
+                    .push((i, build(types::Fd(dirfd), fname.as_ptr())));
+                batch.paths.push(fname);
+                if let Some(subdir) = subdir {
+                    batch.dirs.push(subdir);
+                }
+            }
+
+            for (i, res) in self.run_batch(&mut batch) {
+                let path = paths[i].as_ref();
+                results[i] = Some(if res >= 0 {
+                    Ok(())
+                } else {
+                    Err(error::at_final(path, io::Error::from_raw_os_error(-res)).context(op, path))
+                });
+            }
+        }
+
+        results
+            .into_iter()
+            .zip(paths)
+            .map(|(res, path)| res.unwrap_or_else(|| sync(path.as_ref())))
+            .collect()
+    }
+
+    /// Create several directories at once, using `IORING_OP_MKDIRAT`.
+    pub fn create_dir_many<D: AsRawFd + ?Sized, P: AsRef<Path>>(
+        &mut self,
+        dir: &D,
+        paths: &[P],
+        mode: libc::mode_t,
+        lookup_flags: LookupFlags,
+    ) -> Vec<Result<(), Error>> {
+        self.run_inner_many(
+            dir,
+            paths,
+            lookup_flags,
+            "create_dir_secure",
+            opcode::MkDirAt::CODE,
+            |dirfd, fname| opcode::MkDirAt::new(dirfd, fname).mode(mode).build(),
+            |path| util::borrow_dir(dir).create_dir_secure(path, mode, lookup_flags),
+        )
+    }
+
+    /// Remove several files at once, using `IORING_OP_UNLINKAT`.
+    pub fn remove_file_many<D: AsRawFd + ?Sized, P: AsRef<Path>>(
+        &mut self,
+        dir: &D,
+        paths: &[P],
+        lookup_flags: LookupFlags,
+    ) -> Vec<Result<(), Error>> {
+        self.run_inner_many(
+            dir,
+            paths,
+            lookup_flags,
+            "remove_file_secure",
+            opcode::UnlinkAt::CODE,
+            |dirfd, fname| opcode::UnlinkAt::new(dirfd, fname).build(),
+            |path| util::borrow_dir(dir).remove_file_secure(path, lookup_flags),
+        )
+    }
+
+    /// Remove several empty directories at once, using `IORING_OP_UNLINKAT`.
+    pub fn remove_dir_many<D: AsRawFd + ?Sized, P: AsRef<Path>>(
+        &mut self,
+        dir: &D,
+        paths: &[P],
+        lookup_flags: LookupFlags,
+    ) -> Vec<Result<(), Error>> {
+        self.run_inner_many(
+            dir,
+            paths,
+            lookup_flags,
+            "remove_dir_secure",
+            opcode::UnlinkAt::CODE,
+            |dirfd, fname| {
+                opcode::UnlinkAt::new(dirfd, fname)
+                    .flags(libc::AT_REMOVEDIR)
+                    .build()
+            },
+            |path| util::borrow_dir(dir).remove_dir_secure(path, lookup_flags),
+        )
+    }
+
+    /// Perform several renames within the same directory at once, using
+    /// `IORING_OP_RENAMEAT`.
+    ///
+    /// The renames are submitted together, so they may be performed in any order (see above).
+    pub fn local_rename_many<D: AsRawFd + ?Sized, P: AsRef<Path>, R: AsRef<Path>>(
+        &mut self,
+        dir: &D,
+        pairs: &[(P, R)],
+        lookup_flags: LookupFlags,
+    ) -> Vec<Result<(), Error>> {
+        let mut results: Vec<Option<Result<(), Error>>> = pairs.iter().map(|_| None).collect();
+
+        if self.supports(opcode::RenameAt::CODE) {
+            let mut batch = Batch::default();
+
+            for (i, (old, new)) in pairs.iter().enumerate() {
+                let (old, new) = (old.as_ref(), new.as_ref());
+                let res = (|| {
+                    let old = crate::prepare_inner_operation(dir, old, lookup_flags)
+                        .map_err(|e| e.context("rename_secure", old))?;
+                    let new = crate::prepare_inner_operation(dir, new, lookup_flags)
+                        .map_err(|e| e.context("rename_secure", new))?;
+                    Ok::<_, Error>((old, new))
+                })();
+
+                let ((old_subdir, old_fname), (new_subdir, new_fname)) = match res {
+                    Ok(((old_subdir, Some(old_fname)), (new_subdir, Some(new_fname)))) => {
+                        ((old_subdir, old_fname), (new_subdir, new_fname))
+                    }
+                    // Let the synchronous implementation figure out the correct error
+                    Ok(_) => continue,
+                    Err(e) => {
+                        results[i] = Some(Err(e));
+                        continue;
+                    }
+                };
+
+                let (old_fname, new_fname) = match (
+                    CString::new(old_fname.as_bytes()),
+                    CString::new(new_fname.as_bytes()),
+                ) {
+                    (Ok(old_fname), Ok(new_fname)) => (old_fname, new_fname),
+                    (Err(e), _) | (_, Err(e)) => {
+                        results[i] = Some(Err(
+                            Error::from(io::Error::from(e)).context("rename_secure", old)
+                        ));
+                        continue;
+                    }
+                };
+
+                let entry = opcode::RenameAt::new(
+                    types::Fd(
+                        old_subdir
+                            .as_ref()
+                            .map_or(dir.as_raw_fd(), |d| d.as_raw_fd()),
+                    ),
+                    old_fname.as_ptr(),
+                    types::Fd(
+                        new_subdir
+                            .as_ref()
+                            .map_or(dir.as_raw_fd(), |d| d.as_raw_fd()),
+                    ),
+                    new_fname.as_ptr(),
+                )
+                .build();
+                batch.entries.push((i, entry));
+
+                batch.paths.push(old_fname);
+                batch.paths.push(new_fname);
+                batch.dirs.extend(old_subdir);
+                batch.dirs.extend(new_subdir);
+            }
+
+            for (i, res) in self.run_batch(&mut batch) {
+                results[i] = Some(if res >= 0 {
+                    Ok(())
+                } else {
+                    Err(Error::from(io::Error::from_raw_os_error(-res))
+                        .context("rename_secure", pairs[i].0.as_ref()))
+                });
+            }
+        }
+
+        results
+            .into_iter()
+            .zip(pairs)
+            .map(|(res, (old, new))| {
+                res.unwrap_or_else(|| {
+                    util::borrow_dir(dir).local_rename_secure(old, new, lookup_flags)
+                })
+            })
+            .collect()
+    }
+
+    /// Query the metadata of several files at once (without following symbolic links in the
+    /// final components), using `IORING_OP_STATX`.
+    ///
+    /// `mask` is a combination of the `libc::STATX_*` flags that specifies which fields are
+    /// requested; check `stx_mask` in the results to see which were filled in. Apart from the
+    /// type of the result, each request behaves like
+    /// [`DirSecureExt::metadata_secure()`](trait.DirSecureExt.html#method.metadata_secure).
+    pub fn statx_many<D: AsRawFd + ?Sized, P: AsRef<Path>>(
+        &mut self,
+        dir: &D,
+        paths: &[P],
+        mask: libc::c_uint,
+        lookup_flags: LookupFlags,
+    ) -> Vec<Result<libc::statx, Error>> {
+        let mut results: Vec<Option<Result<libc::statx, Error>>> =
+            paths.iter().map(|_| None).collect();
+
+        if self.supports(opcode::Statx::CODE) {
+            let mut batch = Batch {
+                // This must not be resized after entries refer to it
+                statx_bufs: vec![unsafe { std::mem::zeroed() }; paths.len()],
+                ..Default::default()
+            };
+            let bufs = batch.statx_bufs.as_mut_ptr();
+
+            for (i, path) in paths.iter().enumerate() {
+                let path = path.as_ref();
+
+                let (subdir, fname) = match crate::prepare_inner_operation(dir, path, lookup_flags)
+                {
+                    Ok((subdir, Some(fname))) => (subdir, fname),
+                    // Something like "/" or "a/.."; handled synchronously
+                    Ok((_, None)) => continue,
+                    Err(e) => {
+                        results[i] = Some(Err(e.context("metadata_secure", path)));
+                        continue;
+                    }
+                };
+
+                let fname = match CString::new(fname.as_bytes()) {
+                    Ok(fname) => fname,
+                    Err(e) => {
+                        results[i] = Some(Err(
+                            error::at_final(path, e.into()).context("metadata_secure", path)
+                        ));
+                        continue;
+                    }
+                };
+
+                let entry = opcode::Statx::new(
+                    types::Fd(subdir.as_ref().map_or(dir.as_raw_fd(), |d| d.as_raw_fd())),
+                    fname.as_ptr(),
+                    unsafe { bufs.add(i) } as *mut types::statx,
+                )
+                .flags(libc::AT_SYMLINK_NOFOLLOW)
+                .mask(mask)
+                .build();
+
+                batch.entries.push((i, entry));
+                batch.paths.push(fname);
+                batch.dirs.extend(subdir);
+            }
+
+            for (i, res) in self.run_batch(&mut batch) {
+                let path = paths[i].as_ref();
+
+                if res < 0 {
+                    results[i] = Some(Err(error::at_final(
+                        path,
+                        io::Error::from_raw_os_error(-res),
+                    )
+                    .context("metadata_secure", path)));
+                } else if let Some(buf) = batch.statx_bufs.get(i) {
+                    results[i] = Some(Ok(*buf));
+                }
+                // Otherwise, the ring failed and the buffers were leaked; retry synchronously
+            }
+        }
+
+        results
+            .into_iter()
+            .zip(paths)
+            .map(|(res, path)| {
+                res.unwrap_or_else(|| {
+                    let path = path.as_ref();
+                    error::with_context("metadata_secure", path, || {
+                        statx_sync(dir, path, mask, lookup_flags)
+                    })
+                })
+            })
+            .collect()
+    }
+}
+
+/// Query the metadata of a file with the `statx()` system call (or `fstatat()`, on kernels too
+/// old to support `statx()`).
+fn statx_sync<D: AsRawFd + ?Sized>(
+    dir: &D,
+    path: &Path,
+    mask: libc::c_uint,
+    lookup_flags: LookupFlags,
+) -> Result<libc::statx, Error> {
+    let (subdir, fname) = crate::prepare_inner_operation(dir, path, lookup_flags)?;
+    let dirfd = subdir.as_ref().map_or(dir.as_raw_fd(), |d| d.as_raw_fd());
+
+    let (fname, flags) = match fname {
+        Some(fname) => (
+            CString::new(fname.as_bytes()).map_err(|e| error::at_final(path, e.into()))?,
+            libc::AT_SYMLINK_NOFOLLOW,
+        ),
+        None => (
+            CString::default(),
+            libc::AT_SYMLINK_NOFOLLOW | libc::AT_EMPTY_PATH,
+        ),
+    };
+
+    let res = match crate::mount::statx(dirfd, &fname, flags, mask) {
+        Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {
+            let mut st = unsafe { std::mem::zeroed() };
+            if unsafe { libc::fstatat(dirfd, fname.as_ptr(), &mut st, flags) } < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(stat_to_statx(&st))
+            }
+        }
+        res => res,
+    };
+
+    res.map_err(|e| error::at_final(path, e))
+}
+
+/// Convert the result of `stat()` to the format used by `statx()` (with the basic fields filled
+/// in).
+fn stat_to_statx(st: &libc::stat) -> libc::statx {
+    let mut stx: libc::statx = unsafe { std::mem::zeroed() };
+
+    stx.stx_mask = libc::STATX_BASIC_STATS;
+    stx.stx_blksize = st.st_blksize as _;
+    stx.stx_nlink = st.st_nlink as _;
+    stx.stx_uid = st.st_uid;
+    stx.stx_gid = st.st_gid;
+    stx.stx_mode = st.st_mode as _;
+    stx.stx_ino = st.st_ino as _;
+    stx.stx_size = st.st_size as _;
+    stx.stx_blocks = st.st_blocks as _;
+    stx.stx_atime.tv_sec = st.st_atime as _;
+    stx.stx_atime.tv_nsec = st.st_atime_nsec as _;
+    stx.stx_mtime.tv_sec = st.st_mtime as _;
+    stx.stx_mtime.tv_nsec = st.st_mtime_nsec as _;
+    stx.stx_ctime.tv_sec = st.st_ctime as _;
+    stx.stx_ctime.tv_nsec = st.st_ctime_nsec as _;
+    stx.stx_rdev_major = libc::major(st.st_rdev) as _;
+    stx.stx_rdev_minor = libc::minor(st.st_rdev) as _;
+    stx.stx_dev_major = libc::major(st.st_dev) as _;
+    stx.stx_dev_minor = libc::minor(st.st_dev) as _;
+
+    stx
+}
+
+impl std::fmt::Debug for SecureUring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecureUring")
+            .field("available", &self.is_available())
+            .finish()
+    }
+}
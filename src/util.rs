@@ -1,17 +1,41 @@
 use std::ffi::OsStr;
 use std::io;
+use std::mem::ManuallyDrop;
 use std::os::unix::prelude::*;
 use std::path::Path;
 
-fn same_stat(st1: &libc::stat, st2: &libc::stat) -> bool {
+pub fn same_stat(st1: &libc::stat, st2: &libc::stat) -> bool {
     st1.st_dev == st2.st_dev && st1.st_ino == st2.st_ino
 }
 
-pub fn same_dir(d1: &openat::Dir, d2: &openat::Dir) -> io::Result<bool> {
-    Ok(same_stat(
-        d1.self_metadata()?.stat(),
-        d2.self_metadata()?.stat(),
-    ))
+pub fn fstat(fd: RawFd) -> io::Result<libc::stat> {
+    let mut st = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::fstat(fd, &mut st) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(st)
+    }
+}
+
+pub fn same_dir<D1: AsRawFd + ?Sized, D2: AsRawFd + ?Sized>(d1: &D1, d2: &D2) -> io::Result<bool> {
+    Ok(same_stat(&fstat(d1.as_raw_fd())?, &fstat(d2.as_raw_fd())?))
+}
+
+/// Temporarily view the given file descriptor as an `openat::Dir`, without taking ownership of
+/// it.
+pub fn borrow_dir<D: AsRawFd + ?Sized>(dir: &D) -> ManuallyDrop<openat::Dir> {
+    ManuallyDrop::new(unsafe { openat::Dir::from_raw_fd(dir.as_raw_fd()) })
+}
+
+/// Duplicate a directory file descriptor (unlike `Dir::try_clone()`, the new one is close-on-exec).
+pub fn dup_dir(dir: &openat::Dir) -> io::Result<openat::Dir> {
+    let fd = unsafe { libc::fcntl(dir.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(unsafe { openat::Dir::from_raw_fd(fd) })
+    }
 }
 
 pub fn get_symloop_max() -> Option<usize> {
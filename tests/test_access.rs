@@ -0,0 +1,76 @@
+use std::ffi::CString;
+use std::os::unix::prelude::*;
+use std::path::Path;
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, LookupFlags};
+
+fn access_real(path: &Path, mode: libc::c_int) -> bool {
+    let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+    unsafe { libc::access(path.as_ptr(), mode) == 0 }
+}
+
+#[test]
+fn test_access() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    for &(name, mode) in [("r", 0o400), ("rw", 0o600), ("x", 0o500), ("none", 0o000)].iter() {
+        tmpdir.new_file(name, 0o600).unwrap();
+        std::fs::set_permissions(
+            tmpdir_path.join(name),
+            std::fs::Permissions::from_mode(mode),
+        )
+        .unwrap();
+        // Absolute symlinks resolve inside the root
+        tmpdir
+            .symlink(format!("a/{}", name), format!("/{}", name))
+            .unwrap();
+    }
+
+    let modes = [
+        libc::F_OK,
+        libc::R_OK,
+        libc::W_OK,
+        libc::X_OK,
+        libc::R_OK | libc::W_OK,
+        libc::R_OK | libc::X_OK,
+    ];
+
+    for name in ["r", "rw", "x", "none", "a"].iter() {
+        for &mode in modes.iter() {
+            let expected = access_real(&tmpdir_path.join(name), mode);
+
+            for path in [name.to_string(), format!("a/{}", name)].iter() {
+                if path == "a/a" {
+                    continue;
+                }
+                for &effective in [false, true].iter() {
+                    assert_eq!(
+                        tmpdir
+                            .access_secure(path, mode, LookupFlags::empty(), effective)
+                            .unwrap(),
+                        expected,
+                        "{:?} {:o}",
+                        path,
+                        mode
+                    );
+                }
+            }
+        }
+    }
+
+    let err = tmpdir
+        .access_secure("missing", libc::F_OK, LookupFlags::empty(), true)
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    assert_eq!(err.operation(), "access_secure");
+
+    let err = tmpdir
+        .access_secure("a/r", libc::R_OK, LookupFlags::NO_SYMLINKS, true)
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+}
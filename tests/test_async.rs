@@ -0,0 +1,138 @@
+#![cfg(feature = "tokio")]
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use openat_secure::{AsyncSecureDir, LookupFlags};
+
+#[tokio::test]
+async fn test_async_files() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dir = AsyncSecureDir::open(tmpdir.path()).await.unwrap();
+
+    dir.create_dir_secure("a", 0o777, LookupFlags::empty())
+        .await
+        .unwrap();
+
+    let mut file = dir
+        .new_file_secure("/../a/b", 0o666, LookupFlags::empty())
+        .await
+        .unwrap();
+    file.write_all(b"abc").await.unwrap();
+    file.flush().await.unwrap();
+    drop(file);
+
+    // Resolve concurrently from several tasks sharing the same directory
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let dir = dir.clone();
+        handles.push(tokio::spawn(async move {
+            let mut buf = String::new();
+            dir.open_file_secure("a/../../a/b", LookupFlags::empty())
+                .await
+                .unwrap()
+                .read_to_string(&mut buf)
+                .await
+                .unwrap();
+            buf
+        }));
+    }
+    for handle in handles {
+        assert_eq!(handle.await.unwrap(), "abc");
+    }
+
+    let sub = dir.sub_dir_secure("a", LookupFlags::empty()).await.unwrap();
+    assert!(sub
+        .metadata_secure("b", LookupFlags::empty())
+        .await
+        .unwrap()
+        .is_file());
+
+    assert_eq!(
+        dir.open_file_secure("nonexistent", LookupFlags::empty())
+            .await
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+}
+
+#[tokio::test]
+async fn test_async_read_dir_walk() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dir = AsyncSecureDir::open(tmpdir.path()).await.unwrap();
+
+    dir.create_dir_secure("a", 0o777, LookupFlags::empty())
+        .await
+        .unwrap();
+    dir.create_dir_secure("a/b", 0o777, LookupFlags::empty())
+        .await
+        .unwrap();
+    for i in 0..100 {
+        dir.new_file_secure(format!("a/b/{}", i), 0o666, LookupFlags::empty())
+            .await
+            .unwrap();
+    }
+    // The walker must not follow this
+    dir.symlink_secure("a/up", "..", LookupFlags::empty())
+        .await
+        .unwrap();
+    dir.symlink_secure("a/abs", "/a/b", LookupFlags::empty())
+        .await
+        .unwrap();
+
+    let mut read_dir = dir.read_dir("a/b", LookupFlags::empty()).await.unwrap();
+    let mut count = 0;
+    while let Some(_entry) = read_dir.next_entry().await.unwrap() {
+        count += 1;
+    }
+    assert_eq!(count, 100);
+
+    assert_eq!(
+        dir.read_dir("a/b/0", LookupFlags::empty())
+            .await
+            .err()
+            .unwrap()
+            .raw_os_error(),
+        Some(libc::ENOTDIR)
+    );
+
+    // Symlinks in the entries are resolved against the original root
+    let identify = |d: &openat::Dir| {
+        let st = *d.self_metadata().unwrap().stat();
+        (st.st_dev, st.st_ino)
+    };
+    let root_id = identify(dir.as_dir());
+    let b_id = identify(&dir.as_dir().sub_dir("a/b").unwrap());
+
+    let mut read_dir = dir.read_dir("a", LookupFlags::empty()).await.unwrap();
+    while let Some(entry) = read_dir.next_entry().await.unwrap() {
+        let sub = entry.sub_dir_secure(LookupFlags::empty()).unwrap();
+        match entry.file_name().to_str().unwrap() {
+            "up" => assert_eq!(identify(&sub), root_id),
+            "abs" | "b" => assert_eq!(identify(&sub), b_id),
+            name => panic!("unexpected entry {:?}", name),
+        }
+    }
+
+    let mut walk = dir.walk("/", LookupFlags::empty());
+    let mut paths = HashSet::new();
+    while let Some(entry) = walk.next_entry().await.unwrap() {
+        if entry.path() == Path::new("a/up") {
+            let sub = entry.entry().sub_dir_secure(LookupFlags::empty()).unwrap();
+            assert_eq!(identify(&sub), root_id);
+        }
+        paths.insert(entry.path().to_path_buf());
+    }
+
+    let mut expected: HashSet<PathBuf> = (0..100)
+        .map(|i| PathBuf::from(format!("a/b/{}", i)))
+        .collect();
+    expected.insert("a".into());
+    expected.insert("a/b".into());
+    expected.insert("a/up".into());
+    expected.insert("a/abs".into());
+    assert_eq!(paths, expected);
+}
@@ -0,0 +1,76 @@
+use std::io::Read;
+
+use openat::Dir;
+
+use openat_secure::{Backend, DirSecureExt, LookupFlags};
+
+fn check_ops(tmpdir: &Dir) {
+    tmpdir
+        .create_dir_secure("a", 0o777, LookupFlags::empty())
+        .unwrap();
+    tmpdir
+        .new_file_secure("/../a/b", 0o666, LookupFlags::empty())
+        .unwrap();
+    tmpdir.symlink("a/up", "..").unwrap();
+
+    let mut buf = String::new();
+    tmpdir
+        .open_file_secure("a/up/a/up/../a/b", LookupFlags::empty())
+        .unwrap()
+        .read_to_string(&mut buf)
+        .unwrap();
+    assert_eq!(buf, "");
+
+    assert_eq!(
+        tmpdir
+            .open_file_secure("a/up/a/b", LookupFlags::NO_SYMLINKS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+
+    tmpdir
+        .remove_file_secure("a/up", LookupFlags::empty())
+        .unwrap();
+    tmpdir
+        .remove_file_secure("a/b", LookupFlags::empty())
+        .unwrap();
+    tmpdir.remove_dir_secure("a", LookupFlags::empty()).unwrap();
+}
+
+// The backend is process-wide, so everything is done in one test to avoid interference
+#[test]
+fn test_backends() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    assert_eq!(openat_secure::backend(), Backend::Auto);
+    check_ops(&tmpdir);
+
+    openat_secure::set_backend(Backend::ForceFallback);
+    assert_eq!(openat_secure::backend(), Backend::ForceFallback);
+    check_ops(&tmpdir);
+
+    openat_secure::set_backend(Backend::ForceOpenat2);
+    assert_eq!(openat_secure::backend(), Backend::ForceOpenat2);
+
+    // openat2() can't implement this combination
+    assert_eq!(
+        tmpdir
+            .sub_dir_secure(".", LookupFlags::NO_XDEV | LookupFlags::XDEV_BIND_OK)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(if cfg!(target_os = "linux") {
+            libc::ENOTSUP
+        } else {
+            libc::ENOSYS
+        })
+    );
+
+    // Only run the rest if openat2() is actually available
+    if tmpdir.sub_dir_secure(".", LookupFlags::empty()).is_ok() {
+        check_ops(&tmpdir);
+    }
+
+    openat_secure::set_backend(Backend::Auto);
+}
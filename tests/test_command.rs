@@ -0,0 +1,105 @@
+use std::os::unix::prelude::*;
+use std::process::Command;
+
+use openat::Dir;
+
+use openat_secure::{CommandSecureExt, DirSecureExt, LookupFlags};
+
+fn pwd(cmd: &mut Command) -> Vec<u8> {
+    let out = cmd.output().unwrap();
+    assert!(out.status.success());
+    out.stdout
+}
+
+fn expected(path: &std::path::Path) -> Vec<u8> {
+    let mut res = path.canonicalize().unwrap().into_os_string().into_vec();
+    res.push(b'\n');
+    res
+}
+
+#[test]
+fn test_current_dir() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.create_dir("a/b", 0o777).unwrap();
+    tmpdir.symlink("link", "/a/b").unwrap();
+
+    // "pwd -P" prints the physical path of the directory that the shell started in
+    let sh = || {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(["-c", "pwd -P"]);
+        cmd
+    };
+
+    let mut cmd = sh();
+    cmd.current_dir_secure(&tmpdir, "a/b", LookupFlags::empty())
+        .unwrap();
+    assert_eq!(pwd(&mut cmd), expected(&tmpdir_path.join("a/b")));
+
+    // Absolute symlinks are resolved within the root
+    let mut cmd = sh();
+    cmd.current_dir_secure(&tmpdir, "link", LookupFlags::empty())
+        .unwrap();
+    assert_eq!(pwd(&mut cmd), expected(&tmpdir_path.join("a/b")));
+
+    // The child starts in the directory that was opened, even after it is moved and replaced
+    let mut cmd = sh();
+    cmd.current_dir_fd(tmpdir.sub_dir_secure("a", LookupFlags::empty()).unwrap());
+    std::fs::rename(tmpdir_path.join("a"), tmpdir_path.join("c")).unwrap();
+    std::os::unix::fs::symlink(tmpdir_path, tmpdir_path.join("a")).unwrap();
+    assert_eq!(pwd(&mut cmd), expected(&tmpdir_path.join("c")));
+    // And the hook can run more than once
+    assert_eq!(pwd(&mut cmd), expected(&tmpdir_path.join("c")));
+
+    // It takes precedence over Command::current_dir()
+    let mut cmd = sh();
+    cmd.current_dir("/")
+        .current_dir_secure(&tmpdir, "c/b", LookupFlags::empty())
+        .unwrap();
+    assert_eq!(pwd(&mut cmd), expected(&tmpdir_path.join("c/b")));
+
+    // Errors are reported when the directory is opened, not when the command is spawned
+    let mut cmd = sh();
+    let err = cmd
+        .current_dir_secure(&tmpdir, "link", LookupFlags::NO_SYMLINKS)
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+    assert_eq!(err.operation(), "current_dir_secure");
+
+    let err = cmd
+        .current_dir_secure(&tmpdir, "missing", LookupFlags::empty())
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_exec_current_dir() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    std::io::Write::write_all(
+        &mut tmpdir.write_file("pwd.sh", 0o755).unwrap(),
+        b"#!/bin/sh\npwd -P\n",
+    )
+    .unwrap();
+
+    let root = Dir::open("/").unwrap();
+    let exe = root
+        .open_executable_secure(tmpdir_path.join("pwd.sh"), LookupFlags::empty())
+        .unwrap();
+    let out = exe
+        .command()
+        .current_dir_fd(tmpdir.sub_dir_secure("a", LookupFlags::empty()).unwrap())
+        .to_command()
+        .unwrap()
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(out.stdout, expected(&tmpdir_path.join("a")));
+}
@@ -0,0 +1,226 @@
+use std::io::{Read, Write};
+use std::os::unix::prelude::*;
+
+use openat::Dir;
+
+use openat_secure::{copy_file_secure, CopyOptions, LookupFlags};
+
+fn read_all(dir: &Dir, path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    dir.open_file(path).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+fn names(dir: &Dir, path: &str) -> Vec<String> {
+    let mut names: Vec<String> = dir
+        .list_dir(path)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_str().unwrap().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_copy_file() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.create_dir("b", 0o777).unwrap();
+    tmpdir.symlink("a/out", "/b").unwrap();
+    tmpdir.symlink("a/src", "../f").unwrap();
+
+    let data: Vec<u8> = (0..(3 << 20)).map(|i| (i % 251) as u8).collect();
+    let mut f = tmpdir.write_file("f", 0o640).unwrap();
+    f.write_all(&data).unwrap();
+    f.set_permissions(std::fs::Permissions::from_mode(0o640))
+        .unwrap();
+    let times = [
+        libc::timespec {
+            tv_sec: 1_000_000_000,
+            tv_nsec: 0,
+        },
+        libc::timespec {
+            tv_sec: 1_200_000_000,
+            tv_nsec: 5000,
+        },
+    ];
+    assert_eq!(unsafe { libc::futimens(f.as_raw_fd(), times.as_ptr()) }, 0);
+    drop(f);
+
+    // Symlinks are resolved inside the root on both sides
+    let n = copy_file_secure(
+        &tmpdir,
+        "a/src",
+        &tmpdir,
+        "a/out/g",
+        &CopyOptions::default().preserve_timestamps(true),
+    )
+    .unwrap();
+    assert_eq!(n, data.len() as u64);
+
+    // (Before reading the file, which may update the access time)
+    let meta = std::fs::metadata(tmpdir_path.join("b/g")).unwrap();
+    assert_eq!(meta.mode() & 0o7777, 0o640);
+    assert_eq!(meta.mtime(), 1_200_000_000);
+    assert_eq!(meta.mtime_nsec(), 5000);
+    assert_eq!(meta.atime(), 1_000_000_000);
+
+    assert_eq!(read_all(&tmpdir, "b/g"), data);
+    assert_eq!(names(&tmpdir, "b"), ["g"]);
+
+    // Without preserve_timestamps, they are new
+    copy_file_secure(&tmpdir, "f", &tmpdir, "b/h", &CopyOptions::default()).unwrap();
+    assert!(tmpdir.metadata("b/h").unwrap().stat().st_mtime > 1_200_000_000);
+
+    // A symlink at the destination is replaced, not followed
+    tmpdir.write_file("b/small", 0o666).unwrap();
+    tmpdir.symlink("b/link", "small").unwrap();
+    copy_file_secure(
+        &tmpdir,
+        "b/small",
+        &tmpdir,
+        "b/link",
+        &CopyOptions::default(),
+    )
+    .unwrap();
+    assert!(tmpdir.metadata("b/link").unwrap().is_file());
+
+    // no_clobber
+    let err = copy_file_secure(
+        &tmpdir,
+        "f",
+        &tmpdir,
+        "b/small",
+        &CopyOptions::default().no_clobber(true),
+    )
+    .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+    assert_eq!(err.operation(), "copy_file_secure");
+    assert_eq!(err.path(), Some(std::path::Path::new("b/small")));
+    assert_eq!(read_all(&tmpdir, "b/small"), b"");
+    copy_file_secure(
+        &tmpdir,
+        "f",
+        &tmpdir,
+        "b/new",
+        &CopyOptions::default().no_clobber(true),
+    )
+    .unwrap();
+    assert_eq!(read_all(&tmpdir, "b/new"), data);
+
+    // Replacing
+    copy_file_secure(&tmpdir, "f", &tmpdir, "b/small", &CopyOptions::default()).unwrap();
+    assert_eq!(read_all(&tmpdir, "b/small"), data);
+
+    for &(src, dst, eno) in [
+        ("a", "b/x", libc::EISDIR),
+        ("f", "b", libc::EISDIR),
+        ("f", "b/", libc::EISDIR),
+        ("f", "b/..", libc::EISDIR),
+        ("missing", "b/x", libc::ENOENT),
+        ("f", "missing/x", libc::ENOENT),
+    ]
+    .iter()
+    {
+        let err = copy_file_secure(&tmpdir, src, &tmpdir, dst, &CopyOptions::default());
+        assert_eq!(
+            err.unwrap_err().raw_os_error(),
+            Some(eno),
+            "{} {}",
+            src,
+            dst
+        );
+    }
+
+    // Nothing was left behind
+    assert_eq!(names(&tmpdir, "b"), ["g", "h", "link", "new", "small"]);
+}
+
+#[test]
+fn test_copy_file_lookup_flags() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir.write_file("f", 0o666).unwrap();
+    tmpdir.symlink("l", "f").unwrap();
+
+    let options = CopyOptions::default().lookup_flags(LookupFlags::NO_SYMLINKS);
+    let err = copy_file_secure(&tmpdir, "l", &tmpdir, "g", &options).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+    assert_eq!(err.path(), Some(std::path::Path::new("l")));
+
+    copy_file_secure(&tmpdir, "f", &tmpdir, "g", &options).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_copy_file_xattrs() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.write_file("f", 0o666).unwrap();
+
+    let path = std::ffi::CString::new(tmpdir_path.join("f").into_os_string().into_vec()).unwrap();
+    if unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            b"user.test\0".as_ptr() as *const libc::c_char,
+            b"value".as_ptr() as *const libc::c_void,
+            5,
+            0,
+        )
+    } < 0
+    {
+        // The filesystem doesn't support user xattrs
+        return;
+    }
+
+    copy_file_secure(
+        &tmpdir,
+        "f",
+        &tmpdir,
+        "g",
+        &CopyOptions::default().preserve_xattrs(true),
+    )
+    .unwrap();
+
+    let handle =
+        openat_secure::DirSecureExt::open_path_secure(&tmpdir, "g", LookupFlags::empty()).unwrap();
+    assert_eq!(handle.get_xattr("user.test").unwrap(), b"value");
+}
+
+#[test]
+fn test_copy_file_special() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    // Opening the FIFO must not block waiting for a writer
+    let fifo =
+        std::ffi::CString::new(tmpdir_path.join("fifo").into_os_string().into_vec()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o666) }, 0);
+    tmpdir.symlink("l", "fifo").unwrap();
+
+    for &src in ["fifo", "l"].iter() {
+        let err = copy_file_secure(&tmpdir, src, &tmpdir, "g", &CopyOptions::default());
+        assert_eq!(err.unwrap_err().raw_os_error(), Some(libc::EINVAL));
+    }
+    assert_eq!(names(&tmpdir, "."), ["fifo", "l"]);
+}
+
+#[test]
+fn test_copy_file_long_name() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir.write_file("f", 0o666).unwrap();
+
+    // The temporary file's name has to be shortened to fit
+    let long = "x".repeat(255);
+    copy_file_secure(&tmpdir, "f", &tmpdir, &long, &CopyOptions::default()).unwrap();
+    assert_eq!(names(&tmpdir, "."), ["f", long.as_str()]);
+}
@@ -0,0 +1,165 @@
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, Error, ErrorKind, LookupFlags, RetryPolicy};
+
+// NO_XDEV with XDEV_BIND_OK always uses the manual resolver, which knows the failing component
+const FALLBACK_FLAGS: LookupFlags = LookupFlags::NO_XDEV.union(LookupFlags::XDEV_BIND_OK);
+
+fn unwrap_err<T, E>(r: Result<T, E>) -> E {
+    match r {
+        Ok(_) => panic!("unwrap_err() on Ok() value"),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn test_error_kinds() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir
+        .create_dir_secure("a", 0o777, LookupFlags::empty())
+        .unwrap();
+    tmpdir
+        .new_file_secure("a/f", 0o666, LookupFlags::empty())
+        .unwrap();
+    tmpdir.symlink("a/loop", "loop").unwrap();
+    tmpdir.symlink("a/up", "..").unwrap();
+
+    for &flags in &[LookupFlags::empty(), FALLBACK_FLAGS] {
+        let err = unwrap_err(tmpdir.open_file_secure("a/f/x", flags));
+        assert_eq!(err.kind(), ErrorKind::NotADirectory);
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+        assert_eq!(err.operation(), "open_file_secure");
+        assert_eq!(err.path(), Some(Path::new("a/f/x")));
+
+        let err = unwrap_err(tmpdir.open_file_secure("a/loop", flags));
+        assert_eq!(err.kind(), ErrorKind::SymlinkLoop);
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+
+        let err = unwrap_err(tmpdir.sub_dir_secure("a/up", flags | LookupFlags::NO_SYMLINKS));
+        assert_eq!(err.kind(), ErrorKind::SymlinkLoop);
+        assert_eq!(err.operation(), "sub_dir_secure");
+
+        let err = unwrap_err(tmpdir.open_file_secure("a/nonexistent", flags));
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+}
+
+#[test]
+fn test_error_component() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir
+        .create_dir_secure("a", 0o777, LookupFlags::empty())
+        .unwrap();
+    tmpdir
+        .new_file_secure("a/f", 0o666, LookupFlags::empty())
+        .unwrap();
+    tmpdir.symlink("a/up", "..").unwrap();
+    tmpdir.symlink("a/bad", "f/x").unwrap();
+
+    let err = unwrap_err(tmpdir.open_file_secure("a/f/x", FALLBACK_FLAGS));
+    assert_eq!(err.component(), Some(1));
+    assert_eq!(err.component_name(), Some(OsStr::new("f")));
+
+    let err = unwrap_err(tmpdir.open_file_secure("a/up/a/f/x", FALLBACK_FLAGS));
+    assert_eq!(err.component(), Some(3));
+
+    // Errors inside a symlink target are attributed to the symlink
+    let err = unwrap_err(tmpdir.open_file_secure("a/bad", FALLBACK_FLAGS));
+    assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    assert_eq!(err.component(), Some(1));
+    assert_eq!(err.component_name(), Some(OsStr::new("bad")));
+
+    // The index refers to the original path, even if it started with "/"
+    let err = unwrap_err(tmpdir.create_dir_secure("/a/f/x", 0o777, FALLBACK_FLAGS));
+    assert_eq!(err.kind(), ErrorKind::NotADirectory);
+    assert_eq!(err.operation(), "create_dir_secure");
+    assert_eq!(err.component_name(), Some(OsStr::new("f")));
+
+    // Errors from the final operation are attributed to the final component
+    let err = unwrap_err(tmpdir.remove_file_secure("a/nonexistent", LookupFlags::empty()));
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    assert_eq!(err.component(), Some(1));
+    assert_eq!(err.component_name(), Some(OsStr::new("nonexistent")));
+
+    let err = unwrap_err(tmpdir.remove_dir_secure("/", LookupFlags::empty()));
+    assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+    assert_eq!(err.component(), None);
+}
+
+#[test]
+fn test_error_into_io_error() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    let err = unwrap_err(tmpdir.open_file_secure("nonexistent", LookupFlags::empty()));
+    let msg = err.to_string();
+    assert!(
+        msg.starts_with("open_file_secure(\"nonexistent\"): "),
+        "{}",
+        msg
+    );
+
+    // OS errors are converted back to the original error number
+    let err = io::Error::from(err);
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+    tmpdir.symlink("loop", "loop").unwrap();
+    tmpdir.symlink("up", "..").unwrap();
+    for (path, flags, errno) in [
+        ("loop", LookupFlags::empty(), libc::ELOOP),
+        ("up", LookupFlags::NO_SYMLINKS, libc::ELOOP),
+        (".", LookupFlags::empty(), libc::EISDIR),
+    ]
+    .iter()
+    {
+        let err = unwrap_err(tmpdir.write_file_secure(path, 0o666, *flags));
+        assert_eq!(
+            io::Error::from(err).raw_os_error(),
+            Some(*errno),
+            "{:?}",
+            path
+        );
+    }
+
+    fn open_io(dir: &Dir, path: &str) -> io::Result<std::fs::File> {
+        Ok(dir.open_file_secure(path, LookupFlags::empty())?)
+    }
+    assert_eq!(
+        open_io(&tmpdir, "a/nonexistent")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+
+    // Other errors are wrapped
+    let err = io::Error::from(Error::from(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "bad",
+    )));
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    let inner = err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+    assert_eq!(inner.raw_os_error(), None);
+    assert_eq!(inner.kind(), ErrorKind::Other);
+}
+
+#[test]
+fn test_retry_policy() {
+    let policy = RetryPolicy::new(8).backoff(Duration::from_micros(5), Duration::from_millis(2));
+    assert_eq!(openat_secure::retry_policy(), RetryPolicy::default());
+
+    openat_secure::set_retry_policy(policy);
+    assert_eq!(openat_secure::retry_policy(), policy);
+
+    openat_secure::set_retry_policy(RetryPolicy::default());
+}
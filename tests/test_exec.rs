@@ -0,0 +1,131 @@
+#![cfg(target_os = "linux")]
+
+use std::os::unix::prelude::*;
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, LookupFlags};
+
+fn write_script(dir: &Dir, path: &str, contents: &str) {
+    std::io::Write::write_all(
+        &mut dir.write_file(path, 0o755).unwrap(),
+        contents.as_bytes(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_exec() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    // An interpreter that only exists inside the root
+    tmpdir.create_dir("bin", 0o777).unwrap();
+    std::fs::copy("/bin/sh", tmpdir_path.join("bin/mysh")).unwrap();
+    std::fs::set_permissions(
+        tmpdir_path.join("bin/mysh"),
+        std::fs::Permissions::from_mode(0o755),
+    )
+    .unwrap();
+    tmpdir.symlink("bin/link", "../../bin/mysh").unwrap();
+
+    write_script(
+        &tmpdir,
+        "s.sh",
+        "#!/bin/mysh -e\necho hello \"$@\" \"$FOO\"\n",
+    );
+    write_script(&tmpdir, "nested.sh", "#! /s.sh  \nexit 1\n");
+    write_script(&tmpdir, "missing.sh", "#!/bin/missing\n");
+    write_script(&tmpdir, "loop.sh", "#!/loop.sh\n");
+
+    // A binary
+    let exe = tmpdir
+        .open_executable_secure("bin/link", LookupFlags::empty())
+        .unwrap();
+    assert!(exe.interpreter().is_none());
+    let out = exe
+        .command()
+        .args(["-c", "echo direct \"$0\""])
+        .to_command()
+        .unwrap()
+        .output()
+        .unwrap();
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"direct bin/link\n");
+
+    // A script
+    let exe = tmpdir
+        .open_executable_secure("s.sh", LookupFlags::empty())
+        .unwrap();
+    assert_eq!(
+        exe.interpreter().unwrap().path(),
+        std::path::Path::new("/bin/mysh")
+    );
+    let out = exe
+        .command()
+        .arg("a")
+        .env("FOO", "bar")
+        .to_command()
+        .unwrap()
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    assert_eq!(out.stdout, b"hello a bar\n");
+
+    let out = exe
+        .command()
+        .env_clear()
+        .to_command()
+        .unwrap()
+        .output()
+        .unwrap();
+    assert_eq!(out.stdout, b"hello \n");
+
+    // A script whose interpreter is another script
+    let exe = tmpdir
+        .open_executable_secure("nested.sh", LookupFlags::empty())
+        .unwrap();
+    let out = exe
+        .command()
+        .arg("a")
+        .to_command()
+        .unwrap()
+        .output()
+        .unwrap();
+    assert!(out.status.success(), "{:?}", out);
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.starts_with("hello /dev/fd/"), "{:?}", stdout);
+    assert!(stdout.ends_with(" a \n"), "{:?}", stdout);
+
+    for &(path, eno) in [
+        ("bin", libc::EACCES),
+        ("missing.sh", libc::ENOENT),
+        ("loop.sh", libc::ELOOP),
+    ]
+    .iter()
+    {
+        let err = tmpdir
+            .open_executable_secure(path, LookupFlags::empty())
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(eno), "{:?}", path);
+        assert_eq!(err.operation(), "open_executable_secure");
+    }
+
+    assert_eq!(
+        tmpdir
+            .open_executable_secure("missing.sh", LookupFlags::empty())
+            .unwrap_err()
+            .path(),
+        Some(std::path::Path::new("/bin/missing"))
+    );
+
+    // Too many interpreters isn't a symlink loop
+    assert_eq!(
+        tmpdir
+            .open_executable_secure("loop.sh", LookupFlags::empty())
+            .unwrap_err()
+            .kind(),
+        openat_secure::ErrorKind::Other
+    );
+}
@@ -0,0 +1,85 @@
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, ErrorKind, LookupFlags};
+
+#[test]
+fn test_exists() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.new_file("a/f", 0o666).unwrap();
+    tmpdir.symlink("a/l", "f").unwrap();
+    tmpdir.symlink("a/abs", "/a/f").unwrap();
+    tmpdir.symlink("a/dangling", "missing").unwrap();
+    tmpdir.symlink("a/chain", "dangling").unwrap();
+    tmpdir.symlink("a/loop", "loop").unwrap();
+
+    for &(path, exists, symlink_exists) in [
+        ("a", true, true),
+        ("a/f", true, true),
+        ("a/l", true, true),
+        ("a/abs", true, true),
+        // ".." can't go above the root
+        ("../../a/f", true, true),
+        ("a/dangling", false, true),
+        ("a/chain", false, true),
+        ("a/missing", false, false),
+        ("b/missing", false, false),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            tmpdir
+                .try_exists_secure(path, LookupFlags::empty())
+                .unwrap(),
+            exists,
+            "{:?}",
+            path
+        );
+        assert_eq!(
+            tmpdir
+                .symlink_exists_secure(path, LookupFlags::empty())
+                .unwrap(),
+            symlink_exists,
+            "{:?}",
+            path
+        );
+    }
+
+    // Loops are errors, not nonexistence
+    let err = tmpdir
+        .try_exists_secure("a/loop", LookupFlags::empty())
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+    assert_eq!(err.kind(), ErrorKind::SymlinkLoop);
+    assert_eq!(err.operation(), "try_exists_secure");
+    assert!(tmpdir
+        .symlink_exists_secure("a/loop", LookupFlags::empty())
+        .unwrap());
+
+    // So are symlinks that are forbidden by the lookup flags
+    assert_eq!(
+        tmpdir
+            .try_exists_secure("a/l", LookupFlags::NO_SYMLINKS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+    assert!(tmpdir
+        .symlink_exists_secure("a/l", LookupFlags::NO_SYMLINKS)
+        .unwrap());
+
+    // And looking up a file as a directory
+    let err = tmpdir
+        .try_exists_secure("a/f/x", LookupFlags::empty())
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+    assert_eq!(
+        tmpdir
+            .symlink_exists_secure("a/l/x", LookupFlags::empty())
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOTDIR)
+    );
+}
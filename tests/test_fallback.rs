@@ -0,0 +1,227 @@
+use std::fs;
+use std::os::unix::prelude::*;
+use std::path::Path;
+
+use openat::Dir;
+
+use openat_secure::{Backend, DirSecureExt, LookupFlags};
+
+// NO_XDEV with XDEV_BIND_OK always uses the manual resolver
+const FALLBACK_FLAGS: LookupFlags = LookupFlags::NO_XDEV.union(LookupFlags::XDEV_BIND_OK);
+
+fn make_tree(root: &Path) {
+    fs::create_dir(root.join("a")).unwrap();
+    fs::write(root.join("a/f"), b"").unwrap();
+    fs::write(root.join("g"), b"").unwrap();
+
+    let links = [
+        ("up", ".."),
+        ("abs", "/a/f"),
+        ("loop", "loop"),
+        ("dirlink", "/a/"),
+        ("flink_slash", "f/"),
+        ("deep", "../a/../a/up/a"),
+        ("escape", "../../../../g"),
+    ];
+    for &(name, target) in links.iter() {
+        std::os::unix::fs::symlink(target, root.join("a").join(name)).unwrap();
+    }
+}
+
+fn identify(meta: &fs::Metadata) -> (u64, u64) {
+    (meta.dev(), meta.ino())
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn identify_dir(dir: &Dir) -> (u64, u64) {
+    let meta = dir.self_metadata().unwrap();
+    (meta.stat().st_dev as u64, meta.stat().st_ino as u64)
+}
+
+/// Check every case (with `extra_flags` added), given the expected target (relative to the root)
+/// or error number
+fn check_cases(root: &Path, dir: &Dir, extra_flags: LookupFlags) {
+    let cases: &[(&str, LookupFlags, Result<&str, i32>)] = &[
+        ("a/f", LookupFlags::empty(), Ok("a/f")),
+        ("/a/f", LookupFlags::empty(), Ok("a/f")),
+        ("../../a/f", LookupFlags::empty(), Ok("a/f")),
+        ("./a//f", LookupFlags::empty(), Ok("a/f")),
+        ("a/up/a/up/a/f", LookupFlags::empty(), Ok("a/f")),
+        ("a/abs", LookupFlags::empty(), Ok("a/f")),
+        ("a/dirlink/f", LookupFlags::empty(), Ok("a/f")),
+        ("a/deep/f", LookupFlags::empty(), Ok("a/f")),
+        ("a/escape", LookupFlags::empty(), Ok("g")),
+        ("a/deep", LookupFlags::empty(), Ok("a")),
+        (".", LookupFlags::empty(), Ok(".")),
+        ("/", LookupFlags::empty(), Ok(".")),
+        ("a/..", LookupFlags::empty(), Ok(".")),
+        ("a/up", LookupFlags::empty(), Ok(".")),
+        ("a/f", LookupFlags::NO_SYMLINKS, Ok("a/f")),
+        ("a/../a/f", LookupFlags::NO_SYMLINKS, Ok("a/f")),
+        ("a/loop", LookupFlags::empty(), Err(libc::ELOOP)),
+        ("a/up", LookupFlags::NO_SYMLINKS, Err(libc::ELOOP)),
+        ("a/up/a/f", LookupFlags::NO_SYMLINKS, Err(libc::ELOOP)),
+        ("a/flink_slash", LookupFlags::empty(), Err(libc::ENOTDIR)),
+        ("a/f/x", LookupFlags::empty(), Err(libc::ENOTDIR)),
+        ("a/", LookupFlags::empty(), Ok("a")),
+        ("a/dirlink/.", LookupFlags::empty(), Ok("a")),
+        ("g/", LookupFlags::empty(), Err(libc::ENOTDIR)),
+        ("a/f/.", LookupFlags::empty(), Err(libc::ENOTDIR)),
+        ("a/abs/", LookupFlags::empty(), Err(libc::ENOTDIR)),
+        ("a/nonexistent", LookupFlags::empty(), Err(libc::ENOENT)),
+        ("a/nonexistent/f", LookupFlags::empty(), Err(libc::ENOENT)),
+    ];
+
+    for &(path, flags, expected) in cases.iter() {
+        let flags = flags | extra_flags;
+        let res = dir
+            .open_file_secure(path, flags)
+            .map(|f| identify(&f.metadata().unwrap()))
+            .map_err(|e| e.raw_os_error().unwrap());
+
+        let expected = expected.map(|p| identify(&fs::metadata(root.join(p)).unwrap()));
+        assert_eq!(
+            res,
+            expected,
+            "{:?} (flags {:?}, backend {:?})",
+            path,
+            flags,
+            openat_secure::backend()
+        );
+    }
+}
+
+// The backend is process-wide, so both backends are checked in one test to avoid interference
+#[test]
+fn test_fallback_behavior() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    make_tree(tmpdir.path());
+    let dir = Dir::open(tmpdir.path()).unwrap();
+
+    for &backend in [Backend::Auto, Backend::ForceFallback].iter() {
+        openat_secure::set_backend(backend);
+
+        for &flags in [
+            LookupFlags::empty(),
+            LookupFlags::VERIFY_BENEATH,
+            FALLBACK_FLAGS,
+            FALLBACK_FLAGS | LookupFlags::VERIFY_BENEATH,
+        ]
+        .iter()
+        {
+            check_cases(tmpdir.path(), &dir, flags);
+        }
+
+        check_trailing_slash(tmpdir.path(), &dir);
+    }
+    openat_secure::set_backend(Backend::Auto);
+}
+
+#[test]
+fn test_fallback_long_paths() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dir = Dir::open(tmpdir.path()).unwrap();
+
+    // Enough components to spill every inline buffer
+    let mut path = String::new();
+    for i in 0..64 {
+        path.push_str(&format!("d{}/", i));
+        dir.create_dir_secure(&path, 0o777, LookupFlags::empty())
+            .unwrap();
+    }
+    dir.symlink_secure(
+        format!("{}link", path),
+        "../".repeat(64),
+        LookupFlags::empty(),
+    )
+    .unwrap();
+
+    let deep = dir.sub_dir_secure(&path, FALLBACK_FLAGS).unwrap();
+    let link_target = dir
+        .sub_dir_secure(format!("{}link/{}", path, path), FALLBACK_FLAGS)
+        .unwrap();
+    assert_eq!(identify_dir(&deep), identify_dir(&link_target));
+
+    // Component names of up to NAME_MAX bytes work, and the filesystem rejects longer ones
+    let long = "x".repeat(255);
+    dir.new_file(&long, 0o666).unwrap();
+    let file = dir.open_file_secure(&long, FALLBACK_FLAGS).unwrap();
+    assert_eq!(
+        identify(&file.metadata().unwrap()),
+        identify(&fs::metadata(tmpdir.path().join(&long)).unwrap())
+    );
+    assert_eq!(
+        dir.open_file_secure("x".repeat(300), FALLBACK_FLAGS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENAMETOOLONG)
+    );
+
+    // Paths containing NUL bytes
+    assert_eq!(
+        dir.open_file_secure("a\0b", FALLBACK_FLAGS)
+            .unwrap_err()
+            .kind(),
+        openat_secure::ErrorKind::Other
+    );
+}
+
+/// Open `path` with a plain `openat()` (there are no symlinks that could escape the root on the
+/// paths it is used for) and return the identity of the result or the error number.
+fn openat_oracle(dir: &Dir, path: &str, flags: libc::c_int) -> Result<(u64, u64), i32> {
+    let c_path = std::ffi::CString::new(path).unwrap();
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            c_path.as_ptr(),
+            flags | libc::O_CLOEXEC,
+            0o666,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    let file = unsafe { fs::File::from_raw_fd(fd) };
+    Ok(identify(&file.metadata().unwrap()))
+}
+
+/// Check that a trailing "/" or "/." requires the final component to be a directory (and can't
+/// be combined with O_CREAT), exactly like it does for open()
+fn check_trailing_slash(root: &Path, dir: &Dir) {
+    let read_paths = ["a/", "a/.", "a//", "g/", "g/.", "nonexistent/"];
+    let create_paths = [
+        "a/",
+        "g/",
+        "g/.",
+        "new/",
+        "new/.",
+        "missing/new/",
+        ".",
+        "a/..",
+        "a/up/",
+        "a/flink_slash",
+    ];
+    let backend = openat_secure::backend();
+
+    for &path in read_paths.iter() {
+        let res = dir
+            .open_file_secure(path, LookupFlags::empty())
+            .map(|f| identify(&f.metadata().unwrap()))
+            .map_err(|e| e.raw_os_error().unwrap());
+        let expected = openat_oracle(dir, path, libc::O_RDONLY);
+        assert_eq!(res, expected, "{:?} (backend {:?})", path, backend);
+    }
+
+    for &path in create_paths.iter() {
+        let res = dir
+            .update_file_secure(path, 0o666, LookupFlags::empty())
+            .map(|f| identify(&f.metadata().unwrap()))
+            .map_err(|e| e.raw_os_error().unwrap());
+        let expected = openat_oracle(dir, path, libc::O_CREAT | libc::O_RDWR);
+        assert_eq!(res, expected, "{:?} (backend {:?})", path, backend);
+    }
+
+    // Nothing was created, and the existing file is untouched
+    assert!(!root.join("new").exists());
+    assert!(root.join("g").is_file());
+}
@@ -0,0 +1,69 @@
+use std::fs;
+use std::os::unix::prelude::*;
+
+use openat_secure::{DirSecureExt, LookupFlags};
+
+fn check_root<D: DirSecureExt>(root: &D) {
+    root.create_dir_secure("a", 0o777, LookupFlags::empty())
+        .unwrap();
+    root.new_file_secure("/../a/b", 0o666, LookupFlags::empty())
+        .unwrap();
+
+    assert!(root
+        .metadata_secure("a/b", LookupFlags::empty())
+        .unwrap()
+        .is_file());
+    assert!(root
+        .sub_dir_secure("a/..", LookupFlags::empty())
+        .unwrap()
+        .self_metadata()
+        .unwrap()
+        .is_dir());
+
+    openat_secure::rename_secure(root, "a/b", root, "c", LookupFlags::empty()).unwrap();
+    root.remove_file_secure("c", LookupFlags::empty()).unwrap();
+    root.remove_dir_secure("a", LookupFlags::empty()).unwrap();
+
+    assert!(root.parent_secure().unwrap().is_some());
+}
+
+#[test]
+fn test_file_root() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    check_root(&fs::File::open(tmpdir.path()).unwrap());
+}
+
+#[test]
+fn test_owned_fd_root() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    check_root(&OwnedFd::from(fs::File::open(tmpdir.path()).unwrap()));
+}
+
+#[test]
+fn test_borrowed_fd_root() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let file = fs::File::open(tmpdir.path()).unwrap();
+    check_root(&file.as_fd());
+}
+
+#[cfg(feature = "cap-std")]
+#[test]
+fn test_cap_std_root() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    check_root(
+        &cap_std::fs::Dir::open_ambient_dir(tmpdir.path(), cap_std::ambient_authority()).unwrap(),
+    );
+}
+
+#[test]
+fn test_not_dir_root() {
+    let tmpfile = tempfile::NamedTempFile::new().unwrap();
+    let file = fs::File::open(tmpfile.path()).unwrap();
+
+    assert_eq!(
+        file.open_file_secure("a", LookupFlags::empty())
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOTDIR)
+    );
+}
@@ -1,21 +1,44 @@
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
 use std::collections::HashSet;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::io;
+use std::os::unix::prelude::*;
 
 use openat::Dir;
 
-use openat_secure::{DirSecureExt, LookupFlags};
+use openat_secure::{DirEntry, DirSecureExt, LookupFlags};
 
-fn collect_entries(it: openat::DirIter) -> io::Result<HashSet<OsString>> {
+fn collect_entries<I, E>(it: I) -> io::Result<HashSet<OsString>>
+where
+    I: Iterator<Item = io::Result<E>>,
+    E: Entry,
+{
     let mut res = HashSet::new();
 
     for entry in it {
-        res.insert(entry?.file_name().into());
+        res.insert(entry?.name().into());
     }
 
     Ok(res)
 }
 
+trait Entry {
+    fn name(&self) -> &OsStr;
+}
+
+impl Entry for openat::Entry {
+    fn name(&self) -> &OsStr {
+        self.file_name()
+    }
+}
+
+impl Entry for DirEntry {
+    fn name(&self) -> &OsStr {
+        self.file_name()
+    }
+}
+
 #[test]
 fn test_list_dir() {
     let tmpdir = tempfile::tempdir().unwrap();
@@ -31,3 +54,163 @@ fn test_list_dir() {
         root_entries
     );
 }
+
+#[test]
+fn test_dir_entries() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.create_dir("a/d", 0o777).unwrap();
+    tmpdir.new_file("a/f", 0o666).unwrap();
+    tmpdir.symlink("a/s", "/a/f").unwrap();
+    tmpdir.symlink("a/up", "../..").unwrap();
+    tmpdir.new_file("f", 0o666).unwrap();
+
+    let mut entries: Vec<DirEntry> = tmpdir
+        .list_dir_secure("a", LookupFlags::empty())
+        .unwrap()
+        .collect::<io::Result<_>>()
+        .unwrap();
+    entries.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+
+    let names: Vec<&OsStr> = entries.iter().map(|e| e.file_name()).collect();
+    assert_eq!(names, ["d", "f", "s", "up"]);
+
+    let (d, f, s, up) = (&entries[0], &entries[1], &entries[2], &entries[3]);
+
+    // Symlinks are resolved against the root that the listing started from, not the containing
+    // directory
+    assert_eq!(
+        s.open_secure(LookupFlags::empty())
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .ino(),
+        std::fs::metadata(tmpdir_path.join("a/f")).unwrap().ino()
+    );
+    assert_eq!(
+        s.open_secure(LookupFlags::NO_SYMLINKS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+    let up_dir = up.sub_dir_secure(LookupFlags::empty()).unwrap();
+    assert!(up_dir.metadata("a").unwrap().is_dir());
+    assert_eq!(
+        up.sub_dir_secure(LookupFlags::NO_SYMLINKS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+
+    // Move the directory; the entries still refer to it
+    std::fs::rename(tmpdir_path.join("a"), tmpdir_path.join("b")).unwrap();
+    tmpdir.create_dir("a", 0o777).unwrap();
+
+    for &(entry, ftype) in [
+        (d, openat::SimpleType::Dir),
+        (f, openat::SimpleType::File),
+        (s, openat::SimpleType::Symlink),
+    ]
+    .iter()
+    {
+        if let Some(t) = entry.file_type() {
+            assert_eq!(t, ftype);
+        }
+        assert_eq!(entry.metadata().unwrap().simple_type(), ftype);
+    }
+
+    // Other entries are opened in the directory that was listed, wherever it is now
+    let d_dir = d.sub_dir_secure(LookupFlags::empty()).unwrap();
+    assert_eq!(
+        d_dir.self_metadata().unwrap().stat().st_ino,
+        tmpdir.metadata("b/d").unwrap().stat().st_ino
+    );
+    assert_eq!(
+        f.open_secure(LookupFlags::empty())
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .ino(),
+        std::fs::metadata(tmpdir_path.join("b/f")).unwrap().ino()
+    );
+
+    let err = f.sub_dir_secure(LookupFlags::empty()).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+    assert_eq!(err.operation(), "DirEntry::sub_dir_secure");
+
+    for entry in entries.iter() {
+        entry.remove().unwrap();
+    }
+    assert_eq!(
+        collect_entries(tmpdir.list_dir("b").unwrap()).unwrap(),
+        HashSet::new()
+    );
+    assert_eq!(f.remove().unwrap_err().raw_os_error(), Some(libc::ENOENT));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_list_dir_raw() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.symlink("s", "/a").unwrap();
+    tmpdir.create_dir("a/d", 0o777).unwrap();
+    tmpdir.symlink("a/l", "d").unwrap();
+    // Enough entries (with long enough names) to need many getdents64() calls
+    for i in 0..1000 {
+        tmpdir
+            .new_file(format!("a/{}{}", "x".repeat(100), i), 0o666)
+            .unwrap();
+    }
+
+    let expected: HashMap<OsString, (u64, openat::SimpleType)> =
+        std::fs::read_dir(tmpdir_path.join("a"))
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let ftype = e.file_type().unwrap();
+                let ftype = if ftype.is_dir() {
+                    openat::SimpleType::Dir
+                } else if ftype.is_symlink() {
+                    openat::SimpleType::Symlink
+                } else {
+                    openat::SimpleType::File
+                };
+                (e.file_name(), (e.ino(), ftype))
+            })
+            .collect();
+    assert_eq!(expected.len(), 1002);
+
+    for &buf_size in [0, 4096, 1024 * 1024].iter() {
+        let entries: HashMap<OsString, (u64, openat::SimpleType)> = tmpdir
+            .list_dir_raw_secure("s", buf_size, LookupFlags::empty())
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                let ftype = e
+                    .file_type()
+                    .unwrap_or_else(|| tmpdir.metadata(e.file_name()).unwrap().simple_type());
+                (e.file_name().into(), (e.ino(), ftype))
+            })
+            .collect();
+        assert_eq!(entries, expected);
+    }
+
+    // The entries are read from the directory that was opened, not "."
+    let names = collect_entries(tmpdir.list_dir_secure("s", LookupFlags::empty()).unwrap());
+    assert_eq!(names.unwrap().len(), 1002);
+
+    // O_PATH file descriptors can't be read
+    let dir = tmpdir.sub_dir_secure("a", LookupFlags::empty()).unwrap();
+    let err = openat_secure::Getdents::new(dir, 4096)
+        .next()
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+}
@@ -0,0 +1,129 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, LockKind, LookupFlags};
+
+#[test]
+fn test_lock_conflicts() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    let lock = tmpdir
+        .lock_file_secure("lock", 0o600, LockKind::Exclusive, LookupFlags::empty())
+        .unwrap();
+    assert_eq!(lock.kind(), LockKind::Exclusive);
+
+    // Locks conflict even within the same process
+    for &kind in [LockKind::Shared, LockKind::Exclusive].iter() {
+        let err = tmpdir
+            .try_lock_file_secure("/../lock", 0o600, kind, LookupFlags::empty())
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EWOULDBLOCK));
+        assert_eq!(err.operation(), "try_lock_file_secure");
+    }
+
+    let start = Instant::now();
+    let err = tmpdir
+        .lock_file_secure_timeout(
+            "lock",
+            0o600,
+            LockKind::Shared,
+            Duration::from_millis(50),
+            LookupFlags::empty(),
+        )
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ETIMEDOUT));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+
+    // Release it and take shared locks
+    let mut file = lock.unlock().unwrap();
+    file.write_all(b"abc").unwrap();
+
+    let shared1 = tmpdir
+        .try_lock_file_secure("lock", 0o600, LockKind::Shared, LookupFlags::empty())
+        .unwrap();
+    let shared2 = tmpdir
+        .lock_file_secure_timeout(
+            "lock",
+            0o600,
+            LockKind::Shared,
+            Duration::from_millis(50),
+            LookupFlags::empty(),
+        )
+        .unwrap();
+
+    let mut buf = String::new();
+    shared1.file().read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "abc");
+
+    assert_eq!(
+        tmpdir
+            .try_lock_file_secure("lock", 0o600, LockKind::Exclusive, LookupFlags::empty())
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EWOULDBLOCK)
+    );
+
+    // Dropping the guards releases the locks
+    drop(shared1);
+    drop(shared2);
+    tmpdir
+        .try_lock_file_secure("lock", 0o600, LockKind::Exclusive, LookupFlags::empty())
+        .unwrap();
+}
+
+#[test]
+fn test_lock_wait() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    let lock = tmpdir
+        .lock_file_secure("lock", 0o600, LockKind::Exclusive, LookupFlags::empty())
+        .unwrap();
+
+    let tmpdir2 = tmpdir.try_clone().unwrap();
+    let handle = std::thread::spawn(move || {
+        tmpdir2
+            .lock_file_secure("lock", 0o600, LockKind::Exclusive, LookupFlags::empty())
+            .unwrap();
+    });
+
+    std::thread::sleep(Duration::from_millis(20));
+    drop(lock);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_lock_symlinks() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    // Planted symlinks can't redirect the lock file outside of the directory
+    std::os::unix::fs::symlink(outside.path().join("lock"), tmpdir_path.join("abs")).unwrap();
+    std::os::unix::fs::symlink("../../lock", tmpdir_path.join("rel")).unwrap();
+
+    assert_eq!(
+        tmpdir
+            .lock_file_secure("abs", 0o600, LockKind::Exclusive, LookupFlags::empty())
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+    tmpdir
+        .lock_file_secure("rel", 0o600, LockKind::Exclusive, LookupFlags::empty())
+        .unwrap();
+    assert!(tmpdir_path.join("lock").exists());
+    assert!(!outside.path().join("lock").exists());
+
+    assert_eq!(
+        tmpdir
+            .lock_file_secure("rel", 0o600, LockKind::Exclusive, LookupFlags::NO_SYMLINKS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+}
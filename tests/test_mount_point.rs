@@ -0,0 +1,43 @@
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, LookupFlags};
+
+#[test]
+fn test_is_mount_point() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.new_file("f", 0o666).unwrap();
+    tmpdir.symlink("root", "/").unwrap();
+
+    assert!(!tmpdir
+        .is_mount_point_secure("a", LookupFlags::empty())
+        .unwrap());
+
+    // Symlinks are resolved relative to the root, which isn't a mount point here
+    assert!(!tmpdir
+        .is_mount_point_secure("root", LookupFlags::empty())
+        .unwrap());
+
+    let err = tmpdir
+        .is_mount_point_secure("f", LookupFlags::empty())
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOTDIR));
+    assert_eq!(err.operation(), "is_mount_point_secure");
+
+    let root = Dir::open("/").unwrap();
+    assert!(root
+        .is_mount_point_secure(".", LookupFlags::empty())
+        .unwrap());
+
+    #[cfg(target_os = "linux")]
+    if std::path::Path::new("/proc/self").exists() {
+        assert!(root
+            .is_mount_point_secure("proc", LookupFlags::empty())
+            .unwrap());
+        assert!(!root
+            .is_mount_point_secure("proc/self", LookupFlags::empty())
+            .unwrap());
+    }
+}
@@ -0,0 +1,196 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, ErrorKind, LookupFlags};
+
+const NAMES: &[&str] = &["a", "b", "c", "d"];
+
+/// A small deterministic PRNG (xorshift64*), so failures can be reproduced from the seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn choose<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+}
+
+/// Generate a random relative or absolute path, possibly with `.`/`..` components and a trailing
+/// slash.
+fn random_path(rng: &mut Rng) -> String {
+    let mut path = String::new();
+    if rng.below(4) == 0 {
+        path.push('/');
+    }
+
+    let n = 1 + rng.below(5);
+    for i in 0..n {
+        if i > 0 {
+            path.push('/');
+        }
+        path.push_str(match rng.below(8) {
+            0 => "..",
+            1 => ".",
+            _ => rng.choose(NAMES),
+        });
+    }
+
+    if rng.below(5) == 0 {
+        path.push('/');
+    }
+    path
+}
+
+/// Populate `dir` with a random tree of directories, files, and symlinks (whose targets may
+/// escape the root, loop, or dangle).
+fn random_tree(rng: &mut Rng, dir: &Path, depth: usize) {
+    for name in NAMES.iter() {
+        let path = dir.join(name);
+
+        match rng.below(if depth == 0 { 3 } else { 4 }) {
+            0 => (),
+            1 => fs::write(&path, b"").unwrap(),
+            2 => std::os::unix::fs::symlink(random_path(rng), &path).unwrap(),
+            _ => {
+                fs::create_dir(&path).unwrap();
+                random_tree(rng, &path, depth - 1);
+            }
+        }
+    }
+}
+
+fn check_tree(seed: u64) {
+    let mut rng = Rng::new(seed);
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    random_tree(&mut rng, tmpdir.path(), 3);
+    let dir = Dir::open(tmpdir.path()).unwrap();
+
+    let flags = [
+        LookupFlags::empty(),
+        LookupFlags::NO_SYMLINKS,
+        LookupFlags::NO_XDEV,
+    ];
+
+    for _ in 0..200 {
+        let path = PathBuf::from(random_path(&mut rng));
+
+        for &flags in flags.iter() {
+            let flags = flags | LookupFlags::PARANOID;
+
+            let results = [
+                dir.open_file_secure(&path, flags).map(drop),
+                dir.sub_dir_secure(&path, flags).map(drop),
+            ];
+
+            for res in results.iter() {
+                if let Err(e) = res {
+                    assert_ne!(
+                        e.kind(),
+                        ErrorKind::BackendMismatch,
+                        "seed {}, path {:?}, flags {:?}: {}",
+                        seed,
+                        path,
+                        flags,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_paranoid_random_trees() {
+    for seed in 0..50 {
+        check_tree(seed);
+    }
+}
+
+#[test]
+fn test_paranoid_create() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dir = Dir::open(tmpdir.path()).unwrap();
+    dir.symlink("up", "..").unwrap();
+
+    // Side effects of the final open must not be repeated by the check
+    dir.new_file_secure("up/up/f", 0o666, LookupFlags::PARANOID)
+        .unwrap();
+    assert!(tmpdir.path().join("f").exists());
+
+    assert_eq!(
+        dir.new_file_secure("up/f", 0o666, LookupFlags::PARANOID)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EEXIST)
+    );
+    assert_eq!(
+        dir.new_file_secure("g/", 0o666, LookupFlags::PARANOID)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EISDIR)
+    );
+
+    // When openat2() fails, the check must not create or truncate anything either
+    fs::write(tmpdir.path().join("g"), b"data").unwrap();
+    dir.create_dir("d", 0o777).unwrap();
+    dir.symlink("dangling", "missing/f").unwrap();
+
+    let before = list(tmpdir.path());
+    let cases: &[(&str, i32)] = &[
+        ("missing/f", libc::ENOENT),
+        ("new/", libc::EISDIR),
+        ("new/.", libc::ENOENT),
+        ("g/", libc::EISDIR),
+        ("g/.", libc::ENOTDIR),
+        ("g/f", libc::ENOTDIR),
+        ("d", libc::EISDIR),
+        ("dangling", libc::ENOENT),
+        ("up/dangling", libc::ENOENT),
+    ];
+    for &(path, errno) in cases.iter() {
+        for &flags in [LookupFlags::empty(), LookupFlags::NO_SYMLINKS].iter() {
+            let err = dir
+                .write_file_secure(path, 0o666, flags | LookupFlags::PARANOID)
+                .unwrap_err();
+            assert_ne!(
+                err.kind(),
+                ErrorKind::BackendMismatch,
+                "{:?}: {}",
+                path,
+                err
+            );
+            if flags.is_empty() {
+                assert_eq!(err.raw_os_error(), Some(errno), "{:?}", path);
+            }
+        }
+    }
+    assert_eq!(list(tmpdir.path()), before);
+    assert_eq!(fs::read(tmpdir.path().join("g")).unwrap(), b"data");
+}
+
+/// All the names in the directory, sorted.
+fn list(dir: &Path) -> Vec<std::ffi::OsString> {
+    let mut names: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect();
+    names.sort();
+    names
+}
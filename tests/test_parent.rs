@@ -15,3 +15,41 @@ fn test_parent() {
         .unwrap()
         .is_some());
 }
+
+#[test]
+fn test_parent_within() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    std::fs::create_dir_all(tmpdir.path().join("root/a/b")).unwrap();
+    std::fs::create_dir(tmpdir.path().join("other")).unwrap();
+
+    let root = Dir::open(&tmpdir.path().join("root")).unwrap();
+    let b = Dir::open(&tmpdir.path().join("root/a/b")).unwrap();
+    let other = Dir::open(&tmpdir.path().join("other")).unwrap();
+
+    assert!(root.is_descendant_of(&root).unwrap());
+    assert!(b.is_descendant_of(&root).unwrap());
+    assert!(!other.is_descendant_of(&root).unwrap());
+    assert!(!root.is_descendant_of(&b).unwrap());
+
+    // Walk up from "b" to the root, and stop there
+    let a = b.parent_within(&root).unwrap().unwrap();
+    assert_eq!(
+        a.metadata(".").unwrap().stat().st_ino,
+        root.metadata("a").unwrap().stat().st_ino
+    );
+    let top = a.parent_within(&root).unwrap().unwrap();
+    assert!(top.parent_within(&root).unwrap().is_none());
+
+    let err = other.parent_within(&root).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+    assert_eq!(err.kind(), openat_secure::ErrorKind::EscapeAttempt);
+    assert_eq!(err.operation(), "parent_within");
+
+    // Once "a" is moved out of the root, it's no longer a descendant
+    std::fs::rename(tmpdir.path().join("root/a"), tmpdir.path().join("a")).unwrap();
+    assert!(!b.is_descendant_of(&root).unwrap());
+    assert_eq!(
+        b.parent_within(&root).unwrap_err().raw_os_error(),
+        Some(libc::EXDEV)
+    );
+}
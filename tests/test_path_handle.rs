@@ -0,0 +1,165 @@
+#![cfg(target_os = "linux")]
+
+use std::ffi::CString;
+use std::io::{Read, Write};
+use std::os::unix::prelude::*;
+use std::path::Path;
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, LookupFlags};
+
+#[test]
+fn test_open_path() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.write_file("a/f", 0o000).unwrap();
+    tmpdir.symlink("l", "/a/f").unwrap();
+    tmpdir.symlink("up", "..").unwrap();
+
+    let file_ino = std::fs::metadata(tmpdir_path.join("a/f")).unwrap().ino();
+
+    // Following symlinks (relative to the root)
+    let handle = tmpdir
+        .open_path_secure("up/l", LookupFlags::empty())
+        .unwrap();
+    assert!(handle.metadata().unwrap().is_file());
+    assert_eq!(handle.metadata().unwrap().ino(), file_ino);
+    assert_eq!(
+        handle.read_link().unwrap_err().raw_os_error(),
+        Some(libc::EINVAL)
+    );
+
+    // Not following the final symlink
+    let link = tmpdir
+        .open_path_nofollow_secure("up/l", LookupFlags::empty())
+        .unwrap();
+    assert!(link.metadata().unwrap().file_type().is_symlink());
+    assert_eq!(link.read_link().unwrap(), Path::new("/a/f"));
+
+    // Symlinks in other components are still followed (unless NO_SYMLINKS is passed)
+    assert_eq!(
+        tmpdir
+            .open_path_nofollow_secure("up/l", LookupFlags::NO_SYMLINKS)
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+
+    // The handle keeps referring to the same file after it's renamed
+    tmpdir.local_rename("a/f", "a/g").unwrap();
+    tmpdir.write_file("a/f", 0o666).unwrap();
+    assert_eq!(handle.metadata().unwrap().ino(), file_ino);
+
+    // Reopening works, and refers to the same file
+    let mut perms = handle.metadata().unwrap().permissions();
+    perms.set_mode(0o600);
+    std::fs::set_permissions(tmpdir_path.join("a/g"), perms).unwrap();
+    handle
+        .reopen(libc::O_WRONLY)
+        .unwrap()
+        .write_all(b"abc")
+        .unwrap();
+    let mut buf = String::new();
+    handle
+        .reopen(libc::O_RDONLY)
+        .unwrap()
+        .read_to_string(&mut buf)
+        .unwrap();
+    assert_eq!(buf, "abc");
+
+    // Directory handles can be used as roots
+    let dir = tmpdir.open_path_secure("a", LookupFlags::empty()).unwrap();
+    assert!(dir
+        .open_path_secure("../../g", LookupFlags::empty())
+        .unwrap()
+        .metadata()
+        .unwrap()
+        .is_file());
+    assert!(dir.into_dir().metadata("g").unwrap().is_file());
+}
+
+#[test]
+fn test_path_handle_xattr() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.new_file("f", 0o666).unwrap();
+
+    let path = CString::new(tmpdir_path.join("f").as_os_str().as_bytes()).unwrap();
+    let name = CString::new("user.openat_secure").unwrap();
+    if unsafe {
+        libc::setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            b"value".as_ptr() as *const libc::c_void,
+            5,
+            0,
+        )
+    } < 0
+    {
+        // Extended attributes aren't supported on this filesystem
+        return;
+    }
+
+    let handle = tmpdir.open_path_secure("f", LookupFlags::empty()).unwrap();
+    assert_eq!(handle.get_xattr("user.openat_secure").unwrap(), b"value");
+    assert!(handle
+        .list_xattr()
+        .unwrap()
+        .iter()
+        .any(|name| name == "user.openat_secure"));
+    assert_eq!(
+        handle
+            .get_xattr("user.nonexistent")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENODATA)
+    );
+
+    // Directories are supported too
+    let handle = tmpdir.open_path_secure(".", LookupFlags::empty()).unwrap();
+    assert_eq!(
+        handle
+            .get_xattr("user.nonexistent")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENODATA)
+    );
+    handle.list_xattr().unwrap();
+
+    // Symlinks are supported too (the attributes of the link itself are read)
+    tmpdir.symlink("l", "f").unwrap();
+    let handle = tmpdir
+        .open_path_nofollow_secure("l", LookupFlags::empty())
+        .unwrap();
+    assert!(!handle
+        .list_xattr()
+        .unwrap()
+        .iter()
+        .any(|name| name == "user.openat_secure"));
+
+    // Read permission isn't needed
+    tmpdir.new_file("noperm", 0o000).unwrap();
+    let path = CString::new(tmpdir_path.join("noperm").as_os_str().as_bytes()).unwrap();
+    assert_eq!(
+        unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                b"other".as_ptr() as *const libc::c_void,
+                5,
+                0,
+            )
+        },
+        0
+    );
+    let handle = tmpdir
+        .open_path_secure("noperm", LookupFlags::empty())
+        .unwrap();
+    assert_eq!(handle.get_xattr("user.openat_secure").unwrap(), b"other");
+}
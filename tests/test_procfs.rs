@@ -0,0 +1,105 @@
+#![cfg(target_os = "linux")]
+
+use std::io::Read;
+use std::os::unix::prelude::*;
+
+use openat_secure::{Backend, ProcfsBase, ProcfsHandle};
+
+#[test]
+fn test_procfs() {
+    for &backend in [Backend::Auto, Backend::ForceFallback].iter() {
+        openat_secure::set_backend(backend);
+        check_procfs();
+    }
+
+    openat_secure::set_backend(Backend::Auto);
+}
+
+fn check_procfs() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let path = tmpdir.path().join("f");
+    let file = std::fs::File::create(&path).unwrap();
+    let fd = file.as_raw_fd();
+
+    let procfs = ProcfsHandle::new().unwrap();
+
+    // Magic links can be read
+    assert_eq!(
+        procfs
+            .read_link(ProcfsBase::ProcSelf, format!("fd/{}", fd))
+            .unwrap(),
+        path
+    );
+
+    // But only followed by open_follow()
+    let err = procfs
+        .open(ProcfsBase::ProcSelf, format!("fd/{}", fd), libc::O_RDONLY)
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+    let reopened = procfs
+        .open_follow(ProcfsBase::ProcSelf, format!("fd/{}", fd), libc::O_RDONLY)
+        .unwrap();
+    assert_eq!(
+        reopened.metadata().unwrap().ino(),
+        file.metadata().unwrap().ino()
+    );
+
+    let mut fdinfo = String::new();
+    procfs
+        .open(
+            ProcfsBase::ProcThreadSelf,
+            format!("fdinfo/{}", fd),
+            libc::O_RDONLY,
+        )
+        .unwrap()
+        .read_to_string(&mut fdinfo)
+        .unwrap();
+    assert!(fdinfo.starts_with("pos:"));
+
+    // Ordinary symlinks are followed
+    let mut status = String::new();
+    procfs
+        .open(ProcfsBase::ProcRoot, "self/status", libc::O_RDONLY)
+        .unwrap()
+        .read_to_string(&mut status)
+        .unwrap();
+    assert!(status.contains(&format!("\nPid:\t{}\n", std::process::id())));
+
+    // Paths must stay inside procfs
+    for &(base, path, eno) in [
+        (ProcfsBase::ProcSelf, "/etc", libc::EINVAL),
+        (ProcfsBase::ProcRoot, "..", libc::EXDEV),
+        (ProcfsBase::ProcSelf, "../..", libc::EXDEV),
+        (ProcfsBase::ProcSelf, "cwd/.", libc::ELOOP),
+        (ProcfsBase::ProcSelf, "root/etc", libc::ELOOP),
+    ]
+    .iter()
+    {
+        let err = procfs.open_follow(base, path, libc::O_PATH).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(eno), "{:?}", path);
+    }
+}
+
+#[test]
+fn test_procfs_shared() {
+    // The handle is only opened once
+    let procfs = ProcfsHandle::shared().unwrap();
+    assert!(std::ptr::eq(procfs, ProcfsHandle::shared().unwrap()));
+    assert_eq!(
+        ProcfsHandle::shared().unwrap().as_raw_fd(),
+        procfs.as_raw_fd()
+    );
+
+    let file = tempfile::tempfile().unwrap();
+    let reopened = procfs
+        .open_follow(
+            ProcfsBase::ProcSelf,
+            format!("fd/{}", file.as_raw_fd()),
+            libc::O_RDONLY,
+        )
+        .unwrap();
+    assert_eq!(
+        reopened.metadata().unwrap().ino(),
+        file.metadata().unwrap().ino()
+    );
+}
@@ -0,0 +1,150 @@
+use std::path::Path;
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, ErrorKind, LookupFlags};
+
+fn chain(dir: &Dir, path: &str, lookup_flags: LookupFlags) -> Vec<(String, String)> {
+    dir.read_link_chain_secure(path, lookup_flags)
+        .unwrap()
+        .iter()
+        .map(|hop| {
+            (
+                hop.path().to_str().unwrap().to_string(),
+                hop.target().to_str().unwrap().to_string(),
+            )
+        })
+        .collect()
+}
+
+fn pairs(hops: &[(&str, &str)]) -> Vec<(String, String)> {
+    hops.iter()
+        .map(|&(p, t)| (p.to_string(), t.to_string()))
+        .collect()
+}
+
+#[test]
+fn test_read_link_chain() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.new_file("a/f", 0o666).unwrap();
+    tmpdir.symlink("l1", "a/l2").unwrap();
+    tmpdir.symlink("a/l2", "../l3").unwrap();
+    tmpdir.symlink("l3", "/a/f").unwrap();
+    tmpdir.symlink("s", "a").unwrap();
+    tmpdir.symlink("escape", "../../../../a/f").unwrap();
+    tmpdir.symlink("dangling", "nonexistent/x").unwrap();
+    tmpdir.symlink("dirlink", "a/").unwrap();
+    tmpdir.symlink("loop", "loop").unwrap();
+
+    assert_eq!(
+        chain(&tmpdir, "l1", LookupFlags::empty()),
+        pairs(&[("l1", "a/l2"), ("a/l2", "../l3"), ("a/../l3", "/a/f")])
+    );
+
+    // Intermediate symlinks are resolved, but not reported
+    assert_eq!(
+        chain(&tmpdir, "/s/l2", LookupFlags::empty()),
+        pairs(&[("/s/l2", "../l3"), ("/s/../l3", "/a/f")])
+    );
+
+    assert_eq!(
+        chain(&tmpdir, "escape", LookupFlags::empty()),
+        pairs(&[("escape", "../../../../a/f")])
+    );
+    assert_eq!(
+        chain(&tmpdir, "dangling", LookupFlags::empty()),
+        pairs(&[("dangling", "nonexistent/x")])
+    );
+    assert_eq!(
+        chain(&tmpdir, "dirlink/", LookupFlags::empty()),
+        pairs(&[("dirlink/", "a/")])
+    );
+
+    // Not symlinks
+    for &path in ["a", "a/f", "/", "a/..", "s/.."].iter() {
+        assert_eq!(chain(&tmpdir, path, LookupFlags::empty()), pairs(&[]));
+    }
+    assert_eq!(chain(&tmpdir, "a/f", LookupFlags::NO_SYMLINKS), pairs(&[]));
+
+    let err = tmpdir
+        .read_link_chain_secure("nonexistent", LookupFlags::empty())
+        .unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+
+    for &(path, flags) in [
+        ("loop", LookupFlags::empty()),
+        ("l1", LookupFlags::NO_SYMLINKS),
+    ]
+    .iter()
+    {
+        let err = tmpdir.read_link_chain_secure(path, flags).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::SymlinkLoop);
+        assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        assert_eq!(err.operation(), "read_link_chain_secure");
+        assert_eq!(err.path(), Some(Path::new(path)));
+        assert_eq!(err.component(), Some(0));
+    }
+}
+
+#[test]
+fn test_read_link_chain_budget() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let dir = Dir::open(tmpdir.path()).unwrap();
+
+    dir.new_file("g", 0o666).unwrap();
+    dir.symlink("f", "g").unwrap();
+    dir.symlink("here", ".").unwrap();
+
+    // Symlinks followed in the leading directories count against the same limit as the hops
+    let max = 40;
+    let path = format!("{}f", "here/".repeat(max - 1));
+    assert_eq!(chain(&dir, &path, LookupFlags::empty()).len(), 1);
+    assert_eq!(
+        dir.open_file_secure(&path, LookupFlags::empty())
+            .unwrap()
+            .metadata()
+            .unwrap()
+            .len(),
+        0
+    );
+
+    let path = format!("{}f", "here/".repeat(max));
+    let err = dir
+        .read_link_chain_secure(&path, LookupFlags::empty())
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::SymlinkLoop);
+    // open_file_secure() agrees
+    assert_eq!(
+        dir.open_file_secure(&path, LookupFlags::empty())
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ELOOP)
+    );
+
+    // Each hop is worth 3 links ("l{i}" -> "here/here/l{i + 1}"), so only 13 hops fit
+    for i in 0..20 {
+        dir.symlink(format!("l{}", i), format!("here/here/l{}", i + 1))
+            .unwrap();
+    }
+    dir.new_file("l20", 0o666).unwrap();
+    for start in 0..20 {
+        let path = format!("l{}", start);
+        let res = dir.read_link_chain_secure(&path, LookupFlags::empty());
+        let n_hops = 20 - start;
+        if n_hops * 3 <= max {
+            assert_eq!(res.unwrap().len(), n_hops);
+            dir.open_file_secure(&path, LookupFlags::empty()).unwrap();
+        } else {
+            assert_eq!(res.unwrap_err().kind(), ErrorKind::SymlinkLoop);
+            assert_eq!(
+                dir.open_file_secure(&path, LookupFlags::empty())
+                    .unwrap_err()
+                    .raw_os_error(),
+                Some(libc::ELOOP)
+            );
+        }
+    }
+}
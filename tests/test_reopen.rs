@@ -0,0 +1,90 @@
+use std::io::{Read, Write};
+use std::os::unix::prelude::*;
+
+use openat::Dir;
+
+use openat_secure::{reopen, DirSecureExt, LookupFlags};
+
+#[test]
+fn test_reopen_dir() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.new_file("a/f", 0o666).unwrap();
+    tmpdir.symlink("s", "/a").unwrap();
+
+    let sub = tmpdir.sub_dir_secure("s", LookupFlags::empty()).unwrap();
+
+    // Even after it has been moved, reopening gives the same directory
+    std::fs::rename(tmpdir_path.join("a"), tmpdir_path.join("b")).unwrap();
+    tmpdir.create_dir("a", 0o777).unwrap();
+
+    let dir = reopen(&sub, libc::O_RDONLY | libc::O_DIRECTORY).unwrap();
+    assert_eq!(
+        dir.metadata().unwrap().ino(),
+        std::fs::metadata(tmpdir_path.join("b")).unwrap().ino()
+    );
+
+    // And it can be listed
+    let names: Vec<_> = unsafe { Dir::from_raw_fd(dir.into_raw_fd()) }
+        .list_self()
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_os_string())
+        .collect();
+    assert_eq!(names, ["f"]);
+}
+
+#[test]
+fn test_reopen_file() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    tmpdir.write_file("f", 0o666).unwrap();
+
+    // A read-only file can be reopened for writing
+    let file = tmpdir.open_file_secure("f", LookupFlags::empty()).unwrap();
+    std::fs::rename(tmpdir_path.join("f"), tmpdir_path.join("g")).unwrap();
+    tmpdir.write_file("f", 0o666).unwrap();
+
+    match reopen(&file, libc::O_WRONLY) {
+        Ok(mut file) => file.write_all(b"abc").unwrap(),
+        // Only directories can be reopened on some platforms
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return,
+        Err(e) => panic!("{}", e),
+    }
+
+    let mut data = String::new();
+    tmpdir
+        .open_file("g")
+        .unwrap()
+        .read_to_string(&mut data)
+        .unwrap();
+    assert_eq!(data, "abc");
+    assert_eq!(tmpdir.metadata("f").unwrap().len(), 0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_reopen_path_handle() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.write_file("a/f", 0o666).unwrap();
+
+    let handle = tmpdir
+        .open_path_secure("a/f", LookupFlags::empty())
+        .unwrap();
+    let mut file = reopen(&handle, libc::O_RDWR).unwrap();
+    file.write_all(b"abc").unwrap();
+    assert_eq!(tmpdir.metadata("a/f").unwrap().len(), 3);
+
+    // Directories can't be reopened for writing
+    let handle = tmpdir.open_path_secure("a", LookupFlags::empty()).unwrap();
+    let err = reopen(&handle, libc::O_RDWR).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EISDIR));
+    assert_eq!(err.operation(), "reopen");
+}
@@ -0,0 +1,215 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::os::unix::prelude::*;
+use std::path::Path;
+
+use openat::Dir;
+
+use openat_secure::secure_fs;
+
+fn unwrap_err<T, E>(r: Result<T, E>) -> E {
+    match r {
+        Ok(_) => panic!("unwrap_err() on Ok() value"),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn test_read_write() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    secure_fs::write(&tmpdir, "a", b"abc").unwrap();
+    assert_eq!(secure_fs::read(&tmpdir, "a").unwrap(), b"abc");
+    assert_eq!(secure_fs::read_to_string(&tmpdir, "/../a").unwrap(), "abc");
+
+    // Overwriting truncates
+    secure_fs::write(&tmpdir, "a", b"d").unwrap();
+    assert_eq!(secure_fs::read_to_string(&tmpdir, "a").unwrap(), "d");
+
+    // Symlinks are resolved relative to the root
+    secure_fs::symlink(&tmpdir, "/../../a", "s").unwrap();
+    assert_eq!(
+        secure_fs::read_link(&tmpdir, "s").unwrap(),
+        Path::new("/../../a")
+    );
+    assert_eq!(secure_fs::read_to_string(&tmpdir, "s").unwrap(), "d");
+}
+
+#[test]
+fn test_copy() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    secure_fs::write(&tmpdir, "a", b"abc").unwrap();
+    std::fs::set_permissions(
+        tmpdir.recover_path().unwrap().join("a"),
+        std::fs::Permissions::from_mode(0o640),
+    )
+    .unwrap();
+
+    assert_eq!(secure_fs::copy(&tmpdir, "a", "b").unwrap(), 3);
+    assert_eq!(secure_fs::read(&tmpdir, "b").unwrap(), b"abc");
+    assert_eq!(
+        secure_fs::metadata(&tmpdir, "b").unwrap().stat().st_mode & 0o777,
+        0o640
+    );
+
+    // Directories can't be copied
+    secure_fs::create_dir(&tmpdir, "c").unwrap();
+    assert_eq!(
+        secure_fs::copy(&tmpdir, "c", "d").unwrap_err().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn test_create_remove_dir_all() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    secure_fs::create_dir_all(&tmpdir, "a/b/c").unwrap();
+    secure_fs::create_dir_all(&tmpdir, "a/b/c").unwrap();
+    secure_fs::create_dir_all(&tmpdir, "/").unwrap();
+    assert!(secure_fs::metadata(&tmpdir, "a/b/c").unwrap().is_dir());
+
+    secure_fs::write(&tmpdir, "a/b/f", b"").unwrap();
+    assert!(secure_fs::create_dir_all(&tmpdir, "a/b/f/g").is_err());
+
+    // Plant a symlink pointing outside of the tree; remove_dir_all() must only remove the link
+    secure_fs::create_dir(&tmpdir, "outside").unwrap();
+    secure_fs::write(&tmpdir, "outside/keep", b"").unwrap();
+    tmpdir.symlink("a/b/c/link", "../../../outside").unwrap();
+    tmpdir.symlink("a/link", "../outside").unwrap();
+
+    secure_fs::remove_dir_all(&tmpdir, "a/link").unwrap();
+    assert!(!secure_fs::exists(&tmpdir, "a/link"));
+    assert!(secure_fs::exists(&tmpdir, "outside/keep"));
+
+    secure_fs::remove_dir_all(&tmpdir, "a").unwrap();
+    assert!(!secure_fs::exists(&tmpdir, "a"));
+    assert!(secure_fs::exists(&tmpdir, "outside/keep"));
+
+    assert_eq!(
+        secure_fs::remove_dir_all(&tmpdir, "/")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::EBUSY)
+    );
+}
+
+#[test]
+fn test_read_dir() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    secure_fs::create_dir(&tmpdir, "a").unwrap();
+    secure_fs::write(&tmpdir, "a/b", b"").unwrap();
+    secure_fs::hard_link(&tmpdir, "a/b", "a/c").unwrap();
+    secure_fs::rename(&tmpdir, "a/c", "a/d").unwrap();
+
+    let entries: HashSet<OsString> = secure_fs::read_dir(&tmpdir, "a")
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into())
+        .collect();
+
+    assert_eq!(
+        entries,
+        ["b", "d"]
+            .iter()
+            .map(OsString::from)
+            .collect::<HashSet<_>>()
+    );
+
+    secure_fs::remove_file(&tmpdir, "a/b").unwrap();
+    secure_fs::remove_file(&tmpdir, "a/d").unwrap();
+    secure_fs::remove_dir(&tmpdir, "a").unwrap();
+    assert!(!secure_fs::exists(&tmpdir, "a"));
+}
+
+#[test]
+fn test_metadata() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir_path = tmpdir.path();
+    let tmpdir = Dir::open(tmpdir_path).unwrap();
+
+    secure_fs::create_dir(&tmpdir, "a").unwrap();
+    tmpdir.symlink("s", "/a").unwrap();
+    tmpdir.symlink("loop", "loop").unwrap();
+
+    assert!(secure_fs::metadata(&tmpdir, "s").unwrap().is_dir());
+    assert_eq!(
+        secure_fs::symlink_metadata(&tmpdir, "s")
+            .unwrap()
+            .simple_type(),
+        openat::SimpleType::Symlink
+    );
+
+    assert!(secure_fs::exists(&tmpdir, "s"));
+    assert!(!secure_fs::exists(&tmpdir, "loop"));
+    assert!(!secure_fs::exists(&tmpdir, "nonexistent"));
+    assert_eq!(
+        unwrap_err(secure_fs::metadata(&tmpdir, "loop")).raw_os_error(),
+        Some(libc::ELOOP)
+    );
+
+    // Querying a FIFO (through a symlink) must not open it for reading, which would block
+    let fifo =
+        std::ffi::CString::new(tmpdir_path.join("a/fifo").into_os_string().into_vec()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+    tmpdir.symlink("f", "a/fifo").unwrap();
+    assert_eq!(
+        secure_fs::metadata(&tmpdir, "f").unwrap().simple_type(),
+        openat::SimpleType::Other
+    );
+    assert_eq!(
+        secure_fs::canonicalize(&tmpdir, "s/../f").unwrap(),
+        Path::new("/a/fifo")
+    );
+}
+
+#[test]
+fn test_canonicalize() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    secure_fs::create_dir_all(&tmpdir, "a/b").unwrap();
+    secure_fs::write(&tmpdir, "a/b/f", b"").unwrap();
+    tmpdir.symlink("a/up", "../../..").unwrap();
+    tmpdir.symlink("a/b/abs", "/a").unwrap();
+    tmpdir.symlink("a/b/file", "f").unwrap();
+
+    for (path, expected) in [
+        (".", "/"),
+        ("/", "/"),
+        ("..", "/"),
+        ("a", "/a"),
+        ("a/b/..", "/a"),
+        ("a/up", "/"),
+        ("a/up/a/b/", "/a/b"),
+        ("a/b/abs", "/a"),
+        ("a/b/abs/b/file", "/a/b/f"),
+    ]
+    .iter()
+    {
+        assert_eq!(
+            secure_fs::canonicalize(&tmpdir, path).unwrap(),
+            Path::new(expected),
+            "{:?}",
+            path
+        );
+    }
+
+    assert_eq!(
+        secure_fs::canonicalize(&tmpdir, "a/b/file/")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOTDIR)
+    );
+    assert_eq!(
+        secure_fs::canonicalize(&tmpdir, "a/nonexistent")
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+}
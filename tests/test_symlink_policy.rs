@@ -0,0 +1,95 @@
+use std::path::Path;
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, ErrorKind, LookupFlags, SymlinkPolicy};
+
+fn check(dir: &Dir, path: &str, target: &str, policy: SymlinkPolicy, allowed: bool) {
+    let res = dir.symlink_secure_with_policy(path, target, policy, LookupFlags::empty());
+
+    if allowed {
+        res.unwrap();
+        assert_eq!(
+            dir.read_link_secure(path, LookupFlags::empty()).unwrap(),
+            Path::new(target)
+        );
+        dir.remove_file_secure(path, LookupFlags::empty()).unwrap();
+    } else {
+        let err = res.unwrap_err();
+        assert_eq!(
+            err.kind(),
+            ErrorKind::EscapeAttempt,
+            "{:?} -> {:?}",
+            path,
+            target
+        );
+        assert_eq!(err.raw_os_error(), Some(libc::EXDEV));
+        assert_eq!(
+            err.component_name(),
+            Path::new(path).file_name(),
+            "{:?} -> {:?}",
+            path,
+            target
+        );
+        assert!(dir.metadata_secure(path, LookupFlags::empty()).is_err());
+    }
+}
+
+#[test]
+fn test_symlink_policy() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir.create_dir("a", 0o777).unwrap();
+    tmpdir.create_dir("a/b", 0o777).unwrap();
+    tmpdir.symlink("s", "/").unwrap();
+    tmpdir.symlink("deep", "/a/b").unwrap();
+
+    let cases = [
+        ("a/b/l", "/etc/shadow", SymlinkPolicy::Any, true),
+        ("a/b/l", "../../../..", SymlinkPolicy::Any, true),
+        ("a/b/l", "/etc/shadow", SymlinkPolicy::RelativeOnly, false),
+        ("a/b/l", "../../../..", SymlinkPolicy::RelativeOnly, true),
+        ("a/b/l", "/a", SymlinkPolicy::WithinRoot, false),
+        ("a/b/l", "../../x", SymlinkPolicy::WithinRoot, true),
+        ("a/b/l", "../../../x", SymlinkPolicy::WithinRoot, false),
+        ("a/b/l", "x/../../..", SymlinkPolicy::WithinRoot, true),
+        (
+            "a/b/l",
+            "x/../../../../..",
+            SymlinkPolicy::WithinRoot,
+            false,
+        ),
+        ("l", "..", SymlinkPolicy::WithinRoot, false),
+        ("l", ".", SymlinkPolicy::WithinRoot, true),
+        ("a/b/l", "../x", SymlinkPolicy::WithinParent, false),
+        ("a/b/l", "c/../d", SymlinkPolicy::WithinParent, true),
+        ("a/b/l", "./c/..", SymlinkPolicy::WithinParent, true),
+        ("a/b/l", "c/../..", SymlinkPolicy::WithinParent, false),
+        // The depth of the directory is checked, not the depth of the path used to reach it
+        ("s/a/b/l", "../../x", SymlinkPolicy::WithinRoot, true),
+        ("s/l", "../x", SymlinkPolicy::WithinRoot, false),
+        ("deep/l", "../../x", SymlinkPolicy::WithinRoot, true),
+        ("deep/l", "../../../x", SymlinkPolicy::WithinRoot, false),
+        ("a/b/../l", "../x", SymlinkPolicy::WithinRoot, true),
+        ("a/b/../l", "../../x", SymlinkPolicy::WithinRoot, false),
+    ];
+
+    for &(path, target, policy, allowed) in cases.iter() {
+        check(&tmpdir, path, target, policy, allowed);
+    }
+
+    // Other errors take priority
+    assert_eq!(
+        tmpdir
+            .symlink_secure_with_policy(
+                "a/c/l",
+                "/x",
+                SymlinkPolicy::WithinParent,
+                LookupFlags::empty()
+            )
+            .unwrap_err()
+            .raw_os_error(),
+        Some(libc::ENOENT)
+    );
+}
@@ -0,0 +1,154 @@
+#![cfg(all(target_os = "linux", feature = "io-uring"))]
+
+use std::io::Read;
+
+use openat::Dir;
+
+use openat_secure::{DirSecureExt, LookupFlags, OpenRequest, SecureUring};
+
+#[test]
+fn test_uring_open_many() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir
+        .create_dir_secure("a", 0o777, LookupFlags::empty())
+        .unwrap();
+    tmpdir
+        .write_file_secure("a/b", 0o666, LookupFlags::empty())
+        .unwrap();
+    tmpdir.symlink("s", "/../a").unwrap();
+
+    // Use a small ring to test splitting the batch
+    let mut ring = SecureUring::new(2).unwrap();
+
+    let mut results = ring.open_many(
+        &tmpdir,
+        &[
+            OpenRequest::new("a/b", LookupFlags::empty()),
+            OpenRequest::new("s/b", LookupFlags::empty()),
+            OpenRequest::new("s/b", LookupFlags::NO_SYMLINKS),
+            OpenRequest::new("a/c", LookupFlags::empty()),
+            OpenRequest::new("a/c", LookupFlags::empty())
+                .flags(libc::O_WRONLY | libc::O_CREAT)
+                .mode(0o600),
+            OpenRequest::new("/", LookupFlags::NO_XDEV | LookupFlags::XDEV_BIND_OK),
+        ],
+    );
+    assert_eq!(results.len(), 6);
+
+    let mut buf = String::new();
+    results.remove(0).unwrap().read_to_string(&mut buf).unwrap();
+    assert_eq!(buf, "");
+    results.remove(0).unwrap();
+    assert_eq!(
+        results.remove(0).unwrap_err().raw_os_error(),
+        Some(libc::ELOOP)
+    );
+    // The requests are performed concurrently, so we don't know if "a/c" was opened before or
+    // after it was created.
+    results.remove(0).ok();
+    results.remove(0).unwrap();
+    assert!(results.remove(0).unwrap().metadata().unwrap().is_dir());
+
+    assert!(tmpdir
+        .metadata_secure("a/c", LookupFlags::empty())
+        .unwrap()
+        .is_file());
+}
+
+#[test]
+fn test_uring_inner_ops() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    let mut ring = SecureUring::new(4).unwrap();
+
+    let results = ring.create_dir_many(
+        &tmpdir,
+        &["a", "b", "/..", "x/y"],
+        0o777,
+        LookupFlags::empty(),
+    );
+    results[0].as_ref().unwrap();
+    results[1].as_ref().unwrap();
+    assert_eq!(
+        results[2].as_ref().unwrap_err().raw_os_error(),
+        Some(libc::EEXIST)
+    );
+    assert_eq!(
+        results[3].as_ref().unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+
+    tmpdir
+        .new_file_secure("a/f", 0o666, LookupFlags::empty())
+        .unwrap();
+
+    let results =
+        ring.local_rename_many(&tmpdir, &[("a/f", "b/g"), ("/", "c")], LookupFlags::empty());
+    results[0].as_ref().unwrap();
+    assert_eq!(
+        results[1].as_ref().unwrap_err().raw_os_error(),
+        Some(libc::EBUSY)
+    );
+
+    let results = ring.remove_file_many(&tmpdir, &["b/g", "a/f"], LookupFlags::empty());
+    results[0].as_ref().unwrap();
+    assert_eq!(
+        results[1].as_ref().unwrap_err().raw_os_error(),
+        Some(libc::ENOENT)
+    );
+
+    let results = ring.remove_dir_many(&tmpdir, &["a", "b", ".."], LookupFlags::empty());
+    results[0].as_ref().unwrap();
+    results[1].as_ref().unwrap();
+    assert_eq!(
+        results[2].as_ref().unwrap_err().raw_os_error(),
+        Some(libc::EBUSY)
+    );
+}
+
+#[test]
+fn test_uring_statx_many() {
+    let tmpdir = tempfile::tempdir().unwrap();
+    let tmpdir = Dir::open(tmpdir.path()).unwrap();
+
+    tmpdir
+        .create_dir_secure("a", 0o755, LookupFlags::empty())
+        .unwrap();
+    tmpdir
+        .new_file_secure("a/f", 0o640, LookupFlags::empty())
+        .unwrap();
+    tmpdir.symlink("s", "/a").unwrap();
+
+    let mut ring = SecureUring::new(2).unwrap();
+    {
+        let results = ring.statx_many(
+            &tmpdir,
+            &["a", "s/f", "s", "/", "a/..", "missing", "a/f/x"],
+            libc::STATX_BASIC_STATS,
+            LookupFlags::empty(),
+        );
+        assert_eq!(results.len(), 7);
+
+        let mode = |i: usize| results[i].as_ref().unwrap().stx_mode as libc::mode_t;
+        assert_eq!(mode(0), libc::S_IFDIR | 0o755);
+        assert_eq!(mode(1), libc::S_IFREG | 0o640);
+        // The final symlink is not followed
+        assert_eq!(mode(2) & libc::S_IFMT, libc::S_IFLNK);
+
+        let root_ino = tmpdir.self_metadata().unwrap().stat().st_ino;
+        assert_eq!(results[3].as_ref().unwrap().stx_ino, root_ino);
+        assert_eq!(results[4].as_ref().unwrap().stx_ino, root_ino);
+
+        assert_eq!(
+            results[5].as_ref().unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+        assert_eq!(
+            results[6].as_ref().unwrap_err().raw_os_error(),
+            Some(libc::ENOTDIR)
+        );
+    }
+}